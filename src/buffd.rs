@@ -62,6 +62,7 @@ impl BufFd {
         &self.buffer[..self.curr_len]
     }
 
+    /// Returns `Ok(0)` at end-of-file and `EAGAIN` when nothing is ready yet.
     #[cfg(not(feature = "io-uring"))]
     pub fn read(&mut self, bytes_ready: Option<usize>) -> Result<usize, Errno> {
         match nix::unistd::read(self.fd.as_raw_fd(), self.buffer.as_mut()) {
@@ -83,7 +84,7 @@ impl BufFd {
             }
             Err(nix::errno::Errno::EAGAIN) => {
                 self.curr_len = 0;
-                Ok(0)
+                Err(Errno::EAGAIN)
             }
             Err(e) => Err(e),
         }
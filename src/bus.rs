@@ -4,13 +4,51 @@
 //! distribute data. Ownership-wise, the Bus is designed to own the consumers
 //! but not to own the producers. It's essentially treated as an open well
 //! that you throw data into and hope it reaches the right location.
-use crate::consumer::Consumer;
-use std::io;
+//!
+//! Buses are identified by the service stream feeding them rather than by a
+//! file descriptor, since a restarted service gets brand new pipes but its
+//! consumers should keep receiving output as if nothing happened.
+//!
+//! With line framing, a bus only ever hands whole lines to its consumers. The
+//! start of a line is held back until its newline arrives, the line grows too
+//! long or the producer reaches its end, and the buffer is only ever flushed
+//! between lines. Lines are put together per fd, as the pipes of a restarted
+//! service may be read from before those of its previous process ran dry.
+//!
+//! A bus with a flush interval asks for a timer whenever output lands in its
+//! empty buffer, so a quiet service doesn't leave lines sitting there until
+//! the buffer fills.
+use crate::{
+    conf::{ConsumerConf, ProducerConf},
+    consumer::{Consumer, StdinPipes},
+    control::ConsumerStatus,
+    pool::{Pool, Queued},
+};
+use nix::unistd::Pid;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io,
+    os::fd::RawFd,
+    sync::Arc,
+    time::{Duration, Instant},
+};
 
 pub struct Bus {
     buffer: Box<[u8]>,
     curr_len: usize,
-    consumers: Vec<Consumer>,
+    consumers: Vec<Queued>,
+    /// Set with line framing, to the length at which an unfinished line gets
+    /// passed on anyway.
+    max_line: Option<usize>,
+    /// The unfinished lines held back with line framing, by the fd they came
+    /// from.
+    partial: HashMap<RawFd, Vec<u8>>,
+    /// The process writing to the bus right now.
+    pid: Option<Pid>,
+    flush_interval: Option<Duration>,
+    /// Whether a flush timer is on its way.
+    flush_armed: bool,
 }
 
 impl Bus {
@@ -20,11 +58,71 @@ impl Bus {
             buffer,
             curr_len: 0,
             consumers: Vec::new(),
+            max_line: None,
+            partial: HashMap::new(),
+            pid: None,
+            flush_interval: None,
+            flush_armed: false,
         }
     }
 
-    pub fn add_consumer(&mut self, consumer: Consumer) {
-        self.consumers.push(consumer)
+    /// Switch line framing on with the given maximum line length, or off.
+    pub fn set_framing(&mut self, max_line: Option<usize>) -> io::Result<()> {
+        if max_line.is_none() {
+            self.end_of_streams()?;
+        }
+        self.max_line = max_line;
+        Ok(())
+    }
+
+    pub fn set_flush_interval(&mut self, interval: Option<Duration>) {
+        self.flush_interval = interval;
+    }
+
+    /// The delay of a flush timer to arm, if the bus needs one now.
+    fn arm_flush(&mut self) -> Option<Duration> {
+        let interval = self.flush_interval?;
+        if self.flush_armed || self.curr_len == 0 {
+            return None;
+        }
+        self.flush_armed = true;
+        Some(interval)
+    }
+
+    /// Swap out every consumer, whatever is buffered goes to the new ones.
+    pub fn set_consumers(&mut self, consumers: Vec<Queued>) {
+        self.consumers = consumers;
+        for consumer in &self.consumers {
+            consumer.set_pid(self.pid);
+        }
+    }
+
+    pub fn set_pid(&mut self, pid: Option<Pid>) {
+        self.pid = pid;
+        for consumer in &self.consumers {
+            consumer.set_pid(pid);
+        }
+    }
+
+    /// Change the size of the buffer, flushing it first if it would shrink.
+    pub fn resize(&mut self, bufsize: usize) -> io::Result<()> {
+        if bufsize == self.buffer.len() {
+            return Ok(());
+        }
+        if self.curr_len > bufsize {
+            self.flush()?;
+        }
+        let mut buffer = unsafe { Box::new_uninit_slice(bufsize).assume_init() };
+        buffer[..self.curr_len].copy_from_slice(&self.buffer[..self.curr_len]);
+        self.buffer = buffer;
+        Ok(())
+    }
+
+    fn deliver(consumers: &[Queued], data: &[u8]) -> io::Result<()> {
+        for consumer in consumers {
+            consumer.write(data);
+        }
+        Ok(())
     }
 
     pub fn flush(&mut self) -> io::Result<()> {
@@ -33,21 +131,80 @@ impl Bus {
         }
 
         // Execute all callbacks on the current buffer
-        for consumer in &mut self.consumers {
-            consumer.write(&self.buffer[..self.curr_len])?;
-        }
+        Self::deliver(&self.consumers, &self.buffer[..self.curr_len])?;
 
         // Reset the buffer after flushing
         self.curr_len = 0;
         Ok(())
     }
 
-    pub fn consume(&mut self, data: &[u8]) -> io::Result<()> {
-        if self.buffer.len() == 0 {
-            for consumer in &mut self.consumers {
-                consumer.write(data)?;
+    /// Take in what was read from `fd`.
+    pub fn consume(&mut self, fd: RawFd, data: &[u8]) -> io::Result<()> {
+        let Some(max_line) = self.max_line else {
+            return self.consume_bytes(data);
+        };
+        let mut partial = self.partial.remove(&fd).unwrap_or_default();
+        match data.iter().rposition(|&b| b == b'\n') {
+            Some(end) => {
+                let (lines, rest) = data.split_at(end + 1);
+                if partial.is_empty() {
+                    self.consume_lines(lines)?;
+                } else {
+                    partial.extend_from_slice(lines);
+                    self.consume_lines(&partial)?;
+                    partial.clear();
+                }
+                partial.extend_from_slice(rest);
             }
-            return Ok(());
+            None => partial.extend_from_slice(data),
+        }
+        while partial.len() >= max_line {
+            let rest = partial.split_off(max_line);
+            let line = std::mem::replace(&mut partial, rest);
+            self.consume_lines(&line)?;
+        }
+        if !partial.is_empty() {
+            self.partial.insert(fd, partial);
+        }
+        Ok(())
+    }
+
+    /// Pass on the line held back from `fd`, now that no more of it is coming.
+    pub fn end_of_stream(&mut self, fd: RawFd) -> io::Result<()> {
+        match self.partial.remove(&fd) {
+            Some(line) => self.consume_lines(&line),
+            None => Ok(()),
+        }
+    }
+
+    /// Pass on every line held back.
+    fn end_of_streams(&mut self) -> io::Result<()> {
+        let fds = self.partial.keys().copied().collect::<Vec<_>>();
+        for fd in fds {
+            self.end_of_stream(fd)?;
+        }
+        Ok(())
+    }
+
+    /// Buffer whole lines without ever splitting them across two flushes.
+    fn consume_lines(&mut self, lines: &[u8]) -> io::Result<()> {
+        if self.curr_len + lines.len() > self.buffer.len() {
+            self.flush()?;
+        }
+        if lines.len() > self.buffer.len() {
+            return Self::deliver(&self.consumers, lines);
+        }
+        self.buffer[self.curr_len..self.curr_len + lines.len()].copy_from_slice(lines);
+        self.curr_len += lines.len();
+        if self.curr_len == self.buffer.len() {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    fn consume_bytes(&mut self, data: &[u8]) -> io::Result<()> {
+        if self.buffer.is_empty() {
+            return Self::deliver(&self.consumers, data);
         }
 
         let num_bytes = data.len();
@@ -85,8 +242,8 @@ impl Bus {
 impl Drop for Bus {
     fn drop(&mut self) {
         // Ensure the buffer is flushed when the struct is dropped
-        if self.curr_len > 0 {
-            match self.flush() {
+        if self.curr_len > 0 || !self.partial.is_empty() {
+            match self.end_of_streams().and_then(|_| self.flush()) {
                 Ok(_) => (),
                 Err(e) => {
                     eprintln!("Failed to flush buffer: {}", e);
@@ -95,3 +252,258 @@ impl Drop for Bus {
         }
     }
 }
+
+/// Owns every bus and routes data read from producer fds into them, along
+/// with the pool their consumers write on.
+pub struct BusMap {
+    buses: HashMap<ProducerConf, Bus>,
+    routes: HashMap<RawFd, ProducerConf>,
+    pool: Pool,
+    stdin_pipes: StdinPipes,
+    /// Buses waiting for their flush timer to be armed.
+    to_arm: Vec<(ProducerConf, Duration)>,
+}
+
+impl BusMap {
+    pub fn new() -> Self {
+        Self {
+            buses: HashMap::new(),
+            routes: HashMap::new(),
+            pool: Pool::new(),
+            stdin_pipes: StdinPipes::default(),
+            to_arm: Vec::new(),
+        }
+    }
+
+    pub fn set_threads(&mut self, threads: usize) -> io::Result<()> {
+        self.pool.resize(threads)
+    }
+
+    /// Open a consumer and put it on the pool, ready to be handed to a bus.
+    pub fn open(&self, conf: &ConsumerConf, name_width: usize) -> io::Result<Queued> {
+        let consumer = Consumer::new(conf, name_width, &self.stdin_pipes)?;
+        Ok(self.pool.queue(consumer, conf))
+    }
+
+    /// Feed `service`'s stdin consumers into `pipe` from now on, or nowhere.
+    pub fn set_stdin(&self, service: &str, pipe: Option<Arc<File>>) {
+        self.stdin_pipes.set(service, pipe);
+    }
+
+    pub fn insert(&mut self, producer: ProducerConf, bus: Bus) {
+        self.buses.insert(producer, bus);
+    }
+
+    pub fn contains(&self, producer: &ProducerConf) -> bool {
+        self.buses.contains_key(producer)
+    }
+
+    pub fn get_mut(&mut self, producer: &ProducerConf) -> Option<&mut Bus> {
+        self.buses.get_mut(producer)
+    }
+
+    /// Send everything `pid` writes to `fd` into the bus of `producer`.
+    pub fn route(&mut self, fd: RawFd, producer: ProducerConf, pid: Option<Pid>) {
+        if let Some(bus) = self.buses.get_mut(&producer) {
+            bus.set_pid(pid);
+        }
+        self.routes.insert(fd, producer);
+    }
+
+    /// Stop routing `fd`, which reached its end.
+    pub fn unroute(&mut self, fd: RawFd) -> io::Result<Option<ProducerConf>> {
+        let Some(producer) = self.routes.remove(&fd) else {
+            return Ok(None);
+        };
+        if let Some(bus) = self.buses.get_mut(&producer) {
+            bus.end_of_stream(fd)?;
+        }
+        Ok(Some(producer))
+    }
+
+    pub fn routed_fds(&self) -> Vec<RawFd> {
+        self.routes.keys().copied().collect()
+    }
+
+    pub fn consume(&mut self, fd: RawFd, data: &[u8]) -> io::Result<()> {
+        let Some(producer) = self.routes.get(&fd) else {
+            return Ok(());
+        };
+        if let Some(bus) = self.buses.get_mut(producer) {
+            bus.consume(fd, data)?;
+            if let Some(after) = bus.arm_flush() {
+                self.to_arm.push((producer.clone(), after));
+            }
+        }
+        Ok(())
+    }
+
+    /// Flush timers the buses asked for since the last call.
+    pub fn take_flush_timers(&mut self) -> Vec<(ProducerConf, Duration)> {
+        std::mem::take(&mut self.to_arm)
+    }
+
+    /// Flush a bus whose flush timer fired.
+    pub fn flush_timer_fired(&mut self, producer: &ProducerConf) -> io::Result<()> {
+        if let Some(bus) = self.buses.get_mut(producer) {
+            bus.flush_armed = false;
+            bus.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush(&mut self, producer: &ProducerConf) -> io::Result<()> {
+        if let Some(bus) = self.buses.get_mut(producer) {
+            bus.flush()?;
+        }
+        Ok(())
+    }
+
+    pub fn flush_all(&mut self) -> io::Result<()> {
+        for bus in self.buses.values_mut() {
+            bus.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Flush every bus and have its consumers reopen their files, so nothing
+    /// buffered ends up in a file that was moved away.
+    pub fn reopen_all(&mut self) -> io::Result<()> {
+        self.flush_all()?;
+        for bus in self.buses.values_mut() {
+            for consumer in &bus.consumers {
+                consumer.reopen();
+            }
+        }
+        Ok(())
+    }
+
+    /// The failure of a consumer whose error policy is fatal, if one failed.
+    pub fn take_fatal(&self) -> Option<io::Error> {
+        self.pool.take_fatal()
+    }
+
+    pub fn consumer_statuses(&self) -> Vec<ConsumerStatus> {
+        let mut statuses = self
+            .buses
+            .values()
+            .flat_map(|bus| bus.consumers.iter().map(Queued::status))
+            .collect::<Vec<_>>();
+        statuses.sort_by(|a, b| (&a.service, &a.stream).cmp(&(&b.service, &b.stream)));
+        statuses
+    }
+
+    /// Wait until `deadline` at the latest for consumers to deliver output
+    /// they still hold on to.
+    pub fn drain_all(&mut self, deadline: Instant) {
+        for bus in self.buses.values_mut() {
+            for consumer in &bus.consumers {
+                consumer.drain(deadline);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{fs, path::PathBuf};
+
+    /// A line framed bus writing into a log file, through a pool of its own.
+    struct Logged {
+        bus: Bus,
+        path: PathBuf,
+        _pool: Pool,
+    }
+
+    impl Logged {
+        fn new(test: &str, max_line: usize) -> Self {
+            let path =
+                std::env::temp_dir().join(format!("kinesin-bus-{}-{}", test, std::process::id()));
+            let _ = fs::remove_file(&path);
+            let conf = format!(
+                "consumes = {{ stdout = \"app\" }}\nkind = {{ log = \"{}\" }}",
+                path.display()
+            );
+            let conf = toml::from_str::<ConsumerConf>(&conf).unwrap();
+            let mut pool = Pool::new();
+            pool.resize(1).unwrap();
+            let consumer = Consumer::new(&conf, 0, &StdinPipes::default()).unwrap();
+            let mut bus = Bus::new(64);
+            bus.set_framing(Some(max_line)).unwrap();
+            bus.set_consumers(vec![pool.queue(consumer, &conf)]);
+            Self {
+                bus,
+                path,
+                _pool: pool,
+            }
+        }
+
+        /// What made it into the file once everything buffered was flushed.
+        fn logged(&mut self) -> String {
+            self.bus.flush().unwrap();
+            let deadline = Instant::now() + Duration::from_secs(5);
+            for consumer in &self.bus.consumers {
+                consumer.drain(deadline);
+            }
+            fs::read_to_string(&self.path).unwrap()
+        }
+    }
+
+    impl Drop for Logged {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+
+    #[test]
+    fn unfinished_lines_are_held_back() {
+        let mut log = Logged::new("held", 1024);
+        log.bus.consume(3, b"hel").unwrap();
+        assert_eq!(log.logged(), "");
+        log.bus.consume(3, b"lo\nwor").unwrap();
+        assert_eq!(log.logged(), "hello\n");
+        log.bus.end_of_stream(3).unwrap();
+        assert_eq!(log.logged(), "hello\nwor");
+    }
+
+    #[test]
+    fn lines_are_put_together_per_fd() {
+        let mut log = Logged::new("per-fd", 1024);
+        log.bus.consume(3, b"first").unwrap();
+        log.bus.consume(4, b"second\nthi").unwrap();
+        log.bus.consume(3, b" line\n").unwrap();
+        log.bus.consume(4, b"rd\n").unwrap();
+        assert_eq!(log.logged(), "second\nfirst line\nthird\n");
+    }
+
+    #[test]
+    fn long_lines_are_passed_on_in_pieces() {
+        let mut log = Logged::new("long", 4);
+        log.bus.consume(3, b"abcdefghij").unwrap();
+        assert_eq!(log.logged(), "abcdefgh");
+        log.bus.consume(3, b"\n").unwrap();
+        assert_eq!(log.logged(), "abcdefghij\n");
+    }
+
+    #[test]
+    fn lines_longer_than_the_buffer_get_through_whole() {
+        let mut log = Logged::new("buffer", 1024);
+        let line = format!("{}\n", "x".repeat(100));
+        log.bus.consume(3, b"short\n").unwrap();
+        log.bus.consume(3, line.as_bytes()).unwrap();
+        assert_eq!(log.logged(), format!("short\n{}", line));
+    }
+
+    #[test]
+    fn switching_framing_off_passes_held_lines_on() {
+        let mut log = Logged::new("off", 1024);
+        log.bus.consume(3, b"one").unwrap();
+        log.bus.consume(4, b"two").unwrap();
+        log.bus.set_framing(None).unwrap();
+        let logged = log.logged();
+        assert!(logged == "onetwo" || logged == "twoone", "{}", logged);
+        log.bus.consume(3, b"three").unwrap();
+        assert!(log.logged().ends_with("three"));
+    }
+}
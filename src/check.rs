@@ -0,0 +1,889 @@
+//! Validation of a whole config before anything gets started.
+//!
+//! Every problem is collected instead of stopping at the first one, and each is
+//! pointed at the line of the config it stems from. serde doesn't keep track of
+//! where values came from in every format, so lines are found by looking for
+//! the keys in the text the way TOML, YAML and JSON all spell them.
+use crate::{
+    conf::{
+        Config, ConfigFormat, ConfigSource, ConsumerKind, DeliveryConf, Encoding, HealthProbe,
+        ProducerConf, ServiceConf, SourceConf, StdinConf, SyslogServer,
+    },
+    deps::start_order,
+    net::split_http_url,
+    registry::{is_hook_name, EXIT_HOOK},
+    users::Credentials,
+};
+use nix::{
+    sys::signal::Signal,
+    unistd::{access, AccessFlags},
+};
+use serde_json::Value;
+use std::{
+    collections::{hash_map::Entry, HashMap},
+    ffi::CString,
+    fmt,
+    path::{Path, PathBuf},
+};
+
+#[derive(Debug)]
+pub struct Problem {
+    /// 1-based line of the config, if it could be found.
+    pub line: Option<usize>,
+    pub message: String,
+}
+
+/// A problem along with the name of the config it was found in.
+pub struct Located<'a> {
+    pub origin: &'a str,
+    pub problem: &'a Problem,
+}
+
+impl fmt::Display for Located<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.problem.line {
+            Some(line) => write!(f, "{}:{}: {}", self.origin, line, self.problem.message),
+            None => write!(f, "{}: {}", self.origin, self.problem.message),
+        }
+    }
+}
+
+/// Whether `key` starts at `idx` of `line` rather than in the middle of a word
+/// or a path.
+fn starts_key(line: &str, idx: usize) -> bool {
+    let before = line[..idx].chars().next_back();
+    before.is_none_or(|c| c.is_whitespace() || "\"'{[,.-".contains(c))
+}
+
+/// Whether `line` defines `key`, be it as `key = `, `key: `, `"key": ` or as a
+/// dotted TOML key or table header.
+fn has_key(line: &str, key: &str) -> bool {
+    line.match_indices(key).any(|(idx, _)| {
+        let after = line[idx + key.len()..]
+            .trim_start_matches(['"', '\''])
+            .trim_start();
+        starts_key(line, idx) && after.starts_with(['=', ':', '.', ']'])
+    })
+}
+
+/// The value assigned to `key` on `line`, stripped of quotes.
+fn value_of<'a>(line: &'a str, key: &str) -> Option<&'a str> {
+    let (idx, _) = line
+        .match_indices(key)
+        .find(|(idx, _)| starts_key(line, *idx))?;
+    let rest = line[idx + key.len()..]
+        .trim_start_matches(['"', '\''])
+        .trim_start();
+    let rest = rest.strip_prefix(['=', ':'])?.trim_start();
+    let rest = rest.trim_start_matches(['"', '\'']);
+    let end = rest.find(['"', '\'', ',', '}', '#']).unwrap_or(rest.len());
+    Some(rest[..end].trim_end())
+}
+
+struct Locator<'a> {
+    lines: Vec<&'a str>,
+    /// Line index at which each service is defined, found through its name.
+    services: Vec<Option<usize>>,
+    consumers: Vec<usize>,
+}
+
+impl<'a> Locator<'a> {
+    fn new(text: &'a str, config: &Config) -> Self {
+        let lines = text.lines().collect::<Vec<_>>();
+
+        let mut services = Vec::with_capacity(config.service.len());
+        let mut from = 0;
+        for def in &config.service {
+            let found = (from..lines.len())
+                .find(|&idx| value_of(lines[idx], "name") == Some(def.name.as_str()));
+            if let Some(idx) = found {
+                from = idx + 1;
+            }
+            services.push(found);
+        }
+
+        let consumers = (0..lines.len())
+            .filter(|&idx| has_key(lines[idx], "consumes"))
+            .collect();
+
+        Self {
+            lines,
+            services,
+            consumers,
+        }
+    }
+
+    fn service(&self, idx: usize) -> Option<usize> {
+        self.services[idx].map(|line| line + 1)
+    }
+
+    /// Line of a (possibly nested) field of a service, falling back to the line
+    /// of the service itself.
+    fn field(&self, idx: usize, path: &[&str]) -> Option<usize> {
+        let start = self.services[idx]?;
+        let end = self.services[idx + 1..]
+            .iter()
+            .flatten()
+            .next()
+            .copied()
+            .unwrap_or(self.lines.len());
+        let mut at = start;
+        for key in path {
+            match (at..end).find(|&line| has_key(self.lines[line], key)) {
+                Some(line) => at = line,
+                None => return Some(start + 1),
+            }
+        }
+        Some(at + 1)
+    }
+
+    fn consumer(&self, idx: usize) -> Option<usize> {
+        self.consumers.get(idx).map(|line| line + 1)
+    }
+
+    /// Line of a key at the top of the config.
+    fn top_level(&self, key: &str) -> Option<usize> {
+        self.lines
+            .iter()
+            .position(|line| has_key(line, key))
+            .map(|line| line + 1)
+    }
+}
+
+/// What's wrong with a command, if anything. Commands are run with `execve`,
+/// so the binary has to be given as a path.
+/// Check a command run from `dir`, or from kinesin's own working directory if
+/// it's unset.
+fn check_exec(exec: &[CString], dir: Option<&Path>) -> Option<String> {
+    let Some(binary) = exec.first() else {
+        return Some("command is empty".to_string());
+    };
+    let binary = binary.to_string_lossy();
+    let path = match dir {
+        Some(dir) => dir.join(binary.as_ref()),
+        None => PathBuf::from(binary.as_ref()),
+    };
+    let path = path.as_path();
+    if !path.is_file() {
+        let hint = match which::which(path) {
+            Ok(found) if !binary.contains('/') => format!(", did you mean {}?", found.display()),
+            _ => String::new(),
+        };
+        return Some(format!("{} doesn't exist{}", binary, hint));
+    }
+    if access(path, AccessFlags::X_OK).is_err() {
+        return Some(format!("{} isn't executable", binary));
+    }
+    None
+}
+
+fn check_env(env: &[CString]) -> Vec<String> {
+    env.iter()
+        .filter(|var| !var.as_bytes().contains(&b'='))
+        .map(|var| format!("env entry {:?} has no '='", var.to_string_lossy()))
+        .collect()
+}
+
+fn check_source(name: &str, stream: &str, conf: &SourceConf) -> Vec<(&'static str, String)> {
+    let mut problems = Vec::new();
+    let zeroes = [
+        ("read_bufsize", conf.read_bufsize == 0),
+        ("max_line", conf.max_line == 0),
+        ("flush_interval_ms", conf.flush_interval_ms == Some(0)),
+    ];
+    for (key, zero) in zeroes {
+        if zero {
+            let problem = format!(
+                "service {}: {}.{} must be greater than 0",
+                name, stream, key
+            );
+            problems.push((key, problem));
+        }
+    }
+    problems
+}
+
+fn check_address(kind: &str, address: &str) -> Option<String> {
+    let port = address.rsplit_once(':').map(|(_, port)| port);
+    match port.is_some_and(|port| port.parse::<u16>().is_ok()) {
+        true => None,
+        false => Some(format!("{} address {} isn't host:port", kind, address)),
+    }
+}
+
+fn check_delivery(kind: &str, conf: &DeliveryConf) -> Option<String> {
+    (conf.queue_bytes == 0).then(|| format!("{} queue_bytes must be greater than 0", kind))
+}
+
+/// Pieces of a line longer than the queue could never be queued.
+fn check_max_line(kind: &str, max_line: usize, conf: &DeliveryConf) -> Option<String> {
+    (max_line == 0 || max_line > conf.queue_bytes)
+        .then(|| format!("{} max_line must be between 1 and queue_bytes", kind))
+}
+
+fn check_consumer(kind: &ConsumerKind) -> Vec<String> {
+    match kind {
+        ConsumerKind::Tcp(conf) => [
+            check_address("tcp", &conf.address),
+            check_delivery("tcp", &conf.delivery),
+            check_max_line("tcp", conf.max_line, &conf.delivery),
+        ]
+        .into_iter()
+        .flatten()
+        .collect(),
+        ConsumerKind::Syslog(conf) => {
+            let address = match &conf.server {
+                SyslogServer::Udp(address) | SyslogServer::Tcp(address) => {
+                    check_address("syslog", address)
+                }
+                SyslogServer::Unix(_) => None,
+            };
+            let app_name = match conf.app_name.as_deref() {
+                Some("") => Some("syslog app_name can't be empty".to_string()),
+                _ => None,
+            };
+            [
+                address,
+                app_name,
+                check_delivery("syslog", &conf.delivery),
+                check_max_line("syslog", conf.max_line, &conf.delivery),
+            ]
+            .into_iter()
+            .flatten()
+            .collect()
+        }
+        ConsumerKind::Log(conf) => {
+            let mut problems = Vec::new();
+            if conf.max_bytes == Some(0) {
+                problems.push("log max_bytes must be greater than 0".to_string());
+            }
+            if conf.rotate_interval_ms == Some(0) {
+                problems.push("log rotate_interval_ms must be greater than 0".to_string());
+            }
+            problems
+        }
+        ConsumerKind::Loki(conf) => {
+            let mut problems = Vec::new();
+            if let Err(e) = split_http_url(&conf.url) {
+                problems.push(format!("loki url {}: {}", conf.url, e));
+            }
+            if conf.batch_bytes == 0 || conf.batch_bytes > conf.delivery.queue_bytes {
+                problems.push("loki batch_bytes must be between 1 and queue_bytes".to_string());
+            }
+            for name in conf.labels.keys() {
+                let mut chars = name.chars();
+                let valid = chars
+                    .next()
+                    .is_some_and(|c| c.is_ascii_alphabetic() || c == '_')
+                    && chars.all(|c| c.is_ascii_alphanumeric() || c == '_');
+                if !valid {
+                    problems.push(format!("loki label name {:?} isn't valid", name));
+                }
+            }
+            problems.extend(check_delivery("loki", &conf.delivery));
+            problems
+        }
+        _ => Vec::new(),
+    }
+}
+
+/// Every command a service runs, along with the key it's configured under.
+fn commands(def: &ServiceConf) -> Vec<(&'static str, &[CString])> {
+    let mut commands = vec![("exec", def.exec.as_slice())];
+    if let Some(exec) = &def.on_failure {
+        commands.push(("on_failure", exec));
+    }
+    if let Some(exec) = &def.on_success {
+        commands.push(("on_success", exec));
+    }
+    if let Some(HealthProbe::Exec(exec)) = def.healthcheck.as_ref().map(|conf| &conf.probe) {
+        commands.push(("healthcheck", exec));
+    }
+    commands
+}
+
+/// Collect the keys of `document` that `parsed`, what the config it makes
+/// serializes to, has no room for. Those are ignored when parsing, so they're
+/// almost always misspelled.
+fn unknown_keys(document: &Value, parsed: &Value, path: &str, unknown: &mut Vec<String>) {
+    match (document, parsed) {
+        (Value::Object(document), Value::Object(parsed)) => {
+            for (key, value) in document {
+                let path = match path {
+                    "" => key.clone(),
+                    path => format!("{}.{}", path, key),
+                };
+                match parsed.get(key) {
+                    Some(parsed) => unknown_keys(value, parsed, &path, unknown),
+                    None => unknown.push(path),
+                }
+            }
+        }
+        (Value::Array(document), Value::Array(parsed)) => {
+            for (idx, (value, parsed)) in document.iter().zip(parsed).enumerate() {
+                unknown_keys(value, parsed, &format!("{}[{}]", path, idx), unknown);
+            }
+        }
+        // shorthands like a log given as just its path
+        _ => {}
+    }
+}
+
+/// Find every problem with `config`, whose source is `text` in `format`.
+pub fn validate(config: &Config, text: &str, format: ConfigFormat) -> Vec<Problem> {
+    let locator = Locator::new(text, config);
+    let mut problems = Vec::new();
+    let mut report = |line: Option<usize>, message: String| {
+        problems.push(Problem { line, message });
+    };
+
+    let document = Config::parse_document(text, format);
+    let parsed = serde_json::to_value(config);
+    if let (Ok(document), Ok(parsed)) = (document, parsed) {
+        let mut unknown = Vec::new();
+        unknown_keys(&document, &parsed, "", &mut unknown);
+        for path in unknown {
+            let key = path.rsplit('.').next().unwrap_or(&path);
+            report(locator.top_level(key), format!("unknown key {}", path));
+        }
+    }
+
+    // streams consumers may read from, hooks included
+    let mut streams = HashMap::new();
+    let mut first_seen = HashMap::new();
+    for (idx, def) in config.service.iter().enumerate() {
+        if let Entry::Occupied(first) = first_seen.entry(def.name.as_str()) {
+            let first_line = locator.service(*first.get()).map_or(String::new(), |line| {
+                format!(", first defined on line {}", line)
+            });
+            report(
+                locator.service(idx),
+                format!("duplicate service name {}{}", def.name, first_line),
+            );
+        } else {
+            first_seen.insert(def.name.as_str(), idx);
+        }
+        if is_hook_name(&def.name) {
+            report(
+                locator.service(idx),
+                format!("service name {} is reserved for hooks", def.name),
+            );
+        }
+
+        streams.insert(def.name.clone(), (def.stdout.watch, def.stderr.watch));
+        for (trigger, exec) in [
+            ("on_failure", &def.on_failure),
+            ("on_success", &def.on_success),
+        ] {
+            if exec.is_some() {
+                streams.insert(format!("{}.{}", def.name, trigger), (true, true));
+            }
+        }
+
+        for (key, exec) in commands(def) {
+            // only the service itself runs from its working_dir
+            let dir = def.working_dir.as_deref().filter(|_| key == "exec");
+            if let Some(problem) = check_exec(exec, dir) {
+                report(
+                    locator.field(idx, &[key]),
+                    format!("service {}: {}: {}", def.name, key, problem),
+                );
+            }
+        }
+        for problem in check_env(&def.env) {
+            report(
+                locator.field(idx, &["env"]),
+                format!("service {}: {}", def.name, problem),
+            );
+        }
+        for (stream, conf) in [("stdout", &def.stdout), ("stderr", &def.stderr)] {
+            for (key, problem) in check_source(&def.name, stream, conf) {
+                report(locator.field(idx, &[stream, key]), problem);
+            }
+        }
+
+        let kinds = [
+            ("after", &def.after),
+            ("requires", &def.requires),
+            ("wants", &def.wants),
+        ];
+        for (kind, dependencies) in kinds {
+            for dependency in dependencies {
+                if config.service.iter().any(|other| other.name == *dependency) {
+                    continue;
+                }
+                report(
+                    locator.field(idx, &[kind]),
+                    format!(
+                        "service {} depends on {} which isn't defined",
+                        def.name, dependency
+                    ),
+                );
+            }
+        }
+        if let Some(group) = &def.group {
+            if !config.group.iter().any(|g| g.name == *group) {
+                report(
+                    locator.field(idx, &["group"]),
+                    format!(
+                        "service {} is in group {} which isn't defined",
+                        def.name, group
+                    ),
+                );
+            }
+        }
+    }
+
+    // these already mean something to kinesin
+    let reserved = [
+        Signal::SIGCHLD,
+        Signal::SIGTERM,
+        Signal::SIGINT,
+        Signal::SIGHUP,
+        Signal::SIGKILL,
+        Signal::SIGSTOP,
+    ];
+    if reserved.contains(&config.reopen_signal) {
+        report(
+            locator.top_level("reopen_signal"),
+            format!("reopen_signal can't be {}", config.reopen_signal),
+        );
+    }
+    if config.consumer_threads == 0 {
+        report(
+            locator.top_level("consumer_threads"),
+            "consumer_threads must be greater than 0".to_string(),
+        );
+    }
+
+    if let Some(exec) = &config.on_exit {
+        if let Some(problem) = check_exec(exec, None) {
+            report(
+                locator.top_level(EXIT_HOOK),
+                format!("on_exit: {}", problem),
+            );
+        }
+        streams.insert(EXIT_HOOK.to_string(), (true, true));
+    }
+
+    for (idx, consumer) in config.consumer.iter().enumerate() {
+        let name = consumer.consumes.service_name();
+        let line = locator.consumer(idx);
+        for problem in check_consumer(&consumer.kind) {
+            report(line, format!("consumer: {}", problem));
+        }
+        if consumer.queue_bytes == 0 {
+            report(
+                line,
+                "consumer: queue_bytes must be greater than 0".to_string(),
+            );
+        }
+        match consumer.encoding {
+            Encoding::Json if consumer.prefix.is_some() => report(
+                line,
+                "consumer: prefix can't be used with json encoding".to_string(),
+            ),
+            Encoding::Text if consumer.merge_json => report(
+                line,
+                "consumer: merge_json only applies to json encoding".to_string(),
+            ),
+            _ => (),
+        }
+        if let Some(prefix) = &consumer.prefix {
+            if prefix.timestamp.is_none() && !prefix.service && !prefix.stream {
+                report(
+                    line,
+                    "consumer: prefix needs a timestamp, service or stream".to_string(),
+                );
+            }
+        }
+        match streams.get(name) {
+            None => report(
+                line,
+                format!("consumer reads from unknown service {}", name),
+            ),
+            Some(&(stdout, stderr)) => {
+                let (stream, watched) = match &consumer.consumes {
+                    ProducerConf::StdOut(_) => ("stdout", stdout),
+                    ProducerConf::StdErr(_) => ("stderr", stderr),
+                };
+                if !watched {
+                    report(
+                        line,
+                        format!(
+                            "consumer reads {} of {}, which has watch = false",
+                            stream, name
+                        ),
+                    );
+                }
+            }
+        }
+    }
+
+    for (idx, def) in config.service.iter().enumerate() {
+        match &def.user {
+            Some(user) => {
+                let group = def.user_group.as_deref();
+                if let Err(e) = Credentials::resolve(user, group, &def.supplementary_groups) {
+                    report(
+                        locator.field(idx, &["user"]),
+                        format!("service {}: {}", def.name, e),
+                    );
+                }
+            }
+            None if def.user_group.is_some() => report(
+                locator.field(idx, &["user_group"]),
+                format!("service {}: user_group needs a user", def.name),
+            ),
+            None if !def.supplementary_groups.is_empty() => report(
+                locator.field(idx, &["supplementary_groups"]),
+                format!("service {}: supplementary_groups need a user", def.name),
+            ),
+            None => {}
+        }
+
+        if let Some(dir) = &def.working_dir {
+            if !dir.is_dir() {
+                report(
+                    locator.field(idx, &["working_dir"]),
+                    format!(
+                        "service {}: working_dir {} isn't a directory",
+                        def.name,
+                        dir.display()
+                    ),
+                );
+            }
+        }
+        for (name, _, limit) in def.rlimits.iter() {
+            if limit.soft().raw() > limit.hard().raw() {
+                report(
+                    locator.field(idx, &["rlimits", name]),
+                    format!(
+                        "service {}: soft rlimit {} is over its hard limit",
+                        def.name, name
+                    ),
+                );
+            }
+        }
+    }
+
+    for (idx, def) in config.service.iter().enumerate() {
+        let line = locator.field(idx, &["stdin"]);
+        if let StdinConf::File(path) = &def.stdin {
+            if !path.exists() {
+                report(
+                    line,
+                    format!(
+                        "service {}: stdin file {} doesn't exist",
+                        def.name,
+                        path.display()
+                    ),
+                );
+            }
+        }
+        let Some(feed) = def.stdin_consumer() else {
+            continue;
+        };
+        let source = feed.consumes.service_name();
+        let watched = streams
+            .get(source)
+            .map(|&(stdout, stderr)| match feed.consumes {
+                ProducerConf::StdOut(_) => stdout,
+                ProducerConf::StdErr(_) => stderr,
+            });
+        let problem = match watched {
+            _ if source == def.name => "which is its own",
+            None => "of an unknown service",
+            Some(false) => "which has watch = false",
+            Some(true) => continue,
+        };
+        report(
+            line,
+            format!(
+                "service {} reads stdin from {} of {}, {}",
+                def.name,
+                feed.consumes.stream_name(),
+                source,
+                problem
+            ),
+        );
+    }
+
+    // cycles only make sense to look for once every dependency exists
+    let all_defined = config.service.iter().all(|def| {
+        def.dependencies()
+            .all(|dep| config.service.iter().any(|other| other.name == *dep))
+    });
+    if all_defined {
+        if let Err(e) = start_order(&config.service) {
+            report(None, e.to_string());
+        }
+    }
+
+    problems.sort_by_key(|problem| problem.line.unwrap_or(usize::MAX));
+    problems
+}
+
+/// Load a config and validate it, returning either the config or every
+/// problem found with it.
+pub fn load_checked(source: &ConfigSource) -> Result<Config, Vec<String>> {
+    let (config, text) = source.load().map_err(|e| vec![e])?;
+    let format = source.resolved_format().map_err(|e| vec![e])?;
+    let origin = source.origin();
+    let problems = validate(&config, &text, format)
+        .iter()
+        .map(|problem| {
+            Located {
+                origin: &origin,
+                problem,
+            }
+            .to_string()
+        })
+        .collect::<Vec<_>>();
+    match problems.is_empty() {
+        true => Ok(config),
+        false => Err(problems),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn problems(text: &str, format: ConfigFormat) -> Vec<(Option<usize>, String)> {
+        let config = Config::parse(text, format).unwrap();
+        validate(&config, text, format)
+            .into_iter()
+            .map(|problem| (problem.line, problem.message))
+            .collect()
+    }
+
+    #[test]
+    fn keys_are_found_in_every_format() {
+        assert!(has_key("name = \"web\"", "name"));
+        assert!(has_key("  name: web", "name"));
+        assert!(has_key("  \"name\": \"web\",", "name"));
+        assert!(has_key("stdout.watch = false", "stdout"));
+        assert!(has_key("[service.healthcheck]", "healthcheck"));
+        assert!(!has_key("hostname = \"web\"", "name"));
+        assert!(!has_key("exec = [\"/bin/name\"]", "name"));
+    }
+
+    #[test]
+    fn values_are_unquoted() {
+        assert_eq!(value_of("name = \"web\"", "name"), Some("web"));
+        assert_eq!(value_of("- name: web", "name"), Some("web"));
+        assert_eq!(
+            value_of("{ \"name\": \"web\", \"exec\": [] }", "name"),
+            Some("web")
+        );
+        assert_eq!(value_of("name = 'web' # the frontend", "name"), Some("web"));
+        assert_eq!(value_of("hostname = \"web\"", "name"), None);
+    }
+
+    #[test]
+    fn toml_problems_point_at_their_lines() {
+        let text = r#"
+[[service]]
+name = "web"
+exec = ["/bin/true"]
+
+[[service]]
+name = "web"
+exec = ["/bin/true"]
+env = ["NOEQUALS"]
+
+[[consumer]]
+consumes = { stdout = "db" }
+kind = { tcp = { address = "logs:5000" } }
+"#;
+        assert_eq!(
+            problems(text, ConfigFormat::Toml),
+            [
+                (
+                    Some(7),
+                    "duplicate service name web, first defined on line 3".to_string()
+                ),
+                (
+                    Some(9),
+                    "service web: env entry \"NOEQUALS\" has no '='".to_string()
+                ),
+                (
+                    Some(12),
+                    "consumer reads from unknown service db".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn yaml_problems_point_at_their_lines() {
+        let text = r#"
+service:
+  - name: web
+    exec: [/bin/true]
+  - name: db
+    exec: [/bin/true]
+    stdout:
+      watch: false
+      max_line: 0
+consumer:
+  - consumes: { stdout: db }
+    kind:
+      tcp: { address: logs }
+"#;
+        assert_eq!(
+            problems(text, ConfigFormat::Yaml),
+            [
+                (
+                    Some(9),
+                    "service db: stdout.max_line must be greater than 0".to_string()
+                ),
+                (
+                    Some(11),
+                    "consumer: tcp address logs isn't host:port".to_string()
+                ),
+                (
+                    Some(11),
+                    "consumer reads stdout of db, which has watch = false".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn json_problems_point_at_their_lines() {
+        let text = r#"{
+    "service": [
+        { "name": "web", "exec": ["/bin/true"], "after": ["db"] },
+        {
+            "name": "db",
+            "exec": ["/bin/true"],
+            "stdin": { "stdout": "db" }
+        }
+    ]
+}"#;
+        assert_eq!(
+            problems(text, ConfigFormat::Json),
+            [(
+                Some(7),
+                "service db reads stdin from stdout of db, which is its own".to_string()
+            )]
+        );
+    }
+
+    #[test]
+    fn located_problems_name_their_origin() {
+        let problem = Problem {
+            line: Some(4),
+            message: "command is empty".to_string(),
+        };
+        let located = Located {
+            origin: "kinesin.toml",
+            problem: &problem,
+        };
+        assert_eq!(located.to_string(), "kinesin.toml:4: command is empty");
+
+        let problem = Problem {
+            line: None,
+            ..problem
+        };
+        let located = Located {
+            origin: "kinesin.toml",
+            problem: &problem,
+        };
+        assert_eq!(located.to_string(), "kinesin.toml: command is empty");
+    }
+
+    #[test]
+    fn unknown_keys_are_reported() {
+        let text = r#"
+consumer_thread = 2
+
+[[service]]
+name = "web"
+exec = ["/bin/true"]
+restart = { policy = "on-failure", max_restarts = 3 }
+
+[[consumer]]
+consumes = { stdout = "web" }
+kind = { tcp = { address = "logs:5000", queue_byte = 1024 } }
+"#;
+        assert_eq!(
+            problems(text, ConfigFormat::Toml),
+            [
+                (Some(2), "unknown key consumer_thread".to_string()),
+                (
+                    Some(7),
+                    "unknown key service[0].restart.max_restarts".to_string()
+                ),
+                (
+                    Some(11),
+                    "unknown key consumer[0].kind.tcp.queue_byte".to_string()
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn known_keys_are_not_reported() {
+        let text = r#"
+version = 1
+consumer_threads = 2
+hook_timeout_ms = 1000
+on_exit = ["/bin/true"]
+
+[[group]]
+name = "core"
+policy = "max-restarts"
+max_restarts = 2
+
+[[service]]
+name = "web"
+type = "notify"
+exec = ["/bin/true"]
+env = ["MODE=prod"]
+group = "core"
+umask = "0027"
+rlimits = { nofile = { soft = 1024, hard = 4096 } }
+stdout = { framing = "line", max_line = 1024, flush_interval_ms = 100 }
+stderr = { watch = true }
+restart = { policy = "always", max_attempts = 3, reset_after_ms = 1000 }
+healthcheck = { probe = { exec = ["/bin/true"] }, interval_ms = 1000 }
+on_failure = ["/bin/true"]
+stop_signal = "SIGINT"
+
+[[consumer]]
+consumes = { stdout = "web" }
+kind = { log = "/tmp/web.log" }
+prefix = { timestamp = "rfc3339", service = true }
+
+[[consumer]]
+consumes = { stdout = "web" }
+kind = { log = { path = "/tmp/web.log", max_bytes = 1024, naming = "timestamp" } }
+encoding = "json"
+merge_json = true
+overflow = "drop-oldest"
+
+[[consumer]]
+consumes = { stderr = "web" }
+kind = { syslog = { server = { udp = "localhost:514" }, format = "rfc3164", timeout_ms = 100 } }
+
+[[consumer]]
+consumes = { stderr = "web" }
+kind = { loki = { url = "http://loki:3100", labels = { env = "prod" }, batch_bytes = 4096 } }
+
+[[consumer]]
+consumes = { stdout = "web.on_failure" }
+kind = "stderr"
+"#;
+        let problems = problems(text, ConfigFormat::Toml);
+        assert!(
+            problems
+                .iter()
+                .all(|(_, problem)| !problem.starts_with("unknown key")),
+            "{:?}",
+            problems
+        );
+    }
+}
@@ -1,7 +1,10 @@
 //! Uses clap to define the CLI interface declaratively.
 use std::path::PathBuf;
 
-use clap::{command, Parser};
+use clap::{Parser, Subcommand};
+use nix::sys::signal::Signal;
+
+use crate::conf::{signal_name, ConfigFormat};
 
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
@@ -9,4 +12,47 @@ pub struct Cli {
     /// pass an initial grid state as an ASCII file
     #[arg(short, default_value = "kinesin.toml", long, value_name = "FILE")]
     pub config: PathBuf,
+
+    /// format of the config, guessed from its extension by default. Required
+    /// when the config is read from stdin with `-c -`
+    #[arg(short, long)]
+    pub format: Option<ConfigFormat>,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Validate the config, reporting every problem found in it
+    Check,
+    /// Manage a running kinesin through its control socket
+    Ctl {
+        /// control socket to talk to, by default the one in the configured runtime_dir
+        #[arg(short, long, value_name = "SOCKET")]
+        socket: Option<PathBuf>,
+
+        #[command(subcommand)]
+        action: CtlAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CtlAction {
+    /// List every service with its state, pid, uptime, restarts and last exit
+    Status,
+    /// Start a service that isn't running
+    Start { service: String },
+    /// Stop a service without restarting it
+    Stop { service: String },
+    /// Stop a service and start it again
+    Restart { service: String },
+    /// Re-read the config and apply the changes, like SIGHUP does
+    Reload,
+    /// Send a signal, e.g. HUP or SIGUSR1, to a service
+    Signal {
+        service: String,
+        #[arg(value_parser = signal_name::parse)]
+        signal: Signal,
+    },
 }
@@ -1,30 +1,448 @@
 //! The Serializable configuration data structures used for setup.
-use std::{ffi::CString, path::PathBuf};
+//!
+//! The same model is deserialized from TOML, YAML or JSON, so every format
+//! supports exactly the same options.
+use std::{collections::BTreeMap, ffi::CString, fmt, path::Path, path::PathBuf};
 
+use clap::ValueEnum;
+use nix::sys::{resource::Resource, signal::Signal};
 use serde::{Deserialize, Serialize};
+use serde_json::Value;
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
 #[serde(rename_all = "lowercase")]
 pub enum ProducerConf {
     StdOut(String),
     StdErr(String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl ProducerConf {
+    pub fn service_name(&self) -> &str {
+        match self {
+            Self::StdOut(name) => name,
+            Self::StdErr(name) => name,
+        }
+    }
+
+    pub fn stream_name(&self) -> &'static str {
+        match self {
+            Self::StdOut(_) => "stdout",
+            Self::StdErr(_) => "stderr",
+        }
+    }
+}
+
+/// What rotated log files are called.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum LogNaming {
+    /// `app.log.1` is the newest, `app.log.2` the one before it and so on.
+    Index,
+    /// `app.log.20250101T120000.000Z`, after the time of the rotation in UTC,
+    /// followed by `-001` and so on for further rotations in the same
+    /// millisecond.
+    Timestamp,
+}
+
+/// A log file, given either as just its path or along with how to rotate it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LogConf {
+    pub path: PathBuf,
+
+    /// Rotate before the file would grow past this many bytes.
+    #[serde(default)]
+    pub max_bytes: Option<u64>,
+
+    /// Rotate once the file has been written to for this long.
+    #[serde(default)]
+    pub rotate_interval_ms: Option<u64>,
+
+    /// Rotated files to keep around, older ones get deleted.
+    #[serde(default = "default_log_keep")]
+    pub keep: usize,
+
+    #[serde(default = "default_log_naming")]
+    pub naming: LogNaming,
+
+    /// gzip rotated files, which then end in `.gz`.
+    #[serde(default)]
+    pub compress: bool,
+}
+
+/// How output sent over the network is cut into messages.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum NetFraming {
+    /// Bytes are sent as they come.
+    None,
+    /// Only whole lines are sent, each ending in a newline.
+    Newline,
+    /// Every line is sent without its newline, prefixed by its length as a
+    /// 32 bit big-endian integer.
+    Length,
+}
+
+/// How a network consumer gets its output to a peer that may be away.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct DeliveryConf {
+    /// Bytes held in memory while the peer is unreachable, after which the
+    /// oldest output is dropped.
+    #[serde(default = "default_net_queue_bytes")]
+    pub queue_bytes: usize,
+
+    /// Limit on connecting and on every write, a peer that's slower than this
+    /// is reconnected to.
+    #[serde(default = "default_net_timeout_ms")]
+    pub timeout_ms: u64,
+
+    #[serde(default = "default_net_reconnect_ms")]
+    pub reconnect_ms: u64,
+
+    #[serde(default = "default_net_max_reconnect_ms")]
+    pub max_reconnect_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct TcpConf {
+    /// `host:port` to stream to.
+    pub address: String,
+
+    #[serde(default = "default_net_framing")]
+    pub framing: NetFraming,
+
+    /// With line framing, longer lines are sent in pieces of this length.
+    #[serde(default = "default_max_line")]
+    pub max_line: usize,
+
+    #[serde(flatten)]
+    pub delivery: DeliveryConf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogServer {
+    Udp(String),
+    /// Messages are framed by octet counting, as in RFC 6587.
+    Tcp(String),
+    /// A local datagram socket such as `/dev/log`.
+    Unix(PathBuf),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFormat {
+    Rfc5424,
+    Rfc3164,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum SyslogFacility {
+    Kern,
+    User,
+    Mail,
+    Daemon,
+    Auth,
+    Syslog,
+    Lpr,
+    News,
+    Uucp,
+    Cron,
+    Authpriv,
+    Ftp,
+    Local0 = 16,
+    Local1,
+    Local2,
+    Local3,
+    Local4,
+    Local5,
+    Local6,
+    Local7,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct SyslogConf {
+    #[serde(default = "default_syslog_server")]
+    pub server: SyslogServer,
+
+    #[serde(default = "default_syslog_format")]
+    pub format: SyslogFormat,
+
+    #[serde(default = "default_syslog_facility")]
+    pub facility: SyslogFacility,
+
+    /// Defaults to the name of the service.
+    #[serde(default)]
+    pub app_name: Option<String>,
+
+    /// Longer lines are sent as several messages of this length.
+    #[serde(default = "default_max_line")]
+    pub max_line: usize,
+
+    #[serde(flatten)]
+    pub delivery: DeliveryConf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct LokiConf {
+    /// Where Loki listens, e.g. `http://loki:3100`. The push API's path is
+    /// used unless the URL has a path of its own.
+    pub url: String,
+
+    /// Added to the `service` and `stream` labels, or replacing them.
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+
+    /// Sent as `X-Scope-OrgID` to multi-tenant setups.
+    #[serde(default)]
+    pub tenant: Option<String>,
+
+    #[serde(default = "default_loki_batch_bytes")]
+    pub batch_bytes: usize,
+
+    /// How long the first line of a batch waits for others to join it.
+    #[serde(default = "default_loki_batch_wait_ms")]
+    pub batch_wait_ms: u64,
+
+    /// Attempts after the first one, after which a batch is dropped.
+    #[serde(default = "default_loki_max_retries")]
+    pub max_retries: u32,
+
+    #[serde(flatten)]
+    pub delivery: DeliveryConf,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 #[serde(rename_all = "lowercase")]
 pub enum ConsumerKind {
-    Log(PathBuf),
+    Log(#[serde(deserialize_with = "path_or_log_conf")] LogConf),
     StdOut,
     StdErr,
+    Tcp(TcpConf),
+    Syslog(SyslogConf),
+    Loki(LokiConf),
+    /// Feeds the stdin of the named service, for services whose `stdin` is
+    /// another service's output. It's never configured directly.
+    #[serde(skip)]
+    Stdin(String),
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+impl ConsumerKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Log(_) => "log",
+            Self::StdOut => "stdout",
+            Self::StdErr => "stderr",
+            Self::Tcp(_) => "tcp",
+            Self::Syslog(_) => "syslog",
+            Self::Loki(_) => "loki",
+            Self::Stdin(_) => "stdin",
+        }
+    }
+}
+
+/// How the time a line was written at is spelled in its prefix.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TimestampFormat {
+    /// `2026-10-17T12:00:00.123Z`
+    Rfc3339,
+    /// Seconds since the unix epoch, `1792238400.123`.
+    Unix,
+    /// Seconds on the monotonic clock, like `dmesg` does.
+    Monotonic,
+}
+
+/// What goes in front of every line a consumer writes.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PrefixConf {
+    #[serde(default)]
+    pub timestamp: Option<TimestampFormat>,
+
+    #[serde(default)]
+    pub service: bool,
+
+    #[serde(default)]
+    pub stream: bool,
+
+    /// Pad service names to the longest one, so that lines line up.
+    #[serde(default)]
+    pub pad: bool,
+
+    /// Give every service a color of its own.
+    #[serde(default)]
+    pub color: bool,
+}
+
+/// What a consumer does with output its queue has no room for.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Overflow {
+    /// Stop reading from the service until there's room.
+    Block,
+    /// Make room by dropping the output queued the longest.
+    DropOldest,
+    /// Drop the output that doesn't fit.
+    DropNewest,
+}
+
+/// Where a service's stdin comes from.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum StdinConf {
+    /// Whatever kinesin's own stdin is.
+    Inherit,
+    Null,
+    File(PathBuf),
+    /// The given text, after which stdin reaches its end.
+    Text(String),
+    /// Another service's output, as it's written. A service that falls
+    /// behind is waited for while its queue takes in what comes meanwhile,
+    /// dropping the oldest output once full, so the reading of the output
+    /// is never held up.
+    StdOut(String),
+    StdErr(String),
+}
+
+/// A resource limit, lifted with `"unlimited"`.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum RlimitValue {
+    Limit(u64),
+    Named(Unlimited),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(rename_all = "lowercase")]
+pub enum Unlimited {
+    Unlimited,
+}
+
+impl RlimitValue {
+    pub fn raw(self) -> u64 {
+        match self {
+            Self::Limit(limit) => limit,
+            Self::Named(Unlimited::Unlimited) => nix::libc::RLIM_INFINITY,
+        }
+    }
+}
+
+/// The soft and hard limits on a resource, both the same unless given apart.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+#[serde(untagged)]
+pub enum RlimitConf {
+    Both(RlimitValue),
+    Split {
+        soft: RlimitValue,
+        hard: RlimitValue,
+    },
+}
+
+impl RlimitConf {
+    pub fn soft(&self) -> RlimitValue {
+        match self {
+            Self::Both(limit) => *limit,
+            Self::Split { soft, .. } => *soft,
+        }
+    }
+
+    pub fn hard(&self) -> RlimitValue {
+        match self {
+            Self::Both(limit) => *limit,
+            Self::Split { hard, .. } => *hard,
+        }
+    }
+}
+
+/// Resource limits of a service, those left out are inherited from kinesin.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+pub struct RlimitsConf {
+    /// Open file descriptors.
+    #[serde(default)]
+    pub nofile: Option<RlimitConf>,
+    /// Processes of the user.
+    #[serde(default)]
+    pub nproc: Option<RlimitConf>,
+    /// Size of core dumps, in bytes.
+    #[serde(default)]
+    pub core: Option<RlimitConf>,
+    /// Size of the address space, in bytes.
+    #[serde(default, rename = "as")]
+    pub address_space: Option<RlimitConf>,
+    /// Size of the stack, in bytes.
+    #[serde(default)]
+    pub stack: Option<RlimitConf>,
+    /// CPU time, in seconds.
+    #[serde(default)]
+    pub cpu: Option<RlimitConf>,
+}
+
+impl RlimitsConf {
+    /// Every limit that's set, along with the name it's configured by.
+    pub fn iter(&self) -> impl Iterator<Item = (&'static str, Resource, &RlimitConf)> {
+        [
+            ("nofile", Resource::RLIMIT_NOFILE, &self.nofile),
+            ("nproc", Resource::RLIMIT_NPROC, &self.nproc),
+            ("core", Resource::RLIMIT_CORE, &self.core),
+            ("as", Resource::RLIMIT_AS, &self.address_space),
+            ("stack", Resource::RLIMIT_STACK, &self.stack),
+            ("cpu", Resource::RLIMIT_CPU, &self.cpu),
+        ]
+        .into_iter()
+        .filter_map(|(name, resource, limit)| Some((name, resource, limit.as_ref()?)))
+    }
+}
+
+/// What happens to a consumer that fails to write.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum OnError {
+    /// Lose what failed and keep trying with what comes next.
+    Retry,
+    /// Stop handing the consumer anything.
+    Disable,
+    /// Shut kinesin down.
+    Fatal,
+}
+
+/// What a consumer makes of the lines it's handed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Encoding {
+    /// Pass them on as they are.
+    Text,
+    /// One JSON object per line, with the line under `message`.
+    Json,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ConsumerConf {
     pub consumes: ProducerConf,
     pub kind: ConsumerKind,
+
+    #[serde(default)]
+    pub prefix: Option<PrefixConf>,
+
+    #[serde(default = "default_encoding")]
+    pub encoding: Encoding,
+
+    /// With JSON encoding, lines that are JSON objects themselves get their
+    /// fields merged in rather than being quoted under `message`.
+    #[serde(default)]
+    pub merge_json: bool,
+
+    /// How much output may wait for the consumer to write it.
+    #[serde(default = "default_consumer_queue_bytes")]
+    pub queue_bytes: usize,
+
+    #[serde(default = "default_overflow")]
+    pub overflow: Overflow,
+
+    #[serde(default = "default_on_error")]
+    pub on_error: OnError,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct SourceConf {
     #[serde(default = "default_src_watch")]
     pub watch: bool,
@@ -34,25 +452,278 @@ pub struct SourceConf {
 
     #[serde(default = "default_bus_bufsize")]
     pub bus_bufsize: usize,
+
+    #[serde(default = "default_bus_framing")]
+    pub framing: BusFraming,
+
+    /// With line framing, a line that grows this long is passed on unfinished.
+    #[serde(default = "default_max_line")]
+    pub max_line: usize,
+
+    /// The longest output may sit in the bus buffer before it's flushed.
+    #[serde(default)]
+    pub flush_interval_ms: Option<u64>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
+/// How a bus cuts up what it hands its consumers.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum BusFraming {
+    /// Whatever was read or buffered, even if it ends mid-line.
+    Bytes,
+    /// Only whole lines.
+    Line,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RestartPolicy {
+    Never,
+    OnFailure,
+    Always,
+    /// Like `Always`, except a service that was killed by SIGTERM or SIGINT stays down.
+    UnlessStopped,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RestartConf {
+    #[serde(default = "default_restart_policy")]
+    pub policy: RestartPolicy,
+
+    /// Total number of restarts allowed over kinesin's lifetime, unbounded if unset.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+
+    #[serde(default = "default_restart_delay_ms")]
+    pub delay_ms: u64,
+
+    #[serde(default = "default_restart_max_delay_ms")]
+    pub max_delay_ms: u64,
+
+    /// A service that stayed up at least this long starts backing off from scratch.
+    #[serde(default = "default_restart_reset_after_ms")]
+    pub reset_after_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthProbe {
+    Exec(#[serde(with = "cstrings")] Vec<CString>),
+    Tcp(String),
+    Http(String),
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum UnhealthyAction {
+    None,
+    Restart,
+    Kill,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct HealthCheckConf {
+    pub probe: HealthProbe,
+
+    #[serde(default = "default_health_interval_ms")]
+    pub interval_ms: u64,
+
+    #[serde(default = "default_health_timeout_ms")]
+    pub timeout_ms: u64,
+
+    /// Failures within this long after the service started don't count.
+    #[serde(default = "default_health_start_period_ms")]
+    pub start_period_ms: u64,
+
+    #[serde(default = "default_health_failure_threshold")]
+    pub failure_threshold: u32,
+
+    #[serde(default = "default_unhealthy_action")]
+    pub on_unhealthy: UnhealthyAction,
+}
+
+/// When the death of members of a group brings the whole container down.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum GroupPolicy {
+    /// Any member failing for good, i.e. without being restarted.
+    AnyFailed,
+    /// Every member being gone for good, whether they failed or not.
+    AllExited,
+    /// A member failing after it was already restarted `max_restarts` times.
+    MaxRestarts,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct GroupConf {
+    pub name: String,
+    pub policy: GroupPolicy,
+
+    #[serde(default)]
+    pub max_restarts: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum ServiceType {
+    Simple,
+    /// Only counts as started once it sent `READY=1` over `NOTIFY_SOCKET`.
+    Notify,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
 pub struct ServiceConf {
     pub name: String,
 
+    #[serde(default = "default_service_type", rename = "type")]
+    pub kind: ServiceType,
+
     #[serde(default = "default_src_config")]
     pub stdout: SourceConf,
 
     #[serde(default = "default_src_config")]
     pub stderr: SourceConf,
 
+    #[serde(default = "default_stdin")]
+    pub stdin: StdinConf,
+
+    #[serde(with = "cstrings")]
     pub exec: Vec<CString>,
 
-    #[serde(default = "default_cfg_env")]
+    #[serde(default = "default_cfg_env", with = "cstrings")]
     pub env: Vec<CString>,
 
+    /// User to run as instead of kinesin's, by name or uid. A uid without an
+    /// entry in `/etc/passwd` runs with `/` as its home.
+    #[serde(default)]
+    pub user: Option<String>,
+
+    /// Primary group of the user, by name or gid. It's the one `/etc/passwd`
+    /// gives the user if unset, or the gid of the same number as a uid
+    /// without an entry.
+    #[serde(default)]
+    pub user_group: Option<String>,
+
+    /// Groups to be in besides those listing the user as a member.
+    #[serde(default)]
+    pub supplementary_groups: Vec<String>,
+
+    /// Directory to start in instead of kinesin's.
+    #[serde(default)]
+    pub working_dir: Option<PathBuf>,
+
+    /// File mode creation mask, in octal like `"0027"`.
+    #[serde(default, with = "umask")]
+    pub umask: Option<u32>,
+
+    #[serde(default)]
+    pub rlimits: RlimitsConf,
+
     #[serde(default = "default_must_be_up")]
     pub must_be_up: bool,
+
+    /// Priority group deciding which deaths are acceptable, overrides `must_be_up`.
+    #[serde(default)]
+    pub group: Option<String>,
+
+    #[serde(default = "default_restart_config")]
+    pub restart: RestartConf,
+
+    #[serde(default, with = "cstrings::option")]
+    pub on_failure: Option<Vec<CString>>,
+
+    #[serde(default, with = "cstrings::option")]
+    pub on_success: Option<Vec<CString>>,
+
+    #[serde(default)]
+    pub healthcheck: Option<HealthCheckConf>,
+
+    #[serde(default)]
+    pub after: Vec<String>,
+
+    #[serde(default)]
+    pub requires: Vec<String>,
+
+    #[serde(default)]
+    pub wants: Vec<String>,
+
+    /// Wait for dependencies to be ready (e.g. healthy), not just started.
+    #[serde(default)]
+    pub wait_ready: bool,
+
+    /// Kill a notify service which doesn't send `WATCHDOG=1` this often.
+    #[serde(default)]
+    pub watchdog_ms: Option<u64>,
+
+    /// Signal asking the service to stop, e.g. `"SIGQUIT"` for nginx.
+    #[serde(default = "default_stop_signal", with = "signal_name")]
+    pub stop_signal: Signal,
+
+    /// How long the service gets to stop before it's killed, the global
+    /// `stop_timeout_ms` if unset.
+    #[serde(default)]
+    pub stop_timeout_ms: Option<u64>,
+}
+
+impl ServiceConf {
+    /// Definition of a hook job, which runs once per trigger with the
+    /// environment of the service it belongs to.
+    pub fn hook(name: String, exec: &[CString], env: &[CString]) -> Self {
+        Self {
+            name,
+            kind: default_service_type(),
+            stdout: default_src_config(),
+            stderr: default_src_config(),
+            stdin: default_stdin(),
+            exec: exec.to_vec(),
+            env: env.to_vec(),
+            user: None,
+            user_group: None,
+            supplementary_groups: Vec::new(),
+            working_dir: None,
+            umask: None,
+            rlimits: RlimitsConf::default(),
+            must_be_up: false,
+            group: None,
+            restart: default_restart_config(),
+            on_failure: None,
+            on_success: None,
+            healthcheck: None,
+            after: Vec::new(),
+            requires: Vec::new(),
+            wants: Vec::new(),
+            wait_ready: false,
+            watchdog_ms: None,
+            stop_signal: default_stop_signal(),
+            stop_timeout_ms: None,
+        }
+    }
+
+    /// Every service this one has to be started after.
+    pub fn dependencies(&self) -> impl Iterator<Item = &String> {
+        self.after.iter().chain(&self.requires).chain(&self.wants)
+    }
+
+    /// The consumer feeding the service's stdin, when that's another
+    /// service's output.
+    pub fn stdin_consumer(&self) -> Option<ConsumerConf> {
+        let consumes = match &self.stdin {
+            StdinConf::StdOut(name) => ProducerConf::StdOut(name.clone()),
+            StdinConf::StdErr(name) => ProducerConf::StdErr(name.clone()),
+            _ => return None,
+        };
+        Some(ConsumerConf {
+            consumes,
+            kind: ConsumerKind::Stdin(self.name.clone()),
+            prefix: None,
+            encoding: default_encoding(),
+            merge_json: false,
+            queue_bytes: default_consumer_queue_bytes(),
+            // blocking could stall the reader's own output, and with it the reader
+            overflow: Overflow::DropOldest,
+            on_error: default_on_error(),
+        })
+    }
 }
 
 #[derive(Serialize, Deserialize, Debug, Clone)]
@@ -63,6 +734,283 @@ pub struct Config {
 
     #[serde(default = "default_consumers")]
     pub consumer: Vec<ConsumerConf>,
+
+    #[serde(default)]
+    pub group: Vec<GroupConf>,
+
+    /// Hook run once every service is gone, right before kinesin exits.
+    #[serde(default, with = "cstrings::option")]
+    pub on_exit: Option<Vec<CString>>,
+
+    #[serde(default = "default_hook_timeout_ms")]
+    pub hook_timeout_ms: u64,
+
+    /// Where kinesin keeps its sockets.
+    #[serde(default = "default_runtime_dir")]
+    pub runtime_dir: PathBuf,
+
+    /// Default time a service gets to stop before it's killed.
+    #[serde(default = "default_stop_timeout_ms")]
+    pub stop_timeout_ms: u64,
+
+    /// Upper bound on the whole shutdown, whatever is left afterwards is killed.
+    #[serde(default = "default_shutdown_grace_ms")]
+    pub shutdown_grace_ms: u64,
+
+    /// Makes every log consumer reopen its file, e.g. after logrotate moved it.
+    #[serde(default = "default_reopen_signal", with = "signal_name")]
+    pub reopen_signal: Signal,
+
+    /// How many threads consumers write their output on.
+    #[serde(default = "default_consumer_threads")]
+    pub consumer_threads: usize,
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ConfigFormat {
+    Toml,
+    Yaml,
+    Json,
+}
+
+impl ConfigFormat {
+    /// Guess the format of a config file from its extension.
+    pub fn from_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            "json" => Some(Self::Json),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Toml(toml::de::Error),
+    Yaml(serde_yaml::Error),
+    Json(serde_json::Error),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Toml(e) => e.fmt(f),
+            Self::Yaml(e) => e.fmt(f),
+            Self::Json(e) => e.fmt(f),
+        }
+    }
+}
+
+/// Where the config comes from, so that it can be read again on reload.
+#[derive(Debug, Clone)]
+pub struct ConfigSource {
+    /// `-` stands for stdin.
+    pub path: PathBuf,
+    pub format: Option<ConfigFormat>,
+}
+
+impl ConfigSource {
+    pub fn is_stdin(&self) -> bool {
+        self.path.as_os_str() == "-"
+    }
+
+    /// How the config is referred to in messages.
+    pub fn origin(&self) -> String {
+        match self.is_stdin() {
+            true => "<stdin>".to_string(),
+            false => self.path.display().to_string(),
+        }
+    }
+
+    /// The format given, or else the one guessed from the path.
+    pub fn resolved_format(&self) -> Result<ConfigFormat, String> {
+        match (self.format, self.is_stdin()) {
+            (Some(format), _) => Ok(format),
+            (None, true) => Err("reading the config from stdin requires --format".into()),
+            (None, false) => ConfigFormat::from_path(&self.path)
+                .ok_or_else(|| format!("{}: unknown config format, pass --format", self.origin())),
+        }
+    }
+
+    /// Read and parse the config, returning it along with its source text.
+    pub fn load(&self) -> Result<(Config, String), String> {
+        let origin = self.origin();
+        let format = self.resolved_format()?;
+
+        let text = if self.is_stdin() {
+            std::io::read_to_string(std::io::stdin())
+        } else {
+            std::fs::read_to_string(&self.path)
+        };
+        let text = text.map_err(|e| format!("{}: {}", origin, e))?;
+
+        let config = Config::parse(&text, format).map_err(|e| format!("{}: {}", origin, e))?;
+        Ok((config, text))
+    }
+}
+
+impl Config {
+    pub fn parse(text: &str, format: ConfigFormat) -> Result<Self, ConfigError> {
+        match format {
+            ConfigFormat::Toml => toml::from_str(text).map_err(ConfigError::Toml),
+            // YAML would otherwise want tags like `!stdout app` for enums,
+            // whereas the other formats spell them as `{ stdout = "app" }`
+            ConfigFormat::Yaml => serde_yaml::with::singleton_map_recursive::deserialize(
+                serde_yaml::Deserializer::from_str(text),
+            )
+            .map_err(ConfigError::Yaml),
+            ConfigFormat::Json => serde_json::from_str(text).map_err(ConfigError::Json),
+        }
+    }
+
+    /// The config as a plain document, with every key as it was written.
+    pub fn parse_document(text: &str, format: ConfigFormat) -> Result<Value, ConfigError> {
+        match format {
+            ConfigFormat::Toml => toml::from_str(text).map_err(ConfigError::Toml),
+            ConfigFormat::Yaml => serde_yaml::from_str(text).map_err(ConfigError::Yaml),
+            ConfigFormat::Json => serde_json::from_str(text).map_err(ConfigError::Json),
+        }
+    }
+}
+
+/// (De)serialize commands and environments as plain strings. serde handles
+/// `CString` as bytes, which YAML has no notion of.
+mod cstrings {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+    use std::ffi::CString;
+
+    pub fn serialize<S>(strings: &[CString], serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.collect_seq(strings.iter().map(|s| s.to_string_lossy()))
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Vec<CString>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        Vec::<String>::deserialize(deserializer)?
+            .into_iter()
+            .map(|s| CString::new(s).map_err(D::Error::custom))
+            .collect()
+    }
+
+    pub mod option {
+        use serde::{Deserialize, Deserializer, Serializer};
+        use std::ffi::CString;
+
+        pub fn serialize<S>(
+            strings: &Option<Vec<CString>>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error>
+        where
+            S: Serializer,
+        {
+            match strings {
+                Some(strings) => super::serialize(strings, serializer),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<Vec<CString>>, D::Error>
+        where
+            D: Deserializer<'de>,
+        {
+            #[derive(Deserialize)]
+            struct Wrapper(#[serde(with = "super")] Vec<CString>);
+
+            Ok(Option::<Wrapper>::deserialize(deserializer)?.map(|Wrapper(strings)| strings))
+        }
+    }
+}
+
+fn path_or_log_conf<'de, D>(deserializer: D) -> Result<LogConf, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum PathOrConf {
+        Path(PathBuf),
+        Conf(LogConf),
+    }
+
+    Ok(match PathOrConf::deserialize(deserializer)? {
+        PathOrConf::Path(path) => LogConf {
+            path,
+            max_bytes: None,
+            rotate_interval_ms: None,
+            keep: default_log_keep(),
+            naming: default_log_naming(),
+            compress: false,
+        },
+        PathOrConf::Conf(conf) => conf,
+    })
+}
+
+/// (De)serialize a umask as a string of octal digits, the way it's usually
+/// written, which JSON and YAML have no other way of spelling.
+mod umask {
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(umask: &Option<u32>, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        match umask {
+            Some(umask) => serializer.serialize_str(&format!("{:04o}", umask)),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let Some(digits) = Option::<String>::deserialize(deserializer)? else {
+            return Ok(None);
+        };
+        match u32::from_str_radix(&digits, 8) {
+            Ok(umask) if umask <= 0o777 => Ok(Some(umask)),
+            _ => Err(D::Error::custom(format!(
+                "umask {} isn't an octal mode like \"0027\"",
+                digits
+            ))),
+        }
+    }
+}
+
+/// (De)serialize signals by name, with or without the `SIG` prefix.
+pub mod signal_name {
+    use nix::sys::signal::Signal;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S>(sig: &Signal, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: Serializer,
+    {
+        serializer.serialize_str(sig.as_str())
+    }
+
+    pub fn deserialize<'de, D>(deserializer: D) -> Result<Signal, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let name = String::deserialize(deserializer)?;
+        parse(&name).map_err(D::Error::custom)
+    }
+
+    pub fn parse(name: &str) -> Result<Signal, String> {
+        let name = name.to_uppercase();
+        let name = if name.starts_with("SIG") {
+            name
+        } else {
+            format!("SIG{}", name)
+        };
+        name.parse().map_err(|_| format!("unknown signal {}", name))
+    }
 }
 
 fn default_src_config() -> SourceConf {
@@ -70,17 +1018,170 @@ fn default_src_config() -> SourceConf {
         watch: default_src_watch(),
         read_bufsize: default_read_bufsize(),
         bus_bufsize: default_bus_bufsize(),
+        framing: default_bus_framing(),
+        max_line: default_max_line(),
+        flush_interval_ms: None,
+    }
+}
+
+fn default_restart_config() -> RestartConf {
+    RestartConf {
+        policy: default_restart_policy(),
+        max_attempts: None,
+        delay_ms: default_restart_delay_ms(),
+        max_delay_ms: default_restart_max_delay_ms(),
+        reset_after_ms: default_restart_reset_after_ms(),
     }
 }
 
+fn default_restart_policy() -> RestartPolicy {
+    RestartPolicy::Never
+}
+
+fn default_restart_delay_ms() -> u64 {
+    100
+}
+
+fn default_restart_max_delay_ms() -> u64 {
+    30_000
+}
+
+fn default_restart_reset_after_ms() -> u64 {
+    60_000
+}
+
+fn default_syslog_server() -> SyslogServer {
+    SyslogServer::Unix(PathBuf::from("/dev/log"))
+}
+
+fn default_syslog_format() -> SyslogFormat {
+    SyslogFormat::Rfc5424
+}
+
+fn default_syslog_facility() -> SyslogFacility {
+    SyslogFacility::User
+}
+
+fn default_log_keep() -> usize {
+    5
+}
+
+fn default_log_naming() -> LogNaming {
+    LogNaming::Index
+}
+
+fn default_loki_batch_bytes() -> usize {
+    256 * 1024
+}
+
+fn default_loki_batch_wait_ms() -> u64 {
+    1_000
+}
+
+fn default_loki_max_retries() -> u32 {
+    10
+}
+
+fn default_net_framing() -> NetFraming {
+    NetFraming::None
+}
+
+fn default_net_queue_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_net_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_net_reconnect_ms() -> u64 {
+    500
+}
+
+fn default_net_max_reconnect_ms() -> u64 {
+    30_000
+}
+
 fn default_consumers() -> Vec<ConsumerConf> {
     Vec::new()
 }
 
+fn default_encoding() -> Encoding {
+    Encoding::Text
+}
+
+fn default_consumer_queue_bytes() -> usize {
+    1024 * 1024
+}
+
+fn default_overflow() -> Overflow {
+    Overflow::Block
+}
+
+fn default_on_error() -> OnError {
+    OnError::Retry
+}
+
+fn default_consumer_threads() -> usize {
+    2
+}
+
+fn default_service_type() -> ServiceType {
+    ServiceType::Simple
+}
+
+pub fn default_runtime_dir() -> PathBuf {
+    std::env::temp_dir().join("kinesin")
+}
+
+fn default_health_interval_ms() -> u64 {
+    30_000
+}
+
+fn default_health_timeout_ms() -> u64 {
+    5_000
+}
+
+fn default_health_start_period_ms() -> u64 {
+    0
+}
+
+fn default_health_failure_threshold() -> u32 {
+    3
+}
+
+fn default_unhealthy_action() -> UnhealthyAction {
+    UnhealthyAction::None
+}
+
+fn default_stop_signal() -> Signal {
+    Signal::SIGTERM
+}
+
+fn default_stop_timeout_ms() -> u64 {
+    10_000
+}
+
+fn default_shutdown_grace_ms() -> u64 {
+    30_000
+}
+
+fn default_reopen_signal() -> Signal {
+    Signal::SIGUSR2
+}
+
+fn default_hook_timeout_ms() -> u64 {
+    30_000
+}
+
 fn default_cfg_ver() -> u32 {
     1
 }
 
+fn default_stdin() -> StdinConf {
+    StdinConf::Inherit
+}
+
 fn default_must_be_up() -> bool {
     true
 }
@@ -97,6 +1198,154 @@ fn default_bus_bufsize() -> usize {
     0
 }
 
+fn default_bus_framing() -> BusFraming {
+    BusFraming::Bytes
+}
+
+fn default_max_line() -> usize {
+    16 * 1024
+}
+
 fn default_cfg_env() -> Vec<CString> {
     Vec::new()
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TOML: &str = r#"
+        reopen_signal = "SIGUSR1"
+        on_exit = ["/bin/echo", "bye"]
+
+        [[service]]
+        name = "app"
+        exec = ["/usr/bin/app", "--serve"]
+        env = ["MODE=prod"]
+        stop_signal = "SIGINT"
+        umask = "0027"
+        rlimits = { nofile = 4096, core = "unlimited", nproc = { soft = 64, hard = "unlimited" } }
+        restart = { policy = "on-failure", max_attempts = 3 }
+        stdout = { framing = "line", max_line = 1024 }
+        healthcheck = { probe = { http = "http://localhost:8080/health" }, on_unhealthy = "restart" }
+
+        [[service]]
+        name = "filter"
+        exec = ["/bin/cat"]
+        stdin = { stdout = "app" }
+        after = ["app"]
+
+        [[consumer]]
+        consumes = { stderr = "app" }
+        kind = { tcp = { address = "logs:5000", framing = "length" } }
+    "#;
+
+    const YAML: &str = r#"
+reopen_signal: SIGUSR1
+on_exit: [/bin/echo, bye]
+service:
+  - name: app
+    exec: [/usr/bin/app, --serve]
+    env: [MODE=prod]
+    stop_signal: SIGINT
+    umask: "0027"
+    rlimits:
+      nofile: 4096
+      core: unlimited
+      nproc: { soft: 64, hard: unlimited }
+    restart: { policy: on-failure, max_attempts: 3 }
+    stdout: { framing: line, max_line: 1024 }
+    healthcheck:
+      probe: { http: "http://localhost:8080/health" }
+      on_unhealthy: restart
+  - name: filter
+    exec: [/bin/cat]
+    stdin: { stdout: app }
+    after: [app]
+consumer:
+  - consumes: { stderr: app }
+    kind:
+      tcp: { address: "logs:5000", framing: length }
+"#;
+
+    const JSON: &str = r#"{
+        "reopen_signal": "SIGUSR1",
+        "on_exit": ["/bin/echo", "bye"],
+        "service": [
+            {
+                "name": "app",
+                "exec": ["/usr/bin/app", "--serve"],
+                "env": ["MODE=prod"],
+                "stop_signal": "SIGINT",
+                "umask": "0027",
+                "rlimits": {
+                    "nofile": 4096,
+                    "core": "unlimited",
+                    "nproc": { "soft": 64, "hard": "unlimited" }
+                },
+                "restart": { "policy": "on-failure", "max_attempts": 3 },
+                "stdout": { "framing": "line", "max_line": 1024 },
+                "healthcheck": {
+                    "probe": { "http": "http://localhost:8080/health" },
+                    "on_unhealthy": "restart"
+                }
+            },
+            {
+                "name": "filter",
+                "exec": ["/bin/cat"],
+                "stdin": { "stdout": "app" },
+                "after": ["app"]
+            }
+        ],
+        "consumer": [
+            {
+                "consumes": { "stderr": "app" },
+                "kind": { "tcp": { "address": "logs:5000", "framing": "length" } }
+            }
+        ]
+    }"#;
+
+    /// Configs don't compare, what they serialize to does.
+    fn parse(text: &str, format: ConfigFormat) -> serde_json::Value {
+        let config = Config::parse(text, format).unwrap();
+        serde_json::to_value(config).unwrap()
+    }
+
+    #[test]
+    fn formats_are_equivalent() {
+        let toml = parse(TOML, ConfigFormat::Toml);
+        assert_eq!(parse(YAML, ConfigFormat::Yaml), toml);
+        assert_eq!(parse(JSON, ConfigFormat::Json), toml);
+    }
+
+    #[test]
+    fn yaml_values_are_typed() {
+        let config = Config::parse(YAML, ConfigFormat::Yaml).unwrap();
+        assert_eq!(config.reopen_signal, Signal::SIGUSR1);
+        let app = &config.service[0];
+        assert_eq!(app.exec[1].to_str().unwrap(), "--serve");
+        assert_eq!(app.stop_signal, Signal::SIGINT);
+        assert_eq!(app.umask, Some(0o027));
+        assert_eq!(app.restart.max_attempts, Some(3));
+        let nofile = app.rlimits.nofile.unwrap();
+        assert_eq!(nofile.soft(), RlimitValue::Limit(4096));
+        let nproc = app.rlimits.nproc.unwrap();
+        assert_eq!(nproc.soft(), RlimitValue::Limit(64));
+        assert_eq!(nproc.hard().raw(), nix::libc::RLIM_INFINITY);
+        assert_eq!(
+            config.service[1].stdin,
+            StdinConf::StdOut("app".to_string())
+        );
+    }
+
+    #[test]
+    fn format_is_guessed_from_the_extension() {
+        let guess = |path: &str| ConfigFormat::from_path(Path::new(path));
+        assert_eq!(guess("kinesin.toml"), Some(ConfigFormat::Toml));
+        assert_eq!(guess("kinesin.yml"), Some(ConfigFormat::Yaml));
+        assert_eq!(guess("kinesin.yaml"), Some(ConfigFormat::Yaml));
+        assert_eq!(guess("kinesin.json"), Some(ConfigFormat::Json));
+        assert_eq!(guess("kinesin.ini"), None);
+        assert_eq!(guess("kinesin"), None);
+    }
+}
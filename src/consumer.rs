@@ -4,60 +4,623 @@
 //! to do whatever they want with that data. Since different consumers must live
 //! together in the same container, an overall Consumer enum has to exist which
 //! encapsulates at runtime the differences between the real consumers.
+use crate::{
+    conf::{ConsumerConf, ConsumerKind, Encoding, LogConf, LogNaming},
+    format::{JsonLines, Prefix},
+    loki::LokiConsumer,
+    net::TcpConsumer,
+    syslog::SyslogConsumer,
+    utils::CivilTime,
+};
+use flate2::{write::GzEncoder, Compression};
+use nix::{
+    errno::Errno,
+    poll::{poll, PollFd, PollFlags, PollTimeout},
+    unistd::Pid,
+};
 use std::{
-    fs::{File, OpenOptions},
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
     io::{self, Write},
+    os::{fd::AsFd, unix::fs::MetadataExt},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
 };
 
 pub struct FileLogger {
     file: File,
+    conf: LogConf,
+    opened_at: Instant,
+    /// Compression of the file rotated last, while it's still going.
+    compressing: Option<JoinHandle<()>>,
+}
+
+/// `path` with `suffix` tacked onto its file name.
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(suffix);
+    PathBuf::from(name)
+}
+
+fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn compress(path: &Path) -> io::Result<()> {
+    let mut input = File::open(path)?;
+    let output = File::create(with_suffix(path, ".gz"))?;
+    let mut encoder = GzEncoder::new(output, Compression::default());
+    io::copy(&mut input, &mut encoder)?;
+    encoder.finish()?;
+    fs::remove_file(path)
 }
 
 impl FileLogger {
-    pub fn new<T>(path: T) -> io::Result<Self>
-    where
-        T: AsRef<std::path::Path>,
-    {
-        let file = OpenOptions::new()
+    pub fn new(conf: &LogConf) -> io::Result<Self> {
+        Ok(Self {
+            file: Self::open(&conf.path)?,
+            conf: conf.clone(),
+            opened_at: Instant::now(),
+            compressing: None,
+        })
+    }
+
+    fn open(path: &Path) -> io::Result<File> {
+        OpenOptions::new()
             .create(true)
             .append(true)
-            .open(&path)
+            .open(path)
             .map_err(|e| {
                 eprintln!("Failed to open log file: {}", e);
                 e
-            })?;
+            })
+    }
+
+    fn needs_rotation(&self, incoming: usize) -> io::Result<bool> {
+        if let Some(max_bytes) = self.conf.max_bytes {
+            let size = self.file.metadata()?.len();
+            if size > 0 && size + incoming as u64 > max_bytes {
+                return Ok(true);
+            }
+        }
+        Ok(self
+            .conf
+            .rotate_interval_ms
+            .is_some_and(|ms| self.opened_at.elapsed() >= Duration::from_millis(ms)))
+    }
 
-        Ok(Self { file })
+    /// Whether the path no longer leads to our file, because another logger
+    /// of the same file rotated it already.
+    fn rotated_elsewhere(&self) -> io::Result<bool> {
+        let ours = self.file.metadata()?;
+        Ok(match fs::metadata(&self.conf.path) {
+            Ok(current) => (current.dev(), current.ino()) != (ours.dev(), ours.ino()),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => true,
+            Err(e) => return Err(e),
+        })
+    }
+
+    /// Make room for one more rotated file, returning the name it gets.
+    fn make_room(&self) -> io::Result<PathBuf> {
+        let path = &self.conf.path;
+        let keep = self.conf.keep;
+        match self.conf.naming {
+            LogNaming::Index => {
+                for suffix in ["", ".gz"] {
+                    remove_if_exists(&with_suffix(path, &format!(".{}{}", keep, suffix)))?;
+                    for idx in (1..keep).rev() {
+                        let from = with_suffix(path, &format!(".{}{}", idx, suffix));
+                        if from.exists() {
+                            fs::rename(
+                                from,
+                                with_suffix(path, &format!(".{}{}", idx + 1, suffix)),
+                            )?;
+                        }
+                    }
+                }
+                Ok(with_suffix(path, ".1"))
+            }
+            LogNaming::Timestamp => {
+                let dir = match path.parent() {
+                    Some(dir) if !dir.as_os_str().is_empty() => dir,
+                    _ => Path::new("."),
+                };
+                let prefix = format!(
+                    "{}.",
+                    path.file_name().unwrap_or_default().to_string_lossy()
+                );
+                let mut rotated = fs::read_dir(dir)?
+                    .filter_map(|entry| entry.ok())
+                    .filter(|entry| {
+                        let name = entry.file_name();
+                        let name = name.to_string_lossy();
+                        name.strip_prefix(&prefix)
+                            .is_some_and(|rest| rest.starts_with(|c: char| c.is_ascii_digit()))
+                    })
+                    .map(|entry| entry.path())
+                    .collect::<Vec<_>>();
+                // timestamps sort by name, newest last
+                rotated.sort();
+                // rotations within the same millisecond are told apart by a count,
+                // which sorts after the timestamp alone
+                let stamp = format!("{}{}", prefix, CivilTime::now().basic());
+                let last = rotated
+                    .iter()
+                    .filter_map(|old| {
+                        let rest = old.file_name()?.to_str()?.strip_prefix(&stamp)?;
+                        match rest.strip_suffix(".gz").unwrap_or(rest) {
+                            "" => Some(0),
+                            count => count.strip_prefix('-')?.parse::<u32>().ok(),
+                        }
+                    })
+                    .max();
+                let name = match last {
+                    Some(last) => format!("{}-{:03}", stamp, last + 1),
+                    None => stamp,
+                };
+                let excess = (rotated.len() + 1).saturating_sub(keep);
+                for old in &rotated[..excess.min(rotated.len())] {
+                    remove_if_exists(old)?;
+                }
+                Ok(dir.join(name))
+            }
+        }
+    }
+
+    /// Move the current file out of the way and start a new one. The new file
+    /// is opened before anything gets written, so no write is ever split
+    /// across the two.
+    fn rotate(&mut self) -> io::Result<()> {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+        if !self.rotated_elsewhere()? {
+            if self.conf.keep == 0 {
+                remove_if_exists(&self.conf.path)?;
+            } else {
+                let rotated = self.make_room()?;
+                fs::rename(&self.conf.path, &rotated)?;
+                if self.conf.compress {
+                    self.compressing = Some(thread::spawn(move || {
+                        if let Err(e) = compress(&rotated) {
+                            eprintln!("Failed to compress {}: {}", rotated.display(), e);
+                        }
+                    }));
+                }
+            }
+        }
+        self.file = Self::open(&self.conf.path)?;
+        self.opened_at = Instant::now();
+        Ok(())
+    }
+
+    /// Let go of the file and open whatever is at its path now.
+    fn reopen(&mut self) -> io::Result<()> {
+        self.file = Self::open(&self.conf.path)?;
+        self.opened_at = Instant::now();
+        Ok(())
     }
 
     fn write(&mut self, log: &[u8]) -> io::Result<()> {
+        if self.needs_rotation(log.len())? {
+            self.rotate()?;
+        }
         self.file.write_all(log)?;
         Ok(())
     }
 }
 
-pub enum Consumer {
+impl Drop for FileLogger {
+    fn drop(&mut self) {
+        if let Some(compressing) = self.compressing.take() {
+            let _ = compressing.join();
+        }
+    }
+}
+
+/// The stdin pipes of running services fed by another service's output, by
+/// service name. A service gets a new pipe every time it's started.
+#[derive(Clone, Default)]
+pub struct StdinPipes(Arc<Mutex<HashMap<String, Arc<File>>>>);
+
+impl StdinPipes {
+    pub fn set(&self, service: &str, pipe: Option<Arc<File>>) {
+        let mut pipes = self.0.lock().unwrap();
+        match pipe {
+            Some(pipe) => pipes.insert(service.to_string(), pipe),
+            None => pipes.remove(service),
+        };
+    }
+
+    fn get(&self, service: &str) -> Option<Arc<File>> {
+        self.0.lock().unwrap().get(service).cloned()
+    }
+
+    /// Whether `pipe` is still the stdin of `service`.
+    fn is_current(&self, service: &str, pipe: &Arc<File>) -> bool {
+        let pipes = self.0.lock().unwrap();
+        pipes
+            .get(service)
+            .is_some_and(|current| Arc::ptr_eq(current, pipe))
+    }
+}
+
+/// Writes output into the stdin of a service. Output is lost while the
+/// service isn't running. The pipe is non-blocking, and a service that doesn't
+/// read is waited for with poll, so that the wait ends as soon as the service
+/// stops or is restarted. One that reads nothing for `STDIN_STALL_TIMEOUT`
+/// loses what was meant for it.
+pub struct StdinWriter {
+    service: String,
+    pipes: StdinPipes,
+}
+
+/// How long a service may read none of its stdin before output gets dropped.
+const STDIN_STALL_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How often a wait for room in a stdin pipe checks the pipe is still in use.
+const STDIN_POLL_MS: u16 = 100;
+
+impl StdinWriter {
+    fn write(&self, data: &[u8]) -> io::Result<()> {
+        let Some(pipe) = self.pipes.get(&self.service) else {
+            return Ok(());
+        };
+        let mut written = 0;
+        let mut stalled_since = None;
+        while written < data.len() {
+            match (&*pipe).write(&data[written..]) {
+                Ok(len) => {
+                    written += len;
+                    stalled_since = None;
+                }
+                Err(e) if e.kind() == io::ErrorKind::Interrupted => {}
+                // the service is on its way out or closed its stdin
+                Err(e) if e.kind() == io::ErrorKind::BrokenPipe => return Ok(()),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => {
+                    let since = *stalled_since.get_or_insert_with(Instant::now);
+                    if since.elapsed() >= STDIN_STALL_TIMEOUT {
+                        let msg = format!(
+                            "service {} read none of its stdin for {}s, lost {} bytes",
+                            self.service,
+                            STDIN_STALL_TIMEOUT.as_secs(),
+                            data.len() - written
+                        );
+                        return Err(io::Error::new(e.kind(), msg));
+                    }
+                    // stopped or restarted, the service won't read this pipe again
+                    if !self.pipes.is_current(&self.service, &pipe) {
+                        return Ok(());
+                    }
+                    let mut fds = [PollFd::new(pipe.as_fd(), PollFlags::POLLOUT)];
+                    match poll(&mut fds, PollTimeout::from(STDIN_POLL_MS)) {
+                        Ok(_) | Err(Errno::EINTR) => {}
+                        Err(e) => return Err(e.into()),
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Where a consumer's output ends up.
+enum Sink {
     File(FileLogger),
     StdOut,
     StdErr,
+    Tcp(TcpConsumer),
+    Syslog(SyslogConsumer),
+    Loki(LokiConsumer),
+    Stdin(StdinWriter),
 }
 
-impl Consumer {
-    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+impl Sink {
+    fn new(conf: &ConsumerConf, pipes: &StdinPipes) -> io::Result<Self> {
+        Ok(match &conf.kind {
+            ConsumerKind::Log(log) => Self::File(FileLogger::new(log)?),
+            ConsumerKind::StdOut => Self::StdOut,
+            ConsumerKind::StdErr => Self::StdErr,
+            ConsumerKind::Tcp(tcp) => Self::Tcp(TcpConsumer::new(tcp)?),
+            ConsumerKind::Syslog(syslog) => {
+                Self::Syslog(SyslogConsumer::new(syslog, &conf.consumes)?)
+            }
+            ConsumerKind::Loki(loki) => Self::Loki(LokiConsumer::new(loki, &conf.consumes)?),
+            ConsumerKind::Stdin(service) => Self::Stdin(StdinWriter {
+                service: service.clone(),
+                pipes: pipes.clone(),
+            }),
+        })
+    }
+
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
         match self {
             Self::File(x) => x.write(bytes),
             Self::StdOut => {
                 let stdout = io::stdout();
                 let mut handle = stdout.lock();
-                handle.write(bytes)?;
+                handle.write_all(bytes)?;
                 Ok(())
             }
             Self::StdErr => {
                 let stderr = io::stderr();
                 let mut handle = stderr.lock();
-                handle.write(bytes)?;
+                handle.write_all(bytes)?;
+                Ok(())
+            }
+            Self::Tcp(x) => {
+                x.write(bytes);
+                Ok(())
+            }
+            Self::Syslog(x) => {
+                x.write(bytes);
+                Ok(())
+            }
+            Self::Loki(x) => {
+                x.write(bytes);
                 Ok(())
             }
+            Self::Stdin(x) => x.write(bytes),
+        }
+    }
+}
+
+/// How a consumer reworks output before its sink gets it.
+enum Format {
+    Raw,
+    Prefix(Prefix),
+    Json(JsonLines),
+}
+
+pub struct Consumer {
+    sink: Sink,
+    format: Format,
+}
+
+impl Consumer {
+    /// `name_width` is what service names get padded to in prefixes, and
+    /// `pipes` is where stdin consumers find the service they feed.
+    pub fn new(conf: &ConsumerConf, name_width: usize, pipes: &StdinPipes) -> io::Result<Self> {
+        let format = match (conf.encoding, &conf.prefix) {
+            (Encoding::Json, _) => Format::Json(JsonLines::new(&conf.consumes, conf.merge_json)),
+            (Encoding::Text, Some(prefix)) => {
+                Format::Prefix(Prefix::new(prefix, &conf.consumes, name_width))
+            }
+            (Encoding::Text, None) => Format::Raw,
+        };
+        Ok(Self {
+            sink: Sink::new(conf, pipes)?,
+            format,
+        })
+    }
+
+    /// Note which process the output comes from now.
+    pub fn set_pid(&mut self, pid: Option<Pid>) {
+        if let Format::Json(json) = &mut self.format {
+            json.set_pid(pid);
+        }
+    }
+
+    pub fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match &mut self.format {
+            Format::Raw => self.sink.write(bytes),
+            Format::Prefix(prefix) => self.sink.write(&prefix.apply(bytes)),
+            Format::Json(json) => {
+                let encoded = json.apply(bytes);
+                match encoded.is_empty() {
+                    true => Ok(()),
+                    false => self.sink.write(&encoded),
+                }
+            }
+        }
+    }
+
+    /// Write out whatever the format is holding back.
+    fn finish(&mut self) -> io::Result<()> {
+        match &mut self.format {
+            Format::Json(json) => {
+                let rest = json.finish();
+                match rest.is_empty() {
+                    true => Ok(()),
+                    false => self.sink.write(&rest),
+                }
+            }
+            _ => Ok(()),
+        }
+    }
+
+    pub fn reopen(&mut self) -> io::Result<()> {
+        match &mut self.sink {
+            Sink::File(x) => x.reopen(),
+            _ => Ok(()),
+        }
+    }
+
+    /// Give output that's still on its way until `deadline` to get there.
+    pub fn drain(&mut self, deadline: Instant) {
+        if let Err(e) = self.finish() {
+            eprintln!("Failed to write the last line: {}", e);
+        }
+        match &mut self.sink {
+            Sink::Tcp(x) => x.drain(deadline),
+            Sink::Syslog(x) => x.drain(deadline),
+            Sink::Loki(x) => x.drain(deadline),
+            _ => (),
+        }
+    }
+}
+
+impl Drop for Consumer {
+    fn drop(&mut self) {
+        if let Err(e) = self.finish() {
+            eprintln!("Failed to write the last line: {}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::utils::set_fd_nonblocking;
+    use flate2::read::GzDecoder;
+    use nix::unistd::pipe;
+    use std::{collections::BTreeSet, io::Read, os::fd::AsRawFd};
+
+    /// A fresh directory for the logs of one test.
+    fn log_dir(test: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("kinesin-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn logger(dir: &Path, extra: &str) -> FileLogger {
+        let conf = format!("path = \"{}\"\n{}", dir.join("app.log").display(), extra);
+        FileLogger::new(&toml::from_str::<LogConf>(&conf).unwrap()).unwrap()
+    }
+
+    /// Names in `dir`, sorted.
+    fn names(dir: &Path) -> Vec<String> {
+        let mut names = fs::read_dir(dir)
+            .unwrap()
+            .map(|entry| entry.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        names.sort();
+        names
+    }
+
+    fn read(path: &Path) -> String {
+        fs::read_to_string(path).unwrap()
+    }
+
+    #[test]
+    fn index_naming_shifts_older_files_up() {
+        let dir = log_dir("index");
+        let mut log = logger(&dir, "max_bytes = 8\nkeep = 2");
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        assert_eq!(names(&dir), ["app.log", "app.log.1", "app.log.2"]);
+        assert_eq!(read(&dir.join("app.log")), "fourth\n");
+        assert_eq!(read(&dir.join("app.log.1")), "third\n");
+        assert_eq!(read(&dir.join("app.log.2")), "second\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn timestamp_naming_keeps_the_newest_files() {
+        let dir = log_dir("timestamp");
+        let mut log = logger(&dir, "max_bytes = 8\nkeep = 2\nnaming = \"timestamp\"");
+        for line in ["first\n", "second\n", "third\n", "fourth\n"] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        let names = names(&dir);
+        assert_eq!(names.len(), 3);
+        assert_eq!(names[0], "app.log");
+        for name in &names[1..] {
+            let stamp = name.strip_prefix("app.log.").unwrap();
+            let (stamp, _count) = stamp.split_once('-').unwrap_or((stamp, ""));
+            assert_eq!(stamp.len(), "20250101T120000.000Z".len());
+            assert!(stamp.ends_with('Z'));
+        }
+        assert_eq!(read(&dir.join(&names[1])), "second\n");
+        assert_eq!(read(&dir.join(&names[2])), "third\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn rotated_files_get_compressed() {
+        let dir = log_dir("compress");
+        let mut log = logger(&dir, "max_bytes = 8\nkeep = 2\ncompress = true");
+        for line in ["first\n", "second\n", "third\n"] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        // compression is waited for on the way out
+        drop(log);
+        assert_eq!(names(&dir), ["app.log", "app.log.1.gz", "app.log.2.gz"]);
+        let mut unpacked = String::new();
+        GzDecoder::new(File::open(dir.join("app.log.1.gz")).unwrap())
+            .read_to_string(&mut unpacked)
+            .unwrap();
+        assert_eq!(unpacked, "second\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn keeping_nothing_truncates() {
+        let dir = log_dir("keep-none");
+        let mut log = logger(&dir, "max_bytes = 8\nkeep = 0");
+        for line in ["first\n", "second\n"] {
+            log.write(line.as_bytes()).unwrap();
+        }
+        assert_eq!(names(&dir), ["app.log"]);
+        assert_eq!(read(&dir.join("app.log")), "second\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A writer into the stdin pipe of `app`, along with the pipe's read end.
+    fn stdin_pipe() -> (StdinWriter, File) {
+        let (read, write) = pipe().unwrap();
+        set_fd_nonblocking(write.as_raw_fd()).unwrap();
+        let pipes = StdinPipes::default();
+        pipes.set("app", Some(Arc::new(File::from(write))));
+        let writer = StdinWriter {
+            service: "app".to_string(),
+            pipes,
+        };
+        (writer, File::from(read))
+    }
+
+    #[test]
+    fn slow_stdin_readers_are_waited_for() {
+        let (writer, mut read) = stdin_pipe();
+        let reader = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            let mut got = Vec::new();
+            read.read_to_end(&mut got).unwrap();
+            got
+        });
+        // several times what a pipe holds
+        let data = (0..1024 * 1024).map(|idx| idx as u8).collect::<Vec<_>>();
+        writer.write(&data).unwrap();
+        writer.pipes.set("app", None);
+        drop(writer);
+        assert!(reader.join().unwrap() == data);
+    }
+
+    #[test]
+    fn stopped_services_are_not_waited_for() {
+        let (writer, _read) = stdin_pipe();
+        let pipes = writer.pipes.clone();
+        let stopper = thread::spawn(move || {
+            thread::sleep(Duration::from_millis(200));
+            pipes.set("app", None);
+        });
+        let started = Instant::now();
+        writer.write(&vec![b'x'; 1024 * 1024]).unwrap();
+        assert!(started.elapsed() < STDIN_STALL_TIMEOUT);
+        stopper.join().unwrap();
+    }
+
+    #[test]
+    fn rotations_in_the_same_millisecond_keep_every_file() {
+        let dir = log_dir("same-millisecond");
+        let mut log = logger(&dir, "max_bytes = 2\nkeep = 20\nnaming = \"timestamp\"");
+        for idx in 0..10 {
+            log.write(format!("{}\n", idx).as_bytes()).unwrap();
         }
+        let rotated = fs::read_dir(&dir)
+            .unwrap()
+            .map(|entry| read(&entry.unwrap().path()))
+            .collect::<BTreeSet<_>>();
+        let written = (0..10).map(|idx| format!("{}\n", idx)).collect();
+        assert_eq!(rotated, written);
+        fs::remove_dir_all(dir).unwrap();
     }
 }
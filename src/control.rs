@@ -0,0 +1,405 @@
+//! Runtime management of a running kinesin through a unix socket.
+//!
+//! The control socket speaks newline delimited JSON: a client connects, sends a
+//! single request and gets a single response back, after which kinesin shuts
+//! down its end of the connection. Connections are read by the watcher like any
+//! other fd, so a slow client never holds up the event loop.
+use crate::{
+    bus::BusMap,
+    conf::signal_name,
+    health::HealthStatus,
+    registry::Registry,
+    service::{Service, State},
+    utils::create_private_dir,
+};
+use nix::{errno::Errno, sys::signal::Signal};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt, fs,
+    io::{self, Read, Write},
+    net::Shutdown,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::{UnixListener, UnixStream},
+    },
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+pub const CONTROL_BUFSIZE: usize = 4096;
+
+/// Requests are tiny, anything longer than this is refused.
+const MAX_REQUEST_LEN: usize = 64 * 1024;
+
+const WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Serialize, Deserialize, Debug)]
+#[serde(tag = "command", rename_all = "lowercase")]
+pub enum Request {
+    Status,
+    Start {
+        service: String,
+    },
+    Stop {
+        service: String,
+    },
+    Restart {
+        service: String,
+    },
+    Signal {
+        service: String,
+        #[serde(with = "signal_name")]
+        signal: Signal,
+    },
+    /// Re-read the config, answered once it's been applied or rejected.
+    Reload,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ServiceStatus {
+    pub name: String,
+    pub state: State,
+    pub pid: Option<i32>,
+    pub uptime_ms: Option<u64>,
+    pub restarts: u32,
+    pub last_exit: Option<String>,
+    pub hook: bool,
+    /// Only reported for running services with a health check.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub health: Option<HealthStatus>,
+    /// Probes failed in a row.
+    #[serde(default)]
+    pub health_failures: u32,
+}
+
+impl From<&Service> for ServiceStatus {
+    fn from(srvc: &Service) -> Self {
+        let health = srvc
+            .health
+            .as_ref()
+            .filter(|_| srvc.state == State::Running);
+        Self {
+            name: srvc.name.clone(),
+            state: srvc.state,
+            pid: srvc.pid.map(|pid| pid.as_raw()),
+            uptime_ms: srvc.uptime().map(|uptime| uptime.as_millis() as u64),
+            restarts: srvc.restarts,
+            last_exit: srvc.last_exit.map(|status| status.to_string()),
+            hook: srvc.hook,
+            health: health.map(|health| health.status),
+            health_failures: health.map_or(0, |health| health.failures),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ConsumerStatus {
+    pub service: String,
+    pub stream: String,
+    pub kind: String,
+    /// `ok`, `failing` or `disabled`.
+    pub state: String,
+    pub queued_bytes: usize,
+    pub dropped_bytes: u64,
+    pub failures: u64,
+    pub last_error: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default)]
+pub struct Response {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub services: Vec<ServiceStatus>,
+
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub consumers: Vec<ConsumerStatus>,
+}
+
+impl Response {
+    fn error(msg: String) -> Self {
+        Self {
+            error: Some(msg),
+            ..Self::default()
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum ControlError {
+    UnknownService(String),
+    Hook(String),
+    AlreadyRunning(String),
+    NotRunning(String),
+    ShuttingDown,
+    Sys(Errno),
+}
+
+impl fmt::Display for ControlError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::UnknownService(name) => write!(f, "no service named {}", name),
+            Self::Hook(name) => write!(f, "{} is a hook, it only runs when triggered", name),
+            Self::AlreadyRunning(name) => write!(f, "service {} is already running", name),
+            Self::NotRunning(name) => write!(f, "service {} isn't running", name),
+            Self::ShuttingDown => write!(f, "kinesin is shutting down"),
+            Self::Sys(e) => e.fmt(f),
+        }
+    }
+}
+
+impl From<Errno> for ControlError {
+    fn from(e: Errno) -> Self {
+        Self::Sys(e)
+    }
+}
+
+/// Carry out a request against the registry. Services which are started get
+/// picked up the next time the registry settles.
+fn handle(registry: &mut Registry, buses: &BusMap, request: Request) -> Response {
+    let result = match request {
+        Request::Status => {
+            return Response {
+                services: registry.services.iter().map(ServiceStatus::from).collect(),
+                consumers: buses.consumer_statuses(),
+                ..Response::default()
+            }
+        }
+        Request::Start { service } => registry.start_service(&service),
+        Request::Stop { service } => registry.stop_service(&service),
+        Request::Restart { service } => registry.restart_service(&service),
+        Request::Signal { service, signal } => registry.signal_service(&service, signal),
+        Request::Reload => unreachable!("reloads are left to the runner"),
+    };
+    match result {
+        Ok(()) => Response::default(),
+        Err(e) => Response::error(e.to_string()),
+    }
+}
+
+struct Connection {
+    stream: UnixStream,
+    request: Vec<u8>,
+    answered: bool,
+}
+
+pub struct ControlServer {
+    listener: UnixListener,
+    path: PathBuf,
+    conns: HashMap<RawFd, Connection>,
+    /// Connections waiting for a reload to be carried out.
+    reloads: Vec<RawFd>,
+}
+
+impl ControlServer {
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir)?;
+        }
+        // a previous run may have left its socket behind
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let listener = UnixListener::bind(&path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path,
+            conns: HashMap::new(),
+            reloads: Vec::new(),
+        })
+    }
+
+    pub fn socket_path(dir: &Path) -> PathBuf {
+        dir.join("control.sock")
+    }
+
+    pub fn owns(&self, fd: RawFd) -> bool {
+        self.conns.contains_key(&fd)
+    }
+
+    /// Accept every pending connection, returning the fds to watch.
+    pub fn accept(&mut self) -> io::Result<Vec<RawFd>> {
+        let mut accepted = Vec::new();
+        loop {
+            match self.listener.accept() {
+                Ok((stream, _)) => {
+                    stream.set_nonblocking(true)?;
+                    let fd = stream.as_raw_fd();
+                    let conn = Connection {
+                        stream,
+                        request: Vec::new(),
+                        answered: false,
+                    };
+                    self.conns.insert(fd, conn);
+                    accepted.push(fd);
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(accepted),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    /// Feed data read from a connection, answering its request once complete.
+    /// Misbehaving clients only ever cost them their connection.
+    pub fn received(&mut self, fd: RawFd, data: &[u8], registry: &mut Registry, buses: &BusMap) {
+        let Some(conn) = self.conns.get_mut(&fd) else {
+            return;
+        };
+        if conn.answered {
+            return;
+        }
+        conn.request.extend_from_slice(data);
+        let response = match conn.request.iter().position(|&b| b == b'\n') {
+            Some(end) => match serde_json::from_slice(&conn.request[..end]) {
+                Ok(Request::Reload) => {
+                    conn.answered = true;
+                    self.reloads.push(fd);
+                    return;
+                }
+                Ok(request) => handle(registry, buses, request),
+                Err(e) => Response::error(format!("bad request: {}", e)),
+            },
+            None if conn.request.len() > MAX_REQUEST_LEN => {
+                Response::error("request too long".to_string())
+            }
+            None => return,
+        };
+        conn.answered = true;
+        if let Err(e) = respond(&mut conn.stream, &response) {
+            eprintln!("Failed to answer control request: {}", e);
+        }
+    }
+
+    pub fn reload_requested(&self) -> bool {
+        !self.reloads.is_empty()
+    }
+
+    /// Tell every client waiting for a reload how it went.
+    pub fn reloaded(&mut self, result: Result<(), String>) {
+        let response = match result {
+            Ok(()) => Response::default(),
+            Err(e) => Response::error(e),
+        };
+        for fd in std::mem::take(&mut self.reloads) {
+            if let Some(conn) = self.conns.get_mut(&fd) {
+                if let Err(e) = respond(&mut conn.stream, &response) {
+                    eprintln!("Failed to answer control request: {}", e);
+                }
+            }
+        }
+    }
+
+    /// Forget a connection the client closed, returning whether it was ours.
+    pub fn closed(&mut self, fd: RawFd) -> bool {
+        self.conns.remove(&fd).is_some()
+    }
+}
+
+fn respond(stream: &mut UnixStream, response: &Response) -> io::Result<()> {
+    // responses are small, so block for them instead of queueing them up
+    stream.set_nonblocking(false)?;
+    stream.set_write_timeout(Some(WRITE_TIMEOUT))?;
+    let mut line = serde_json::to_vec(response)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    stream.shutdown(Shutdown::Write)?;
+    stream.set_nonblocking(true)
+}
+
+impl AsRawFd for ControlServer {
+    fn as_raw_fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+}
+
+impl Drop for ControlServer {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
+
+/// Send a single request to the kinesin listening on `path`.
+pub fn request(path: &Path, request: &Request) -> io::Result<Response> {
+    let mut stream = UnixStream::connect(path)?;
+    let mut line = serde_json::to_vec(request)?;
+    line.push(b'\n');
+    stream.write_all(&line)?;
+    let mut response = String::new();
+    stream.read_to_string(&mut response)?;
+    Ok(serde_json::from_str(&response)?)
+}
+
+fn format_uptime(ms: u64) -> String {
+    let secs = ms / 1000;
+    match secs {
+        0..60 => format!("{}.{}s", secs, ms % 1000 / 100),
+        60..3600 => format!("{}m{:02}s", secs / 60, secs % 60),
+        _ => format!("{}h{:02}m{:02}s", secs / 3600, secs / 60 % 60, secs % 60),
+    }
+}
+
+pub fn print_status(response: &Response) {
+    let services = &response.services;
+    let width = services
+        .iter()
+        .map(|srvc| srvc.name.len())
+        .fold("NAME".len(), usize::max);
+    println!(
+        "{:width$}  {:10}  {:13}  {:>7}  {:>10}  {:>8}  LAST EXIT",
+        "NAME", "STATE", "HEALTH", "PID", "UPTIME", "RESTARTS"
+    );
+    for srvc in services {
+        let state = serde_json::to_value(srvc.state).unwrap();
+        let health = match srvc.health {
+            Some(health) => {
+                let health = serde_json::to_value(health).unwrap();
+                let health = health.as_str().unwrap_or_default().to_string();
+                match srvc.health_failures {
+                    0 => health,
+                    failures => format!("{} ({})", health, failures),
+                }
+            }
+            None => "-".to_string(),
+        };
+        println!(
+            "{:width$}  {:10}  {:13}  {:>7}  {:>10}  {:>8}  {}",
+            srvc.name,
+            state.as_str().unwrap_or_default(),
+            health,
+            srvc.pid.map_or("-".to_string(), |pid| pid.to_string()),
+            srvc.uptime_ms.map_or("-".to_string(), format_uptime),
+            srvc.restarts,
+            srvc.last_exit.as_deref().unwrap_or("-"),
+        );
+    }
+
+    let consumers = &response.consumers;
+    if consumers.is_empty() {
+        return;
+    }
+    let width = consumers
+        .iter()
+        .map(|consumer| consumer.service.len() + consumer.stream.len() + 1)
+        .fold("CONSUMES".len(), usize::max);
+    println!();
+    println!(
+        "{:width$}  {:7}  {:8}  {:>8}  {:>10}  {:>8}  LAST ERROR",
+        "CONSUMES", "KIND", "STATE", "QUEUED", "DROPPED", "FAILURES"
+    );
+    for consumer in consumers {
+        println!(
+            "{:width$}  {:7}  {:8}  {:>8}  {:>10}  {:>8}  {}",
+            format!("{} {}", consumer.service, consumer.stream),
+            consumer.kind,
+            consumer.state,
+            consumer.queued_bytes,
+            consumer.dropped_bytes,
+            consumer.failures,
+            consumer.last_error.as_deref().unwrap_or("-"),
+        );
+    }
+}
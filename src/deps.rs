@@ -0,0 +1,189 @@
+//! Ordering of services according to the dependencies between them.
+//!
+//! `after`, `requires` and `wants` all order a service behind its dependencies,
+//! they only differ in what happens when a dependency goes down. Here they're
+//! treated alike to build the dependency graph, which must be acyclic.
+use crate::conf::ServiceConf;
+use std::{collections::HashMap, fmt};
+
+#[derive(Debug)]
+pub enum DependencyError {
+    Unknown { service: String, dependency: String },
+    Cycle(Vec<String>),
+}
+
+impl fmt::Display for DependencyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unknown {
+                service,
+                dependency,
+            } => write!(
+                f,
+                "service {} depends on {} which isn't defined",
+                service, dependency
+            ),
+            Self::Cycle(path) => write!(f, "dependency cycle: {}", path.join(" -> ")),
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Mark {
+    Unvisited,
+    InProgress,
+    Done,
+}
+
+/// Indices of `services` ordered so that every service comes after all of its
+/// dependencies. Services without any ordering between them keep their
+/// configured order.
+pub fn start_order(services: &[ServiceConf]) -> Result<Vec<usize>, DependencyError> {
+    let index = services
+        .iter()
+        .enumerate()
+        .map(|(idx, def)| (def.name.as_str(), idx))
+        .collect::<HashMap<_, _>>();
+
+    let mut marks = vec![Mark::Unvisited; services.len()];
+    let mut order = Vec::with_capacity(services.len());
+    let mut path = Vec::new();
+
+    for idx in 0..services.len() {
+        visit(idx, services, &index, &mut marks, &mut path, &mut order)?;
+    }
+    Ok(order)
+}
+
+fn visit(
+    idx: usize,
+    services: &[ServiceConf],
+    index: &HashMap<&str, usize>,
+    marks: &mut [Mark],
+    path: &mut Vec<usize>,
+    order: &mut Vec<usize>,
+) -> Result<(), DependencyError> {
+    match marks[idx] {
+        Mark::Done => return Ok(()),
+        Mark::InProgress => {
+            let start = path.iter().position(|&on_path| on_path == idx).unwrap();
+            let mut cycle = path[start..]
+                .iter()
+                .map(|&on_path| services[on_path].name.clone())
+                .collect::<Vec<_>>();
+            cycle.push(services[idx].name.clone());
+            return Err(DependencyError::Cycle(cycle));
+        }
+        Mark::Unvisited => (),
+    }
+
+    marks[idx] = Mark::InProgress;
+    path.push(idx);
+    for dependency in services[idx].dependencies() {
+        let Some(&dep_idx) = index.get(dependency.as_str()) else {
+            return Err(DependencyError::Unknown {
+                service: services[idx].name.clone(),
+                dependency: dependency.clone(),
+            });
+        };
+        visit(dep_idx, services, index, marks, path, order)?;
+    }
+    path.pop();
+    marks[idx] = Mark::Done;
+    order.push(idx);
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::Config;
+
+    /// Services by name, each started after the ones listed along with it.
+    fn services(deps: &[(&str, &[&str])]) -> Vec<ServiceConf> {
+        let mut toml = String::new();
+        for (name, after) in deps {
+            toml.push_str(&format!(
+                "[[service]]\nname = {:?}\nexec = [\"/bin/true\"]\nafter = {:?}\n",
+                name, after
+            ));
+        }
+        toml::from_str::<Config>(&toml).unwrap().service
+    }
+
+    fn names(services: &[ServiceConf], order: &[usize]) -> Vec<String> {
+        order
+            .iter()
+            .map(|&idx| services[idx].name.clone())
+            .collect()
+    }
+
+    #[test]
+    fn independent_services_keep_their_order() {
+        let services = services(&[("c", &[]), ("a", &[]), ("b", &[])]);
+        let order = start_order(&services).unwrap();
+        assert_eq!(names(&services, &order), ["c", "a", "b"]);
+    }
+
+    #[test]
+    fn dependencies_come_first() {
+        let services = services(&[
+            ("web", &["db", "cache"]),
+            ("cache", &[]),
+            ("db", &["volume"]),
+            ("volume", &[]),
+        ]);
+        let order = start_order(&services).unwrap();
+        assert_eq!(names(&services, &order), ["volume", "db", "cache", "web"]);
+    }
+
+    #[test]
+    fn requires_and_wants_order_too() {
+        let toml = r#"
+            [[service]]
+            name = "app"
+            exec = ["/bin/true"]
+            requires = ["db"]
+            wants = ["metrics"]
+
+            [[service]]
+            name = "db"
+            exec = ["/bin/true"]
+
+            [[service]]
+            name = "metrics"
+            exec = ["/bin/true"]
+        "#;
+        let services = toml::from_str::<Config>(toml).unwrap().service;
+        let order = start_order(&services).unwrap();
+        assert_eq!(names(&services, &order), ["db", "metrics", "app"]);
+    }
+
+    #[test]
+    fn cycles_are_reported_along_their_path() {
+        let services = services(&[("a", &["b"]), ("b", &["c"]), ("c", &["a"])]);
+        match start_order(&services) {
+            Err(DependencyError::Cycle(path)) => assert_eq!(path, ["a", "b", "c", "a"]),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn a_service_depending_on_itself_is_a_cycle() {
+        let services = services(&[("a", &[]), ("b", &["b"])]);
+        match start_order(&services) {
+            Err(DependencyError::Cycle(path)) => assert_eq!(path, ["b", "b"]),
+            other => panic!("expected a cycle, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn unknown_dependencies_are_reported() {
+        let services = services(&[("a", &["ghost"])]);
+        let e = start_order(&services).unwrap_err();
+        assert_eq!(
+            e.to_string(),
+            "service a depends on ghost which isn't defined"
+        );
+    }
+}
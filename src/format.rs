@@ -0,0 +1,277 @@
+//! Formatting applied by a consumer to what it's handed, before writing it.
+//!
+//! Buses hand out bytes wherever they happen to be cut, so formatting keeps
+//! track of where lines start across writes instead of assuming every write
+//! starts one.
+use crate::{
+    conf::{PrefixConf, ProducerConf, TimestampFormat},
+    net::Lines,
+    utils::CivilTime,
+};
+use nix::{
+    time::{clock_gettime, ClockId},
+    unistd::Pid,
+};
+use serde_json::{Map, Value};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lines are cut in pieces past this length when encoded as JSON.
+const MAX_JSON_LINE: usize = 64 * 1024;
+
+/// The colors docker-compose cycles through for its services.
+const COLORS: [&str; 6] = ["36", "33", "32", "35", "34", "31"];
+
+fn timestamp(format: TimestampFormat) -> String {
+    match format {
+        TimestampFormat::Rfc3339 => CivilTime::now().rfc3339(),
+        TimestampFormat::Unix => {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            format!("{}.{:03}", now.as_secs(), now.subsec_millis())
+        }
+        TimestampFormat::Monotonic => match clock_gettime(ClockId::CLOCK_MONOTONIC) {
+            Ok(now) => format!("{}.{:06}", now.tv_sec(), now.tv_nsec() / 1000),
+            Err(_) => "-".to_string(),
+        },
+    }
+}
+
+/// The same color for a service every time, whatever else is running.
+fn color_of(name: &str) -> &'static str {
+    let hash = name.bytes().fold(0xcbf29ce484222325u64, |hash, byte| {
+        (hash ^ u64::from(byte)).wrapping_mul(0x100000001b3)
+    });
+    COLORS[(hash % COLORS.len() as u64) as usize]
+}
+
+/// Puts a timestamp and a label naming the stream in front of every line.
+pub struct Prefix {
+    timestamp: Option<TimestampFormat>,
+    /// The part of the prefix that never changes, ending with ` | `.
+    label: String,
+    at_line_start: bool,
+}
+
+impl Prefix {
+    /// `name_width` is how wide padded service names are made.
+    pub fn new(conf: &PrefixConf, producer: &ProducerConf, name_width: usize) -> Self {
+        let mut fields = Vec::new();
+        if conf.service {
+            let width = if conf.pad { name_width } else { 0 };
+            fields.push(format!("{:width$}", producer.service_name()));
+        }
+        if conf.stream {
+            fields.push(producer.stream_name().to_string());
+        }
+        let mut label = fields.join(" ");
+        if !label.is_empty() {
+            label.push_str(" |");
+        }
+        if conf.color && !label.is_empty() {
+            label = format!(
+                "\x1b[{}m{}\x1b[0m",
+                color_of(producer.service_name()),
+                label
+            );
+        }
+        Self {
+            timestamp: conf.timestamp,
+            label,
+            at_line_start: true,
+        }
+    }
+
+    fn prefix(&self) -> String {
+        let mut prefix = String::new();
+        if let Some(format) = self.timestamp {
+            prefix.push_str(&timestamp(format));
+            prefix.push(' ');
+        }
+        if !self.label.is_empty() {
+            prefix.push_str(&self.label);
+            prefix.push(' ');
+        }
+        prefix
+    }
+
+    pub fn apply(&mut self, data: &[u8]) -> Vec<u8> {
+        let mut out = Vec::with_capacity(data.len() + self.label.len() + 32);
+        for line in data.split_inclusive(|&b| b == b'\n') {
+            if self.at_line_start {
+                out.extend_from_slice(self.prefix().as_bytes());
+            }
+            out.extend_from_slice(line);
+            self.at_line_start = line.ends_with(b"\n");
+        }
+        out
+    }
+}
+
+/// Text for a line that may not be valid UTF-8. Bytes that aren't show up as
+/// `\xNN`, so nothing the service wrote gets lost.
+fn escape_invalid(line: &[u8]) -> String {
+    let mut text = String::with_capacity(line.len());
+    for chunk in line.utf8_chunks() {
+        text.push_str(chunk.valid());
+        for byte in chunk.invalid() {
+            text.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    text
+}
+
+/// Turns every line into a JSON object on a line of its own.
+pub struct JsonLines {
+    service: String,
+    stream: &'static str,
+    pid: Option<Pid>,
+    merge: bool,
+    lines: Lines,
+}
+
+impl JsonLines {
+    pub fn new(producer: &ProducerConf, merge: bool) -> Self {
+        Self {
+            service: producer.service_name().to_string(),
+            stream: producer.stream_name(),
+            pid: None,
+            merge,
+            lines: Lines::new(MAX_JSON_LINE),
+        }
+    }
+
+    /// Attribute lines to the process `pid` from now on.
+    pub fn set_pid(&mut self, pid: Option<Pid>) {
+        self.pid = pid;
+    }
+
+    fn encode(&self, line: &[u8]) -> Vec<u8> {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let mut object = Map::new();
+        object.insert("timestamp".into(), CivilTime::now().rfc3339().into());
+        object.insert("service".into(), self.service.clone().into());
+        object.insert("stream".into(), self.stream.into());
+        object.insert("pid".into(), self.pid.map(Pid::as_raw).into());
+        let fields = match self.merge {
+            true => serde_json::from_slice::<Map<String, Value>>(line).ok(),
+            false => None,
+        };
+        match fields {
+            // fields the service set itself win over ours
+            Some(fields) => object.extend(fields),
+            None => {
+                object.insert("message".into(), escape_invalid(line).into());
+            }
+        }
+        let mut encoded = serde_json::to_vec(&object).unwrap();
+        encoded.push(b'\n');
+        encoded
+    }
+
+    pub fn apply(&mut self, data: &[u8]) -> Vec<u8> {
+        let lines = self.lines.split(data);
+        lines.iter().flat_map(|line| self.encode(line)).collect()
+    }
+
+    /// The line left unfinished, encoded as if it had been.
+    pub fn finish(&mut self) -> Vec<u8> {
+        match self.lines.finish() {
+            Some(line) => self.encode(&line),
+            None => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn json(merge: bool) -> JsonLines {
+        let mut json = JsonLines::new(&ProducerConf::StdErr("web".to_string()), merge);
+        json.set_pid(Some(Pid::from_raw(42)));
+        json
+    }
+
+    /// The objects in what was encoded, checking each sits on a line of its own.
+    fn objects(encoded: &[u8]) -> Vec<Map<String, Value>> {
+        let encoded = std::str::from_utf8(encoded).unwrap();
+        assert!(encoded.is_empty() || encoded.ends_with('\n'));
+        encoded
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect()
+    }
+
+    fn messages(encoded: &[u8]) -> Vec<Value> {
+        objects(encoded)
+            .into_iter()
+            .map(|mut object| object.remove("message").unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lines_become_objects() {
+        let mut json = json(false);
+        let object = objects(&json.apply(b"hello\r\n")).remove(0);
+        assert_eq!(object["service"], "web");
+        assert_eq!(object["stream"], "stderr");
+        assert_eq!(object["pid"], 42);
+        assert_eq!(object["message"], "hello");
+        let timestamp = object["timestamp"].as_str().unwrap();
+        assert_eq!(timestamp.len(), "2025-01-01T12:00:00.000Z".len());
+    }
+
+    #[test]
+    fn lines_are_escaped() {
+        let mut json = json(false);
+        let encoded = json.apply(b"say \"hi\"\\\tnow\x1b[0m\n\xff\xfeok\xe2\x82\n");
+        let text = String::from_utf8(encoded.clone()).unwrap();
+        assert!(text.contains(r#""message":"say \"hi\"\\\tnow\u001b[0m""#));
+        assert_eq!(
+            messages(&encoded),
+            ["say \"hi\"\\\tnow\x1b[0m", "\\xff\\xfeok\\xe2\\x82"]
+        );
+    }
+
+    #[test]
+    fn lines_are_put_together_across_writes() {
+        let mut json = json(false);
+        assert!(json.apply(b"hel").is_empty());
+        assert_eq!(messages(&json.apply(b"lo\nwor")), ["hello"]);
+        assert_eq!(messages(&json.finish()), ["wor"]);
+        assert!(json.finish().is_empty());
+    }
+
+    #[test]
+    fn json_objects_are_merged() {
+        let mut json = json(true);
+        let line = br#"{"level":"warn","service":"api","nested":{"a":[1,2]}}"#;
+        let object = objects(&json.apply(&[line.as_slice(), b"\n"].concat())).remove(0);
+        assert_eq!(object["level"], "warn");
+        // fields the service set itself win
+        assert_eq!(object["service"], "api");
+        assert_eq!(object["stream"], "stderr");
+        assert_eq!(object["nested"], serde_json::json!({ "a": [1, 2] }));
+        assert!(!object.contains_key("message"));
+    }
+
+    #[test]
+    fn only_json_objects_are_merged() {
+        let mut json = json(true);
+        let encoded = json.apply(b"[1,2]\n\"text\"\n{\"broken\":\nplain\n");
+        assert_eq!(
+            messages(&encoded),
+            ["[1,2]", "\"text\"", "{\"broken\":", "plain"]
+        );
+    }
+
+    #[test]
+    fn merging_is_off_unless_asked_for() {
+        let mut json = json(false);
+        assert_eq!(
+            messages(&json.apply(b"{\"level\":\"warn\"}\n")),
+            ["{\"level\":\"warn\"}"]
+        );
+    }
+}
@@ -0,0 +1,220 @@
+//! Periodic health checks of running services.
+//!
+//! Command probes run in a short-lived child process whose exit status tells
+//! whether the service is healthy, so they're reaped and timed out like any
+//! other child. TCP and HTTP probes run on a thread of ours instead, keeping
+//! their blocking network I/O out of the event loop, and report back through
+//! a pipe the event loop watches.
+use crate::{
+    conf::{HealthCheckConf, HealthProbe},
+    net::{connect_tcp, read_http_status, split_http_url},
+    utils::{pointers, reset_signal_handlers, set_fd_nonblocking},
+};
+use nix::{
+    fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
+    libc,
+    sys::{signal::SigSet, stat::Mode},
+    unistd::{dup2, fork, pipe, read, write, ForkResult, Pid},
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    ffi::CString,
+    io::{self, Write},
+    os::fd::{AsRawFd, FromRawFd, OwnedFd, RawFd},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+const DEVNULL: &str = "/dev/null";
+
+/// What a probe thread reports: the id of its probe, then 1 if it succeeded.
+/// Short enough to be written to the pipe in one go.
+const REPORT_LEN: usize = 9;
+
+/// A probe in flight.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Probe {
+    Child(Pid),
+    /// Running on a thread, under an id of `ProbeReports`.
+    Thread(u64),
+}
+
+/// The pipe probe threads report their outcome to.
+pub struct ProbeReports {
+    read: OwnedFd,
+    write: Arc<OwnedFd>,
+    next_id: u64,
+}
+
+impl ProbeReports {
+    pub fn new() -> io::Result<Self> {
+        let (read, write) = pipe()?;
+        for fd in [&read, &write] {
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
+        set_fd_nonblocking(read.as_raw_fd())?;
+        Ok(Self {
+            read,
+            write: Arc::new(write),
+            next_id: 0,
+        })
+    }
+
+    /// Run `probe` on a thread of its own.
+    fn spawn<F>(&mut self, probe: F) -> io::Result<Probe>
+    where
+        F: FnOnce() -> bool + Send + 'static,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        let reports = self.write.clone();
+        thread::Builder::new()
+            .name(format!("probe-{}", id))
+            .spawn(move || {
+                let mut report = [0; REPORT_LEN];
+                report[..8].copy_from_slice(&id.to_le_bytes());
+                report[8] = u8::from(probe());
+                let _ = write(&*reports, &report);
+            })?;
+        Ok(Probe::Thread(id))
+    }
+
+    /// The outcome of every probe thread which reported since last time.
+    pub fn take(&self) -> Vec<(Probe, bool)> {
+        let mut outcomes = Vec::new();
+        // reports are written whole and so come out whole
+        let mut buf = [0; REPORT_LEN * 64];
+        while let Ok(len @ 1..) = read(self.read.as_raw_fd(), &mut buf) {
+            for report in buf[..len].as_chunks::<REPORT_LEN>().0 {
+                let id = u64::from_le_bytes(report[..8].try_into().unwrap());
+                outcomes.push((Probe::Thread(id), report[8] == 1));
+            }
+        }
+        outcomes
+    }
+}
+
+impl AsRawFd for ProbeReports {
+    fn as_raw_fd(&self) -> RawFd {
+        self.read.as_raw_fd()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum HealthStatus {
+    Starting,
+    Healthy,
+    Unhealthy,
+}
+
+#[derive(Debug)]
+pub struct Health {
+    pub status: HealthStatus,
+    /// Consecutive failed probes.
+    pub failures: u32,
+    pub probe: Option<Probe>,
+}
+
+impl Health {
+    pub fn new() -> Self {
+        Self {
+            status: HealthStatus::Starting,
+            failures: 0,
+            probe: None,
+        }
+    }
+
+    /// Record the outcome of a probe, returning whether the service just
+    /// turned unhealthy. Failures during the start period only count once the
+    /// service has been healthy at least once.
+    pub fn record(&mut self, conf: &HealthCheckConf, healthy: bool, starting: bool) -> bool {
+        self.probe = None;
+        if healthy {
+            self.failures = 0;
+            self.status = HealthStatus::Healthy;
+            return false;
+        }
+        if starting && self.status == HealthStatus::Starting {
+            return false;
+        }
+        self.failures += 1;
+        if self.failures >= conf.failure_threshold && self.status != HealthStatus::Unhealthy {
+            self.status = HealthStatus::Unhealthy;
+            return true;
+        }
+        false
+    }
+}
+
+/// Start probing, in a child for commands and on a thread otherwise.
+pub fn spawn_probe(
+    conf: &HealthCheckConf,
+    env: &[CString],
+    reports: &mut ProbeReports,
+) -> io::Result<Probe> {
+    let timeout = Duration::from_millis(conf.timeout_ms);
+    match &conf.probe {
+        HealthProbe::Exec(exec) => spawn_command(exec, env).map(Probe::Child),
+        HealthProbe::Tcp(addr) => {
+            let addr = addr.clone();
+            reports.spawn(move || probe_tcp(&addr, timeout).is_ok())
+        }
+        HealthProbe::Http(url) => {
+            let url = url.clone();
+            reports.spawn(move || probe_http(&url, timeout).unwrap_or(false))
+        }
+    }
+}
+
+/// Fork a child running `exec` with its std streams on `/dev/null`. Like the
+/// services, it only makes system calls on what was prepared before forking.
+fn spawn_command(exec: &[CString], env: &[CString]) -> io::Result<Pid> {
+    let devnull = open(DEVNULL, OFlag::O_RDWR | OFlag::O_CLOEXEC, Mode::empty())?;
+    let devnull = unsafe { OwnedFd::from_raw_fd(devnull) };
+    let env = std::env::vars_os()
+        .map(|(k, v)| {
+            CString::new(format!("{}={}", k.to_string_lossy(), v.to_string_lossy())).unwrap()
+        })
+        .chain(env.iter().cloned())
+        .collect::<Vec<_>>();
+    let argv_ptrs = pointers(exec);
+    let env_ptrs = pointers(&env);
+
+    match unsafe { fork() }? {
+        ForkResult::Parent { child } => Ok(child),
+        ForkResult::Child => {
+            reset_signal_handlers();
+            let _ = SigSet::all().thread_unblock();
+            for fd in [libc::STDIN_FILENO, libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+                let _ = dup2(devnull.as_raw_fd(), fd);
+            }
+            unsafe {
+                libc::execve(exec[0].as_ptr(), argv_ptrs.as_ptr(), env_ptrs.as_ptr());
+                // skip destructors and atexit handlers inherited from the supervisor
+                libc::_exit(127)
+            }
+        }
+    }
+}
+
+fn probe_tcp(addr: &str, timeout: Duration) -> io::Result<()> {
+    connect_tcp(addr, timeout).map(|_| ())
+}
+
+/// Issue a plain `GET` and treat any 2xx or 3xx answer as healthy.
+fn probe_http(url: &str, timeout: Duration) -> io::Result<bool> {
+    let (addr, authority, path) = split_http_url(url)?;
+
+    let mut stream = connect_tcp(&addr, timeout)?;
+    stream.set_read_timeout(Some(timeout))?;
+    write!(
+        stream,
+        "GET {} HTTP/1.0\r\nHost: {}\r\nConnection: close\r\n\r\n",
+        path, authority
+    )?;
+
+    let code = read_http_status(&mut stream)?;
+    Ok(code.is_some_and(|code| (200..400).contains(&code)))
+}
@@ -0,0 +1,316 @@
+//! A consumer pushing lines to Grafana Loki.
+//!
+//! Lines are queued up as ready-made JSON entries, which a thread of their own
+//! gathers into batches and POSTs to Loki's push API. Failed pushes are retried
+//! with a backoff, and while they are the queue keeps its memory use bounded by
+//! dropping the oldest lines, like the other network consumers.
+use crate::{
+    conf::{LokiConf, ProducerConf},
+    net::{connect_tcp, read_http_status, split_http_url, Backoff, Lines, Outbox, Shared},
+};
+use std::{
+    io::{self, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const PUSH_PATH: &str = "/loki/api/v1/push";
+
+struct Pusher {
+    addr: String,
+    host: String,
+    path: String,
+    tenant: Option<String>,
+    timeout: Duration,
+    /// The labels of the stream, serialized.
+    labels: String,
+}
+
+impl Pusher {
+    fn body(&self, entries: &[Vec<u8>]) -> Vec<u8> {
+        let mut body =
+            format!(r#"{{"streams":[{{"stream":{},"values":["#, self.labels).into_bytes();
+        for (idx, entry) in entries.iter().enumerate() {
+            if idx > 0 {
+                body.push(b',');
+            }
+            body.extend_from_slice(entry);
+        }
+        body.extend_from_slice(b"]}]}");
+        body
+    }
+
+    /// POST a body, returning the status Loki answered with.
+    fn post(&self, body: &[u8]) -> io::Result<u16> {
+        let mut stream = connect_tcp(&self.addr, self.timeout)?;
+        stream.set_read_timeout(Some(self.timeout))?;
+        let mut head = format!(
+            "POST {} HTTP/1.1\r\nHost: {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n",
+            self.path,
+            self.host,
+            body.len()
+        );
+        if let Some(tenant) = &self.tenant {
+            head.push_str(&format!("X-Scope-OrgID: {}\r\n", tenant));
+        }
+        head.push_str("\r\n");
+        stream.write_all(head.as_bytes())?;
+        stream.write_all(body)?;
+        read_http_status(&mut stream)?
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "not an HTTP response"))
+    }
+}
+
+struct Batching {
+    max_bytes: usize,
+    wait: Duration,
+    max_retries: u32,
+    backoff: Backoff,
+}
+
+fn push_batches(pusher: Pusher, shared: &Shared, batching: Batching) {
+    let name = format!("loki {}", pusher.addr);
+    let mut closing = false;
+    while let Some(batch) = shared.next_batch(batching.max_bytes, batching.wait) {
+        let body = pusher.body(&batch);
+        let mut delay = batching.backoff.min;
+        let mut retries = 0;
+        loop {
+            let failure = match pusher.post(&body) {
+                Ok(status) if (200..300).contains(&status) => break,
+                // retrying a request Loki refused won't make it any better
+                Ok(status) if (400..500).contains(&status) && status != 429 => {
+                    eprintln!(
+                        "{}: push refused with {}, dropping {} lines",
+                        name,
+                        status,
+                        batch.len()
+                    );
+                    break;
+                }
+                Ok(status) => format!("push failed with {}", status),
+                Err(e) => e.to_string(),
+            };
+            if closing || retries == batching.max_retries {
+                eprintln!("{}: {}, dropping {} lines", name, failure, batch.len());
+                break;
+            }
+            eprintln!("{}: {}, retrying in {}ms", name, failure, delay.as_millis());
+            // once closed, the batch gets one last attempt
+            closing = shared.wait_closed(delay);
+            delay = (delay * 2).min(batching.backoff.max);
+            retries += 1;
+        }
+        let dropped = shared.take_dropped();
+        if dropped > 0 {
+            eprintln!(
+                "{}: dropped {} bytes of lines over queue_bytes",
+                name, dropped
+            );
+        }
+    }
+}
+
+pub struct LokiConsumer {
+    lines: Lines,
+    outbox: Outbox,
+}
+
+impl LokiConsumer {
+    pub fn new(conf: &LokiConf, producer: &ProducerConf) -> io::Result<Self> {
+        let (addr, host, path) = split_http_url(&conf.url)?;
+        let path = match path {
+            "/" => PUSH_PATH,
+            path => path,
+        };
+        let mut labels = conf.labels.clone();
+        let stream = producer.stream_name();
+        for (key, value) in [("service", producer.service_name()), ("stream", stream)] {
+            labels
+                .entry(key.to_string())
+                .or_insert_with(|| value.to_string());
+        }
+
+        let pusher = Pusher {
+            addr,
+            host: host.to_string(),
+            path: path.to_string(),
+            tenant: conf.tenant.clone(),
+            timeout: Duration::from_millis(conf.delivery.timeout_ms),
+            labels: serde_json::to_string(&labels)?,
+        };
+        let batching = Batching {
+            max_bytes: conf.batch_bytes,
+            wait: Duration::from_millis(conf.batch_wait_ms),
+            max_retries: conf.max_retries,
+            backoff: (&conf.delivery).into(),
+        };
+        let name = format!("loki {}", pusher.addr);
+        let outbox = Outbox::start(name, conf.delivery.queue_bytes, move |shared| {
+            push_batches(pusher, shared, batching)
+        })?;
+        Ok(Self {
+            lines: Lines::new(conf.batch_bytes),
+            outbox,
+        })
+    }
+
+    /// An entry of the push API, `["<unix nanoseconds>", "<line>"]`.
+    fn entry(line: &[u8]) -> Vec<u8> {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = String::from_utf8_lossy(line.strip_suffix(b"\r").unwrap_or(line));
+        serde_json::to_vec(&(now.as_nanos().to_string(), line)).unwrap()
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        for line in self.lines.split(data) {
+            if !line.is_empty() {
+                self.outbox.push(Self::entry(&line));
+            }
+        }
+    }
+
+    pub fn drain(&mut self, deadline: Instant) {
+        if let Some(line) = self.lines.finish() {
+            self.outbox.push(Self::entry(&line));
+        }
+        self.outbox.drain(deadline);
+    }
+}
+
+impl Drop for LokiConsumer {
+    fn drop(&mut self) {
+        if let Some(line) = self.lines.finish() {
+            self.outbox.push(Self::entry(&line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::{
+        io::Read,
+        net::TcpListener,
+        sync::mpsc::{self, Receiver},
+        thread,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// The head and body of a request.
+    type Request = (String, serde_json::Value);
+
+    /// Stand in for Loki, answering requests with `statuses` in turn.
+    fn loki(statuses: &[u16]) -> (String, Receiver<Request>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let statuses = statuses.to_vec();
+        let (requests, received) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut request = Vec::new();
+                let mut chunk = [0; 4096];
+                let head_end = loop {
+                    let n = stream.read(&mut chunk).unwrap();
+                    request.extend_from_slice(&chunk[..n]);
+                    if let Some(idx) = request.windows(4).position(|w| w == b"\r\n\r\n") {
+                        break idx + 4;
+                    }
+                };
+                let head = String::from_utf8(request[..head_end].to_vec()).unwrap();
+                let len = head
+                    .lines()
+                    .find_map(|line| line.strip_prefix("Content-Length: "))
+                    .unwrap()
+                    .parse::<usize>()
+                    .unwrap();
+                while request.len() < head_end + len {
+                    let n = stream.read(&mut chunk).unwrap();
+                    request.extend_from_slice(&chunk[..n]);
+                }
+                let body = serde_json::from_slice(&request[head_end..]).unwrap();
+                let response = format!("HTTP/1.1 {} Whatever\r\nContent-Length: 0\r\n\r\n", status);
+                stream.write_all(response.as_bytes()).unwrap();
+                requests.send((head, body)).unwrap();
+            }
+        });
+        (url, received)
+    }
+
+    fn consumer(url: &str, extra: &str) -> LokiConsumer {
+        let conf = format!("url = \"{}\"\nreconnect_ms = 10\n{}", url, extra);
+        let conf = toml::from_str::<LokiConf>(&conf).unwrap();
+        LokiConsumer::new(&conf, &ProducerConf::StdOut("web".to_string())).unwrap()
+    }
+
+    /// The lines a push carried.
+    fn lines(body: &serde_json::Value) -> Vec<&str> {
+        body["streams"][0]["values"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|entry| entry[1].as_str().unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lines_are_pushed_in_batches() {
+        let (url, requests) = loki(&[204, 204]);
+        let mut loki = consumer(
+            &url,
+            "batch_bytes = 100\nbatch_wait_ms = 60000\ntenant = \"ops\"",
+        );
+        // entries are 29 bytes each, so three make a batch
+        loki.write(b"one\ntwo\nsix\nten\r\nend\n");
+        let (head, body) = requests.recv_timeout(TIMEOUT).unwrap();
+        assert!(head.starts_with("POST /loki/api/v1/push HTTP/1.1\r\n"));
+        assert!(head.contains("X-Scope-OrgID: ops\r\n"));
+        assert_eq!(
+            body["streams"][0]["stream"],
+            serde_json::json!({ "service": "web", "stream": "stdout" })
+        );
+        assert_eq!(lines(&body), ["one", "two", "six"]);
+
+        loki.drain(Instant::now() + TIMEOUT);
+        let (_, body) = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(lines(&body), ["ten", "end"]);
+    }
+
+    #[test]
+    fn failed_pushes_are_retried() {
+        let (url, requests) = loki(&[503, 429, 204]);
+        let mut loki = consumer(&url, "batch_wait_ms = 10");
+        loki.write(b"hello\n");
+        let bodies = (0..3)
+            .map(|_| requests.recv_timeout(TIMEOUT).unwrap().1)
+            .collect::<Vec<_>>();
+        assert!(bodies.iter().all(|body| lines(body) == ["hello"]));
+        loki.drain(Instant::now() + TIMEOUT);
+    }
+
+    #[test]
+    fn refused_pushes_are_dropped() {
+        let (url, requests) = loki(&[400, 204]);
+        let mut loki = consumer(&url, "batch_wait_ms = 10");
+        loki.write(b"bad\n");
+        assert_eq!(lines(&requests.recv_timeout(TIMEOUT).unwrap().1), ["bad"]);
+        loki.write(b"good\n");
+        assert_eq!(lines(&requests.recv_timeout(TIMEOUT).unwrap().1), ["good"]);
+        loki.drain(Instant::now() + TIMEOUT);
+    }
+
+    #[test]
+    fn queue_keeps_the_newest_lines() {
+        let (url, requests) = loki(&[204]);
+        let mut loki = consumer(&url, "queue_bytes = 100\nbatch_wait_ms = 60000");
+        for idx in 0..10 {
+            loki.write(format!("line{}\n", idx).as_bytes());
+        }
+        loki.drain(Instant::now() + TIMEOUT);
+        let (_, body) = requests.recv_timeout(TIMEOUT).unwrap();
+        assert_eq!(lines(&body), ["line7", "line8", "line9"]);
+    }
+}
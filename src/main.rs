@@ -1,86 +1,155 @@
 mod buffd;
 mod bus;
+mod check;
 mod cli;
 mod conf;
 mod consumer;
+mod control;
+mod deps;
+mod format;
+mod health;
+mod loki;
+mod net;
+mod notify;
+mod pool;
 mod registry;
 mod runner;
 mod service;
+mod syslog;
+mod timer;
+mod users;
 mod utils;
 mod watcher;
-use crate::bus::Bus;
-use crate::cli::Cli;
-use crate::conf::{Config, ProducerConf};
-use crate::consumer::{Consumer, FileLogger};
+use crate::bus::BusMap;
+use crate::check::load_checked;
+use crate::cli::{Cli, Command, CtlAction};
+use crate::conf::{Config, ConfigSource};
+use crate::control::{print_status, ControlServer, Request};
 use crate::registry::Registry;
-use crate::runner::run;
-use crate::watcher::{AsWatcher, Watcher};
+use crate::runner::{run, LoadedConfig};
+use crate::watcher::Watcher;
 use clap::Parser;
 use nix::sys::signal::SigSet;
-use std::collections::HashMap;
-use std::{fs, io};
+use std::io;
+use std::path::PathBuf;
+use std::process::exit;
+use std::time::Instant;
 
-fn get_config() -> Config {
-    let cli = Cli::parse();
+/// Load and validate the config, printing every problem found with it.
+fn checked_config(source: &ConfigSource) -> Option<Config> {
+    match load_checked(source) {
+        Ok(config) => Some(config),
+        Err(problems) => {
+            for problem in problems {
+                eprintln!("{}", problem);
+            }
+            None
+        }
+    }
+}
 
-    let config: Config = match cli.config.as_path().extension() {
-        Some(ext) => match ext.to_str() {
-            Some("toml") => toml::from_str(&fs::read_to_string(&cli.config).unwrap()).unwrap(),
-            _ => panic!("File Extension not supported"),
-        },
-        None => panic!("No extension"),
+/// Run a `kinesin ctl` command against the control socket, returning whether
+/// it succeeded.
+fn ctl(source: &ConfigSource, socket: Option<PathBuf>, action: CtlAction) -> io::Result<bool> {
+    let socket = socket.unwrap_or_else(|| {
+        let runtime_dir = match source.load() {
+            Ok((config, _)) => config.runtime_dir,
+            Err(_) => conf::default_runtime_dir(),
+        };
+        ControlServer::socket_path(&runtime_dir)
+    });
+    let request = match action {
+        CtlAction::Status => Request::Status,
+        CtlAction::Start { service } => Request::Start { service },
+        CtlAction::Stop { service } => Request::Stop { service },
+        CtlAction::Restart { service } => Request::Restart { service },
+        CtlAction::Signal { service, signal } => Request::Signal { service, signal },
+        CtlAction::Reload => Request::Reload,
     };
+    let show_status = matches!(request, Request::Status);
 
-    config
+    let response = control::request(&socket, &request)?;
+    if let Some(e) = response.error {
+        eprintln!("{}", e);
+        return Ok(false);
+    }
+    if show_status {
+        print_status(&response);
+    }
+    Ok(true)
 }
 
 fn main() -> io::Result<()> {
+    let cli = Cli::parse();
+    let source = ConfigSource {
+        path: cli.config,
+        format: cli.format,
+    };
+    match cli.command {
+        Some(Command::Ctl { socket, action }) => {
+            let ok = ctl(&source, socket, action).unwrap_or_else(|e| {
+                eprintln!("Couldn't reach kinesin: {}", e);
+                false
+            });
+            exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::Check) => match checked_config(&source) {
+            Some(config) => {
+                let services = config.service.len();
+                println!("{}: OK, {} service(s)", source.origin(), services);
+                exit(0);
+            }
+            None => exit(1),
+        },
+        None => (),
+    }
+
     // We handle signals in the event loop, so block them all from interupting.
     SigSet::all().thread_block().unwrap();
 
-    let config = get_config();
+    let Some(config) = checked_config(&source) else {
+        exit(1);
+    };
 
     // initialize our main objects
-    let mut registry = Registry::new(&config.service);
-    let mut watcher = Watcher::new();
-    let mut bus_map = HashMap::new();
+    // Let daemons which fork away from the process we started still be reaped
+    // by us, so they can be tracked through `MAINPID=`.
+    #[cfg(target_os = "linux")]
+    nix::sys::prctl::set_child_subreaper(true)?;
 
-    // Register interest in the fds and their associated busses
-    for srvc in &mut registry.services {
-        if let Some(stdout) = srvc.stdout {
-            watcher.watch_fd(stdout, srvc.def.stdout.read_bufsize);
-            bus_map.insert(stdout, Bus::new(srvc.def.stdout.bus_bufsize));
-        }
-        if let Some(stderr) = srvc.stderr {
-            watcher.watch_fd(stderr, srvc.def.stderr.read_bufsize);
-            bus_map.insert(stderr, Bus::new(srvc.def.stderr.bus_bufsize));
+    let mut registry = match Registry::new(&config) {
+        Ok(registry) => registry,
+        Err(e) => {
+            eprintln!("{}: {}", source.origin(), e);
+            exit(1);
         }
-    }
+    };
+    registry.open_notify_sockets(&config.runtime_dir)?;
+    let mut control = ControlServer::bind(ControlServer::socket_path(&config.runtime_dir))?;
+    let mut watcher = Watcher::new();
+    let mut buses = BusMap::new();
 
-    // register the consumers into the busses
-    for consumer_conf in &config.consumer {
-        let consumer = match &consumer_conf.kind {
-            conf::ConsumerKind::Log(path) => Consumer::File(FileLogger::new(path)?),
-            conf::ConsumerKind::StdOut => Consumer::StdOut,
-            conf::ConsumerKind::StdErr => Consumer::StdErr,
-        };
-        let srvc_name = match &consumer_conf.consumes {
-            ProducerConf::StdOut(name) => name,
-            ProducerConf::StdErr(name) => name,
-        };
-        let srvc = registry
-            .get_by_name(srvc_name.as_str())
-            .expect("consumer defined with improper service name");
-        let stream_fd = match &consumer_conf.consumes {
-            ProducerConf::StdOut(_) => srvc.stdout,
-            ProducerConf::StdErr(_) => srvc.stderr,
-        }
-        .expect("trying to consume a stream that's switched off");
-        let bus = bus_map.get_mut(&stream_fd).expect("bus doesn't exist");
-        bus.add_consumer(consumer);
-    }
+    // Create the busses of every watched stream along with their consumers,
+    // the services get hooked up to them as they're started
+    runner::wire_buses(&mut buses, None, &config)?;
+
+    let mut loaded = LoadedConfig { source, config };
+    run(
+        &mut registry,
+        &mut buses,
+        &mut watcher,
+        &mut control,
+        &mut loaded,
+    )?;
+    buses.drain_all(Instant::now() + net::DRAIN_TIMEOUT);
 
-    run(&mut registry, &mut bus_map, &mut watcher)?;
+    // exit() skips destructors, which remove our sockets
+    let exit_code = registry.exit_code;
+    drop(control);
+    drop(registry);
+    if let Some(code) = exit_code {
+        exit(code);
+    }
 
     Ok(())
 }
@@ -0,0 +1,699 @@
+//! Consumers which send output over the network.
+//!
+//! The event loop must never wait on a peer, so network consumers only queue
+//! output up, and a thread of their own delivers it. The queue is bounded: while
+//! the peer is unreachable the oldest output is dropped to make room for new
+//! output, and the thread keeps reconnecting with an exponential backoff.
+use crate::conf::{DeliveryConf, NetFraming, TcpConf};
+use std::{
+    collections::VecDeque,
+    io::{self, Read, Write},
+    net::{Ipv4Addr, Ipv6Addr, SocketAddr, TcpStream, ToSocketAddrs, UdpSocket},
+    os::unix::net::UnixDatagram,
+    path::Path,
+    sync::{
+        mpsc::{self, Receiver},
+        Arc, Condvar, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+/// How long kinesin waits on exit for queued output to be delivered.
+pub const DRAIN_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Opens connections to a peer, for the delivery thread to write to.
+pub trait Connect: Send + 'static {
+    type Stream: Write;
+
+    fn connect(&self) -> io::Result<Self::Stream>;
+
+    /// Names the peer in diagnostics.
+    fn name(&self) -> String;
+}
+
+#[derive(Clone, Copy)]
+pub struct Backoff {
+    pub min: Duration,
+    pub max: Duration,
+}
+
+impl From<&DeliveryConf> for Backoff {
+    fn from(conf: &DeliveryConf) -> Self {
+        Self {
+            min: Duration::from_millis(conf.reconnect_ms),
+            max: Duration::from_millis(conf.max_reconnect_ms),
+        }
+    }
+}
+
+struct Queue {
+    records: VecDeque<Vec<u8>>,
+    len: usize,
+    limit: usize,
+    /// Bytes dropped since the last time anyone asked.
+    dropped: usize,
+    closed: bool,
+}
+
+/// The queue of an `Outbox`, as seen by its delivery thread.
+pub struct Shared {
+    queue: Mutex<Queue>,
+    changed: Condvar,
+}
+
+impl Shared {
+    /// Wait for the next record, or `None` once closed with nothing left.
+    pub fn next(&self) -> Option<Vec<u8>> {
+        let mut queue = self.queue.lock().unwrap();
+        loop {
+            if let Some(record) = queue.records.pop_front() {
+                queue.len -= record.len();
+                return Some(record);
+            }
+            if queue.closed {
+                return None;
+            }
+            queue = self.changed.wait(queue).unwrap();
+        }
+    }
+
+    /// Put back a record that couldn't be delivered, unless newer output took
+    /// up its room in the meantime.
+    fn requeue(&self, record: Vec<u8>) {
+        let mut queue = self.queue.lock().unwrap();
+        if queue.len + record.len() > queue.limit {
+            queue.dropped += record.len();
+            return;
+        }
+        queue.len += record.len();
+        queue.records.push_front(record);
+    }
+
+    /// Wait for records adding up to `max_bytes`, or for `wait` after the
+    /// first one came along, whichever is first. `None` once closed with
+    /// nothing left. A record larger than `max_bytes` makes a batch of its own.
+    pub fn next_batch(&self, max_bytes: usize, wait: Duration) -> Option<Vec<Vec<u8>>> {
+        let mut queue = self
+            .changed
+            .wait_while(self.queue.lock().unwrap(), |queue| {
+                queue.records.is_empty() && !queue.closed
+            })
+            .unwrap();
+        if queue.records.is_empty() {
+            return None;
+        }
+        (queue, _) = self
+            .changed
+            .wait_timeout_while(queue, wait, |queue| queue.len < max_bytes && !queue.closed)
+            .unwrap();
+
+        let mut batch = Vec::new();
+        let mut batch_len = 0;
+        while let Some(record) = queue.records.front() {
+            if !batch.is_empty() && batch_len + record.len() > max_bytes {
+                break;
+            }
+            let record = queue.records.pop_front().unwrap();
+            queue.len -= record.len();
+            batch_len += record.len();
+            batch.push(record);
+        }
+        Some(batch)
+    }
+
+    pub fn take_dropped(&self) -> usize {
+        std::mem::take(&mut self.queue.lock().unwrap().dropped)
+    }
+
+    /// Sleep for `timeout`, returning early with `true` if the outbox closed.
+    pub fn wait_closed(&self, timeout: Duration) -> bool {
+        let queue = self.queue.lock().unwrap();
+        let (queue, _) = self
+            .changed
+            .wait_timeout_while(queue, timeout, |queue| !queue.closed)
+            .unwrap();
+        queue.closed
+    }
+
+    fn close(&self) {
+        self.queue.lock().unwrap().closed = true;
+        self.changed.notify_all();
+    }
+}
+
+/// A bounded queue of records, delivered to a peer by a thread of its own.
+pub struct Outbox {
+    shared: Arc<Shared>,
+    done: Receiver<()>,
+}
+
+impl Outbox {
+    /// Stream records to a peer, reconnecting to it whenever it goes away.
+    pub fn spawn<C>(connector: C, limit: usize, backoff: Backoff) -> io::Result<Self>
+    where
+        C: Connect,
+    {
+        Self::start(connector.name(), limit, move |shared| {
+            deliver(connector, shared, backoff)
+        })
+    }
+
+    /// Hand the queue to `work`, run on a thread named `name`.
+    pub fn start<F>(name: String, limit: usize, work: F) -> io::Result<Self>
+    where
+        F: FnOnce(&Shared) + Send + 'static,
+    {
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                records: VecDeque::new(),
+                len: 0,
+                limit,
+                dropped: 0,
+                closed: false,
+            }),
+            changed: Condvar::new(),
+        });
+        // the sender is dropped along with the thread, which is all `done` waits for
+        let (finished, done) = mpsc::channel::<()>();
+        let worker = shared.clone();
+        thread::Builder::new().name(name).spawn(move || {
+            let _finished = finished;
+            work(&worker);
+        })?;
+        Ok(Self { shared, done })
+    }
+
+    /// Queue a record up, dropping the oldest ones if there's no room left.
+    pub fn push(&self, record: Vec<u8>) {
+        let mut queue = self.shared.queue.lock().unwrap();
+        if queue.closed {
+            return;
+        }
+        queue.len += record.len();
+        queue.records.push_back(record);
+        while queue.len > queue.limit {
+            let Some(oldest) = queue.records.pop_front() else {
+                break;
+            };
+            queue.len -= oldest.len();
+            queue.dropped += oldest.len();
+        }
+        drop(queue);
+        self.shared.changed.notify_one();
+    }
+
+    /// Stop taking output and wait until `deadline` for what's queued to be
+    /// delivered.
+    pub fn drain(&mut self, deadline: Instant) {
+        self.shared.close();
+        let timeout = deadline.saturating_duration_since(Instant::now());
+        let _ = self.done.recv_timeout(timeout);
+    }
+}
+
+impl Drop for Outbox {
+    fn drop(&mut self) {
+        // the thread delivers whatever is left on its own, if it can
+        self.shared.close();
+    }
+}
+
+fn deliver<C>(connector: C, shared: &Shared, backoff: Backoff)
+where
+    C: Connect,
+{
+    let name = connector.name();
+    let mut delay = backoff.min;
+    let mut closing = false;
+    loop {
+        let failure = match connector.connect() {
+            Ok(mut stream) => {
+                let dropped = shared.take_dropped();
+                if dropped > 0 {
+                    eprintln!("{}: dropped {} bytes while disconnected", name, dropped);
+                }
+                loop {
+                    let Some(record) = shared.next() else {
+                        return;
+                    };
+                    if let Err(e) = stream.write_all(&record) {
+                        // whatever part of it went out was lost along with the
+                        // connection, so the next one gets all of it
+                        shared.requeue(record);
+                        break e;
+                    }
+                    delay = backoff.min;
+                }
+            }
+            Err(e) => e,
+        };
+        if closing {
+            eprintln!("{}: {}, giving up on what's left", name, failure);
+            return;
+        }
+        eprintln!(
+            "{}: {}, reconnecting in {}ms",
+            name,
+            failure,
+            delay.as_millis()
+        );
+        // once closed, what's still queued gets one last attempt
+        closing = shared.wait_closed(delay);
+        delay = (delay * 2).min(backoff.max);
+    }
+}
+
+/// Splits output into lines, holding on to the start of a line until its end
+/// comes along.
+pub struct Lines {
+    partial: Vec<u8>,
+    /// Lines longer than this are cut in pieces.
+    max_line: usize,
+}
+
+impl Lines {
+    pub fn new(max_line: usize) -> Self {
+        Self {
+            partial: Vec::new(),
+            max_line: max_line.max(1),
+        }
+    }
+
+    /// Every line `data` completes, without its newline, along with the
+    /// pieces of `max_line` long ones.
+    pub fn split(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        let mut lines = Vec::new();
+        let mut pieces = data.split(|&b| b == b'\n');
+        // the last piece never ends in a newline, so it's held back
+        let last = pieces.next_back().unwrap_or_default();
+        for piece in pieces {
+            self.partial.extend_from_slice(piece);
+            self.cut_longer_than(self.max_line, &mut lines);
+            lines.push(std::mem::take(&mut self.partial));
+        }
+        self.partial.extend_from_slice(last);
+        // a piece of exactly `max_line` is held on to, since the newline may
+        // well be next and would otherwise make an empty line of its own
+        self.cut_longer_than(self.max_line, &mut lines);
+        lines
+    }
+
+    /// Cut pieces of `max_line` off the unfinished line while it's longer
+    /// than `len`.
+    fn cut_longer_than(&mut self, len: usize, lines: &mut Vec<Vec<u8>>) {
+        while self.partial.len() > len {
+            let rest = self.partial.split_off(self.max_line);
+            lines.push(std::mem::replace(&mut self.partial, rest));
+        }
+    }
+
+    /// Whatever line is left unfinished.
+    pub fn finish(&mut self) -> Option<Vec<u8>> {
+        (!self.partial.is_empty()).then(|| std::mem::take(&mut self.partial))
+    }
+}
+
+/// Cuts output into the records a TCP consumer sends.
+struct Framer {
+    framing: NetFraming,
+    lines: Lines,
+}
+
+impl Framer {
+    fn encode(&self, line: &[u8]) -> Vec<u8> {
+        match self.framing {
+            NetFraming::None => line.to_vec(),
+            NetFraming::Newline => [line, b"\n"].concat(),
+            NetFraming::Length => [&(line.len() as u32).to_be_bytes(), line].concat(),
+        }
+    }
+
+    fn frame(&mut self, data: &[u8]) -> Vec<Vec<u8>> {
+        if self.framing == NetFraming::None {
+            return vec![data.to_vec()];
+        }
+        let lines = self.lines.split(data);
+        lines.iter().map(|line| self.encode(line)).collect()
+    }
+
+    fn finish(&mut self) -> Option<Vec<u8>> {
+        let line = self.lines.finish()?;
+        Some(self.encode(&line))
+    }
+}
+
+/// Connect to the first address `address` resolves to that accepts.
+pub fn connect_tcp(address: &str, timeout: Duration) -> io::Result<TcpStream> {
+    let mut last_err = io::Error::new(io::ErrorKind::NotFound, "address didn't resolve");
+    for addr in address.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, timeout) {
+            Ok(stream) => {
+                stream.set_write_timeout(Some(timeout))?;
+                return Ok(stream);
+            }
+            Err(e) => last_err = e,
+        }
+    }
+    Err(last_err)
+}
+
+/// Split a plain `http://` URL into the `host:port` to connect to, the
+/// authority to send as `Host` and the path.
+pub fn split_http_url(url: &str) -> io::Result<(String, &str, &str)> {
+    let rest = url
+        .strip_prefix("http://")
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "only http:// is supported"))?;
+    let (authority, path) = match rest.find('/') {
+        Some(idx) => rest.split_at(idx),
+        None => (rest, "/"),
+    };
+    let addr = if authority.contains(':') {
+        authority.to_string()
+    } else {
+        format!("{}:80", authority)
+    };
+    Ok((addr, authority, path))
+}
+
+/// Read the status code off an HTTP response, if it has a sensible one.
+pub fn read_http_status<R>(stream: &mut R) -> io::Result<Option<u16>>
+where
+    R: Read,
+{
+    let mut response = Vec::new();
+    let mut chunk = [0; 256];
+    // the status line is all we care about
+    while !response.contains(&b'\n') {
+        match stream.read(&mut chunk)? {
+            0 => break,
+            n => response.extend_from_slice(&chunk[..n]),
+        }
+    }
+    let status_line = String::from_utf8_lossy(&response);
+    Ok(status_line
+        .split_whitespace()
+        .nth(1)
+        .and_then(|code| code.parse::<u16>().ok()))
+}
+
+/// A connected datagram socket, which sends every write as a datagram.
+pub enum Datagrams {
+    Udp(UdpSocket),
+    Unix(UnixDatagram),
+}
+
+impl Datagrams {
+    pub fn connect_udp(address: &str) -> io::Result<Self> {
+        let addr = address
+            .to_socket_addrs()?
+            .next()
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, "address didn't resolve"))?;
+        let local: SocketAddr = match addr {
+            SocketAddr::V4(_) => (Ipv4Addr::UNSPECIFIED, 0).into(),
+            SocketAddr::V6(_) => (Ipv6Addr::UNSPECIFIED, 0).into(),
+        };
+        let socket = UdpSocket::bind(local)?;
+        socket.connect(addr)?;
+        Ok(Self::Udp(socket))
+    }
+
+    pub fn connect_unix(path: &Path, timeout: Duration) -> io::Result<Self> {
+        let socket = UnixDatagram::unbound()?;
+        socket.connect(path)?;
+        socket.set_write_timeout(Some(timeout))?;
+        Ok(Self::Unix(socket))
+    }
+}
+
+impl Write for Datagrams {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Udp(socket) => socket.send(buf),
+            Self::Unix(socket) => socket.send(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+struct TcpConnector {
+    address: String,
+    timeout: Duration,
+}
+
+impl Connect for TcpConnector {
+    type Stream = TcpStream;
+
+    fn connect(&self) -> io::Result<TcpStream> {
+        connect_tcp(&self.address, self.timeout)
+    }
+
+    fn name(&self) -> String {
+        format!("tcp {}", self.address)
+    }
+}
+
+pub struct TcpConsumer {
+    framer: Framer,
+    outbox: Outbox,
+}
+
+impl TcpConsumer {
+    pub fn new(conf: &TcpConf) -> io::Result<Self> {
+        let connector = TcpConnector {
+            address: conf.address.clone(),
+            timeout: Duration::from_millis(conf.delivery.timeout_ms),
+        };
+        Ok(Self {
+            framer: Framer {
+                framing: conf.framing,
+                lines: Lines::new(conf.max_line),
+            },
+            outbox: Outbox::spawn(
+                connector,
+                conf.delivery.queue_bytes,
+                (&conf.delivery).into(),
+            )?,
+        })
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        for record in self.framer.frame(data) {
+            self.outbox.push(record);
+        }
+    }
+
+    pub fn drain(&mut self, deadline: Instant) {
+        if let Some(record) = self.framer.finish() {
+            self.outbox.push(record);
+        }
+        self.outbox.drain(deadline);
+    }
+}
+
+impl Drop for TcpConsumer {
+    fn drop(&mut self) {
+        if let Some(record) = self.framer.finish() {
+            self.outbox.push(record);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// What every connection made so far was sent.
+    type Sent = Arc<Mutex<Vec<Vec<u8>>>>;
+
+    /// A peer whose first connection breaks after taking `budget` bytes.
+    struct Flaky {
+        sent: Sent,
+        budget: usize,
+    }
+
+    struct FlakyStream {
+        sent: Sent,
+        idx: usize,
+        budget: Option<usize>,
+    }
+
+    impl Write for FlakyStream {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            let len = match self.budget {
+                Some(0) => return Err(io::ErrorKind::BrokenPipe.into()),
+                Some(budget) => budget.min(buf.len()),
+                None => buf.len(),
+            };
+            if let Some(budget) = &mut self.budget {
+                *budget -= len;
+            }
+            self.sent.lock().unwrap()[self.idx].extend_from_slice(&buf[..len]);
+            Ok(len)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connect for Flaky {
+        type Stream = FlakyStream;
+
+        fn connect(&self) -> io::Result<FlakyStream> {
+            let mut sent = self.sent.lock().unwrap();
+            sent.push(Vec::new());
+            Ok(FlakyStream {
+                sent: self.sent.clone(),
+                idx: sent.len() - 1,
+                budget: (sent.len() == 1).then_some(self.budget),
+            })
+        }
+
+        fn name(&self) -> String {
+            "flaky".to_string()
+        }
+    }
+
+    const BACKOFF: Backoff = Backoff {
+        min: Duration::from_millis(1),
+        max: Duration::from_millis(1),
+    };
+
+    #[test]
+    fn records_cut_short_are_sent_again_whole() {
+        let sent = Sent::default();
+        let connector = Flaky {
+            sent: sent.clone(),
+            budget: 9,
+        };
+        let mut outbox = Outbox::spawn(connector, 1024, BACKOFF).unwrap();
+        for record in [b"\0\0\0\x03one", b"\0\0\0\x03two", b"\0\0\0\x03six"] {
+            outbox.push(record.to_vec());
+        }
+        outbox.drain(Instant::now() + Duration::from_secs(5));
+        let sent = sent.lock().unwrap();
+        assert_eq!(
+            *sent,
+            [
+                b"\0\0\0\x03one\0\0".to_vec(),
+                b"\0\0\0\x03two\0\0\0\x03six".to_vec()
+            ]
+        );
+    }
+
+    fn strings(lines: Vec<Vec<u8>>) -> Vec<String> {
+        lines
+            .into_iter()
+            .map(|line| String::from_utf8(line).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn lines_are_cut_at_max_line() {
+        let mut lines = Lines::new(4);
+        assert_eq!(
+            strings(lines.split(b"abcdefghij\nxy")),
+            ["abcd", "efgh", "ij"]
+        );
+        assert!(lines.split(b"z").is_empty());
+        assert_eq!(strings(lines.split(b"123456")), ["xyz1", "2345"]);
+        assert_eq!(lines.finish(), Some(b"6".to_vec()));
+        assert_eq!(lines.finish(), None);
+    }
+
+    #[test]
+    fn lines_of_exactly_max_line_stay_whole() {
+        let mut lines = Lines::new(4);
+        assert!(lines.split(b"abcd").is_empty());
+        assert_eq!(strings(lines.split(b"\n")), ["abcd"]);
+        assert_eq!(strings(lines.split(b"efgh\n\n")), ["efgh", ""]);
+    }
+
+    #[test]
+    fn max_line_is_at_least_one() {
+        let mut lines = Lines::new(0);
+        assert_eq!(strings(lines.split(b"ab\n")), ["a", "b"]);
+    }
+
+    #[test]
+    fn newline_framing() {
+        let mut framer = Framer {
+            framing: NetFraming::Newline,
+            lines: Lines::new(4),
+        };
+        assert_eq!(strings(framer.frame(b"one\ntw")), ["one\n"]);
+        assert_eq!(strings(framer.frame(b"o\r\nlonger")), ["two\r\n", "long\n"]);
+        assert_eq!(framer.finish(), Some(b"er\n".to_vec()));
+    }
+
+    #[test]
+    fn length_framing() {
+        let mut framer = Framer {
+            framing: NetFraming::Length,
+            lines: Lines::new(1024),
+        };
+        assert_eq!(
+            framer.frame(b"one\n\nsix\n"),
+            [
+                b"\0\0\0\x03one".to_vec(),
+                b"\0\0\0\0".to_vec(),
+                b"\0\0\0\x03six".to_vec()
+            ]
+        );
+        assert_eq!(framer.finish(), None);
+    }
+
+    #[test]
+    fn no_framing_passes_output_through() {
+        let mut framer = Framer {
+            framing: NetFraming::None,
+            lines: Lines::new(4),
+        };
+        assert_eq!(
+            framer.frame(b"one\ntwo and more"),
+            [b"one\ntwo and more".to_vec()]
+        );
+        assert_eq!(framer.finish(), None);
+    }
+
+    /// The records queued up in `outbox`, oldest first.
+    fn queued(outbox: &Outbox) -> Vec<Vec<u8>> {
+        let queue = outbox.shared.queue.lock().unwrap();
+        queue.records.iter().cloned().collect()
+    }
+
+    #[test]
+    fn queue_drops_the_oldest_records_past_its_limit() {
+        // nothing delivers, so whatever is pushed stays queued
+        let outbox = Outbox::start("idle".to_string(), 100, |_| {}).unwrap();
+        for record in [[b'1'; 40], [b'2'; 40], [b'3'; 40]] {
+            outbox.push(record.to_vec());
+        }
+        assert_eq!(queued(&outbox), [[b'2'; 40], [b'3'; 40]]);
+        assert_eq!(outbox.shared.take_dropped(), 40);
+        assert_eq!(outbox.shared.take_dropped(), 0);
+
+        // a record that didn't make it goes first, if there's room for it
+        outbox.shared.requeue(vec![b'0'; 20]);
+        assert_eq!(outbox.shared.queue.lock().unwrap().len, 100);
+        assert_eq!(queued(&outbox)[0], [b'0'; 20]);
+        outbox.shared.requeue(vec![b'0'; 20]);
+        assert_eq!(queued(&outbox).len(), 3);
+        assert_eq!(outbox.shared.take_dropped(), 20);
+    }
+
+    #[test]
+    fn queue_takes_nothing_once_closed() {
+        let mut outbox = Outbox::start("idle".to_string(), 100, |_| {}).unwrap();
+        outbox.push(b"kept".to_vec());
+        outbox.drain(Instant::now());
+        outbox.push(b"lost".to_vec());
+        assert_eq!(queued(&outbox), [b"kept".to_vec()]);
+        assert_eq!(outbox.shared.next(), Some(b"kept".to_vec()));
+        assert_eq!(outbox.shared.next(), None);
+    }
+}
@@ -0,0 +1,178 @@
+//! Support for the systemd notification protocol (`sd_notify`).
+//!
+//! Services of type `notify` get their own unix datagram socket, passed to them
+//! through `NOTIFY_SOCKET`, on which they report their state as newline
+//! separated `KEY=VALUE` assignments. The watcher only tells when the socket is
+//! readable: datagrams are received here, along with the credentials of their
+//! sender, since anyone able to reach the socket could send one.
+use crate::utils::create_private_dir;
+use nix::unistd::Pid;
+use std::{
+    fs, io,
+    os::{
+        fd::{AsRawFd, RawFd},
+        unix::net::UnixDatagram,
+    },
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+/// Large enough for any sensible notification, larger datagrams get truncated.
+const NOTIFY_BUFSIZE: usize = 4096;
+
+#[derive(Debug)]
+pub struct Notify {
+    socket: UnixDatagram,
+    pub path: PathBuf,
+    pub ready: bool,
+    pub stopping: bool,
+    pub status: Option<String>,
+    pub main_pid: Option<Pid>,
+    pub last_watchdog: Option<Instant>,
+}
+
+impl Notify {
+    pub fn bind(path: PathBuf) -> io::Result<Self> {
+        if let Some(dir) = path.parent() {
+            create_private_dir(dir)?;
+        }
+        // a previous run may have left its socket behind
+        match fs::remove_file(&path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => return Err(e),
+            _ => (),
+        }
+        let socket = UnixDatagram::bind(&path)?;
+        socket.set_nonblocking(true)?;
+        #[cfg(target_os = "linux")]
+        nix::sys::socket::setsockopt(&socket, nix::sys::socket::sockopt::PassCred, &true)?;
+        Ok(Self {
+            socket,
+            path,
+            ready: false,
+            stopping: false,
+            status: None,
+            main_pid: None,
+            last_watchdog: None,
+        })
+    }
+
+    pub fn socket_path(dir: &Path, service: &str) -> PathBuf {
+        dir.join(format!("{}.notify", service))
+    }
+
+    /// Forget everything the previous process of the service reported.
+    pub fn reset(&mut self) {
+        self.ready = false;
+        self.stopping = false;
+        self.status = None;
+        self.main_pid = None;
+        self.last_watchdog = None;
+    }
+
+    /// Every datagram waiting on the socket, along with the pid of its sender
+    /// where the platform tells it.
+    pub fn receive(&self) -> io::Result<Vec<(Option<Pid>, Vec<u8>)>> {
+        let mut received = Vec::new();
+        loop {
+            let mut buf = vec![0; NOTIFY_BUFSIZE];
+            match self.receive_one(&mut buf) {
+                Ok((len, sender)) => {
+                    buf.truncate(len);
+                    received.push((sender, buf));
+                }
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return Ok(received),
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    #[cfg(target_os = "linux")]
+    fn receive_one(&self, buf: &mut [u8]) -> io::Result<(usize, Option<Pid>)> {
+        use nix::sys::socket::{recvmsg, ControlMessageOwned, MsgFlags, UnixCredentials};
+        use std::io::IoSliceMut;
+
+        let mut iov = [IoSliceMut::new(buf)];
+        let mut cmsg = nix::cmsg_space!(UnixCredentials);
+        let msg = recvmsg::<()>(
+            self.socket.as_raw_fd(),
+            &mut iov,
+            Some(&mut cmsg),
+            MsgFlags::MSG_CMSG_CLOEXEC,
+        )?;
+        let sender = msg.cmsgs()?.find_map(|cmsg| match cmsg {
+            ControlMessageOwned::ScmCredentials(creds) => Some(Pid::from_raw(creds.pid())),
+            _ => None,
+        });
+        Ok((msg.bytes, sender))
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn receive_one(&self, buf: &mut [u8]) -> io::Result<(usize, Option<Pid>)> {
+        Ok((self.socket.recv(buf)?, None))
+    }
+
+    pub fn handle(&mut self, msg: &[u8]) {
+        for line in String::from_utf8_lossy(msg).lines() {
+            let Some((key, value)) = line.split_once('=') else {
+                continue;
+            };
+            match (key, value) {
+                ("READY", "1") => {
+                    self.ready = true;
+                    // the watchdog only starts ticking once the service is up
+                    self.last_watchdog.get_or_insert_with(Instant::now);
+                }
+                ("STOPPING", "1") => self.stopping = true,
+                ("WATCHDOG", "1") => self.last_watchdog = Some(Instant::now()),
+                ("STATUS", status) => self.status = Some(status.to_string()),
+                ("MAINPID", pid) => match pid.parse() {
+                    Ok(pid) => self.main_pid = Some(Pid::from_raw(pid)),
+                    Err(_) => eprintln!("Ignoring bad MAINPID={}", pid),
+                },
+                _ => (),
+            }
+        }
+    }
+}
+
+/// Whether `pid` is `ancestor` or one of its descendants, by following parent
+/// pids up through `/proc`. A process whose parent went away hangs off us
+/// as a subreaper rather than off its grandparent, so a daemon has to report
+/// its pid before the process it forked from exits.
+#[cfg(target_os = "linux")]
+pub fn descends_from(pid: Pid, ancestor: Pid) -> bool {
+    let mut pid = pid;
+    while pid != ancestor {
+        let Ok(stat) = fs::read_to_string(format!("/proc/{}/stat", pid)) else {
+            return false;
+        };
+        // the command name in parentheses may contain anything, spaces included
+        let ppid = stat
+            .rsplit_once(')')
+            .and_then(|(_, rest)| rest.split_whitespace().nth(1))
+            .and_then(|ppid| ppid.parse::<i32>().ok());
+        match ppid {
+            Some(ppid) if ppid > 1 => pid = Pid::from_raw(ppid),
+            _ => return false,
+        }
+    }
+    true
+}
+
+/// Without `/proc` to follow, only the process itself qualifies.
+#[cfg(not(target_os = "linux"))]
+pub fn descends_from(pid: Pid, ancestor: Pid) -> bool {
+    pid == ancestor
+}
+
+impl AsRawFd for Notify {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.as_raw_fd()
+    }
+}
+
+impl Drop for Notify {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.path);
+    }
+}
@@ -0,0 +1,664 @@
+//! Runs consumers on a pool of worker threads, so that a slow disk or terminal
+//! never holds up the event loop reading output.
+//!
+//! Every consumer gets a queue of its own, bounded by its `queue_bytes`, and
+//! whichever worker is free empties it. A consumer is only ever on one worker
+//! at a time, so what it's handed keeps its order. When a queue is full, the
+//! consumer's overflow policy decides between waiting for room and dropping
+//! output, and dropped output is counted and reported.
+//!
+//! A consumer failing to write only ever affects itself: its error policy has
+//! it carry on, get disabled, or hand the error to the event loop to shut
+//! kinesin down. Either way its failures are counted for `status`.
+use crate::{
+    conf::{ConsumerConf, OnError, Overflow},
+    consumer::Consumer,
+    control::ConsumerStatus,
+};
+use nix::unistd::Pid;
+use std::{
+    collections::VecDeque,
+    io, mem,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread,
+    time::Instant,
+};
+
+/// Something a consumer is asked to do, in the order it was asked.
+enum Job {
+    Write(Vec<u8>),
+    SetPid(Option<Pid>),
+    Reopen,
+}
+
+impl Job {
+    fn len(&self) -> usize {
+        match self {
+            Self::Write(data) => data.len(),
+            _ => 0,
+        }
+    }
+}
+
+struct Queue {
+    jobs: VecDeque<Job>,
+    /// Bytes of output in `jobs`.
+    bytes: usize,
+    /// Whether the consumer is on a worker or waiting for one.
+    scheduled: bool,
+    /// Set once the consumer is no longer wanted, it's dropped once the
+    /// queue is empty.
+    closed: bool,
+    /// Bytes dropped since the last report.
+    dropped: usize,
+    /// Bytes dropped over the consumer's lifetime.
+    dropped_total: u64,
+    failures: u64,
+    /// Failures since the consumer last wrote something.
+    streak: u64,
+    last_error: Option<String>,
+    disabled: bool,
+}
+
+struct Slot {
+    name: String,
+    status: ConsumerStatus,
+    limit: usize,
+    overflow: Overflow,
+    on_error: OnError,
+    /// Where a fatal failure goes for the event loop to pick up.
+    fatal: Arc<Mutex<Option<io::Error>>>,
+    queue: Mutex<Queue>,
+    /// Signalled whenever the queue gets emptied.
+    changed: Condvar,
+    consumer: Mutex<Option<Consumer>>,
+}
+
+impl Slot {
+    /// Run every job queued, until there are none left.
+    fn run(&self) {
+        let mut consumer = self.consumer.lock().unwrap();
+        loop {
+            let mut queue = self.queue.lock().unwrap();
+            if queue.jobs.is_empty() {
+                queue.scheduled = false;
+                let closed = queue.closed;
+                drop(queue);
+                self.changed.notify_all();
+                if closed {
+                    // dropping a consumer can take a while, e.g. to finish
+                    // compressing a log
+                    drop(consumer.take());
+                }
+                return;
+            }
+            let jobs = mem::take(&mut queue.jobs);
+            let dropped = mem::take(&mut queue.dropped);
+            queue.bytes = 0;
+            drop(queue);
+            self.changed.notify_all();
+
+            if dropped > 0 {
+                eprintln!("{}: dropped {} bytes over queue_bytes", self.name, dropped);
+            }
+            let Some(consumer) = consumer.as_mut() else {
+                continue;
+            };
+            let mut jobs = jobs.into_iter();
+            for job in jobs.by_ref() {
+                let len = job.len();
+                let result = match job {
+                    Job::Write(data) => consumer.write(&data),
+                    Job::SetPid(pid) => {
+                        consumer.set_pid(pid);
+                        continue;
+                    }
+                    Job::Reopen => consumer.reopen(),
+                };
+                if !self.record(result, len) {
+                    break;
+                }
+            }
+            // whatever is left was meant for a consumer that got disabled
+            let skipped = jobs.map(|job| job.len() as u64).sum::<u64>();
+            self.queue.lock().unwrap().dropped_total += skipped;
+        }
+    }
+
+    /// Keep track of how the consumer is doing after a job handing it `len`
+    /// bytes, returning whether it should be handed anything else.
+    fn record(&self, result: io::Result<()>, len: usize) -> bool {
+        let mut queue = self.queue.lock().unwrap();
+        let e = match result {
+            Ok(()) => {
+                if queue.streak > 0 {
+                    eprintln!(
+                        "{}: recovered after {} failed writes",
+                        self.name, queue.streak
+                    );
+                    queue.streak = 0;
+                }
+                return true;
+            }
+            Err(e) => e,
+        };
+        queue.failures += 1;
+        queue.streak += 1;
+        queue.dropped_total += len as u64;
+        queue.last_error = Some(e.to_string());
+        match self.on_error {
+            OnError::Retry => {
+                if queue.streak == 1 {
+                    eprintln!("{}: {}, losing its output until it recovers", self.name, e);
+                }
+            }
+            OnError::Disable => {
+                eprintln!("{}: {}, disabling it", self.name, e);
+                queue.disabled = true;
+            }
+            OnError::Fatal => {
+                let e = io::Error::new(e.kind(), format!("{}: {}", self.name, e));
+                self.fatal.lock().unwrap().get_or_insert(e);
+            }
+        }
+        !queue.disabled
+    }
+}
+
+struct Workers {
+    ready: VecDeque<Arc<Slot>>,
+    /// Workers numbered from this on are to stop.
+    size: usize,
+    /// Which workers are still running, those asked to stop included until
+    /// they're done with what they're on.
+    live: Vec<bool>,
+}
+
+struct Shared {
+    workers: Mutex<Workers>,
+    wake: Condvar,
+}
+
+impl Shared {
+    fn schedule(&self, slot: Arc<Slot>) {
+        self.workers.lock().unwrap().ready.push_back(slot);
+        self.wake.notify_one();
+    }
+
+    fn work(&self, idx: usize) {
+        loop {
+            let mut workers = self.workers.lock().unwrap();
+            let slot = loop {
+                if idx >= workers.size {
+                    workers.live[idx] = false;
+                    return;
+                }
+                if let Some(slot) = workers.ready.pop_front() {
+                    break slot;
+                }
+                workers = self.wake.wait(workers).unwrap();
+            };
+            drop(workers);
+            slot.run();
+        }
+    }
+}
+
+pub struct Pool {
+    shared: Arc<Shared>,
+    /// The first failure of a consumer with the fatal error policy.
+    fatal: Arc<Mutex<Option<io::Error>>>,
+}
+
+impl Pool {
+    /// A pool without workers, until it's resized.
+    pub fn new() -> Self {
+        Self {
+            shared: Arc::new(Shared {
+                workers: Mutex::new(Workers {
+                    ready: VecDeque::new(),
+                    size: 0,
+                    live: Vec::new(),
+                }),
+                wake: Condvar::new(),
+            }),
+            fatal: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    /// Start or stop workers until there are `threads` of them.
+    pub fn resize(&mut self, threads: usize) -> io::Result<()> {
+        let mut workers = self.shared.workers.lock().unwrap();
+        workers.size = threads;
+        if workers.live.len() < threads {
+            workers.live.resize(threads, false);
+        }
+        // a worker asked to stop that's still busy carries on as if it never
+        // was, only those gone for good get replaced
+        for idx in 0..threads {
+            if workers.live[idx] {
+                continue;
+            }
+            let shared = self.shared.clone();
+            thread::Builder::new()
+                .name(format!("consumer-{}", idx))
+                .spawn(move || shared.work(idx))?;
+            workers.live[idx] = true;
+        }
+        drop(workers);
+        // the workers past the new size notice once woken
+        self.shared.wake.notify_all();
+        Ok(())
+    }
+
+    /// The failure of a consumer whose error policy is fatal, if one failed.
+    pub fn take_fatal(&self) -> Option<io::Error> {
+        self.fatal.lock().unwrap().take()
+    }
+
+    /// Hand `consumer` over to the pool, queueing up to `conf.queue_bytes`.
+    pub fn queue(&self, consumer: Consumer, conf: &ConsumerConf) -> Queued {
+        let slot = Slot {
+            name: format!(
+                "{} consumer of {} {}",
+                conf.kind.name(),
+                conf.consumes.service_name(),
+                conf.consumes.stream_name()
+            ),
+            status: ConsumerStatus {
+                service: conf.consumes.service_name().to_string(),
+                stream: conf.consumes.stream_name().to_string(),
+                kind: conf.kind.name().to_string(),
+                ..ConsumerStatus::default()
+            },
+            limit: conf.queue_bytes,
+            overflow: conf.overflow,
+            on_error: conf.on_error,
+            fatal: self.fatal.clone(),
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                bytes: 0,
+                scheduled: false,
+                closed: false,
+                dropped: 0,
+                dropped_total: 0,
+                failures: 0,
+                streak: 0,
+                last_error: None,
+                disabled: false,
+            }),
+            changed: Condvar::new(),
+            consumer: Mutex::new(Some(consumer)),
+        };
+        Queued {
+            slot: Arc::new(slot),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+/// A consumer running on the pool, as the bus feeding it sees it.
+pub struct Queued {
+    slot: Arc<Slot>,
+    shared: Arc<Shared>,
+}
+
+impl Queued {
+    fn push(&self, job: Job) {
+        let slot = &self.slot;
+        let len = job.len();
+        let mut queue = slot.queue.lock().unwrap();
+        if queue.disabled {
+            queue.dropped_total += len as u64;
+            return;
+        }
+        // output too big for the queue gets in once the queue is empty
+        let full = |queue: &Queue| queue.bytes > 0 && queue.bytes + len > slot.limit;
+        if len > 0 {
+            match slot.overflow {
+                Overflow::Block => {
+                    while full(&queue) {
+                        queue = slot.changed.wait(queue).unwrap();
+                    }
+                }
+                Overflow::DropNewest => {
+                    if full(&queue) {
+                        queue.dropped += len;
+                        queue.dropped_total += len as u64;
+                        return;
+                    }
+                }
+                Overflow::DropOldest => {
+                    while full(&queue) {
+                        let Some(idx) = queue.jobs.iter().position(|job| job.len() > 0) else {
+                            break;
+                        };
+                        let oldest = queue.jobs.remove(idx).unwrap();
+                        queue.bytes -= oldest.len();
+                        queue.dropped += oldest.len();
+                        queue.dropped_total += oldest.len() as u64;
+                    }
+                }
+            }
+        }
+        queue.bytes += len;
+        queue.jobs.push_back(job);
+        self.wake(queue);
+    }
+
+    /// Get a worker on the consumer unless one already is.
+    fn wake(&self, mut queue: MutexGuard<'_, Queue>) {
+        if !queue.scheduled {
+            queue.scheduled = true;
+            drop(queue);
+            self.shared.schedule(self.slot.clone());
+        }
+    }
+
+    pub fn write(&self, data: &[u8]) {
+        self.push(Job::Write(data.to_vec()))
+    }
+
+    pub fn set_pid(&self, pid: Option<Pid>) {
+        let mut queue = self.slot.queue.lock().unwrap();
+        queue.jobs.push_back(Job::SetPid(pid));
+        self.wake(queue);
+    }
+
+    pub fn reopen(&self) {
+        self.push(Job::Reopen)
+    }
+
+    pub fn status(&self) -> ConsumerStatus {
+        let queue = self.slot.queue.lock().unwrap();
+        let state = match (queue.disabled, queue.streak) {
+            (true, _) => "disabled",
+            (false, 0) => "ok",
+            (false, _) => "failing",
+        };
+        ConsumerStatus {
+            state: state.to_string(),
+            queued_bytes: queue.bytes,
+            dropped_bytes: queue.dropped_total,
+            failures: queue.failures,
+            last_error: queue.last_error.clone(),
+            ..self.slot.status.clone()
+        }
+    }
+
+    /// Wait until `deadline` at the latest for the queue to empty, and then
+    /// for the consumer to deliver what it holds on to.
+    pub fn drain(&self, deadline: Instant) {
+        let mut queue = self.slot.queue.lock().unwrap();
+        while queue.scheduled {
+            let Some(timeout) = deadline.checked_duration_since(Instant::now()) else {
+                eprintln!("{}: gave up on what's still queued", self.slot.name);
+                return;
+            };
+            queue = self.slot.changed.wait_timeout(queue, timeout).unwrap().0;
+        }
+        drop(queue);
+        if let Some(consumer) = self.slot.consumer.lock().unwrap().as_mut() {
+            consumer.drain(deadline);
+        }
+    }
+}
+
+impl Drop for Queued {
+    fn drop(&mut self) {
+        // the consumer still gets whatever is queued before it's dropped
+        let mut queue = self.slot.queue.lock().unwrap();
+        queue.closed = true;
+        self.wake(queue);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::consumer::StdinPipes;
+    use nix::{sys::stat::Mode, unistd::mkfifo};
+    use std::{
+        fs::{self, File},
+        io::Read,
+        os::unix::fs::symlink,
+        path::{Path, PathBuf},
+        sync::mpsc,
+        thread::ScopedJoinHandle,
+        time::Duration,
+    };
+
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    /// A fresh directory for one test.
+    fn test_dir(test: &str) -> PathBuf {
+        let dir =
+            std::env::temp_dir().join(format!("kinesin-pool-{}-{}", test, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn pool() -> Pool {
+        let mut pool = Pool::new();
+        pool.resize(1).unwrap();
+        pool
+    }
+
+    /// A consumer of `app`'s stdout logging to `path`.
+    fn logger(pool: &Pool, path: &Path, extra: &str) -> Queued {
+        let conf = format!(
+            "consumes = {{ stdout = \"app\" }}\nkind = {{ log = \"{}\" }}\n{}",
+            path.display(),
+            extra
+        );
+        let conf = toml::from_str::<ConsumerConf>(&conf).unwrap();
+        let consumer = Consumer::new(&conf, 0, &StdinPipes::default()).unwrap();
+        pool.queue(consumer, &conf)
+    }
+
+    /// Wait for a worker to take everything queued off the consumer's hands.
+    fn wait_taken(queued: &Queued) {
+        let deadline = Instant::now() + TIMEOUT;
+        while queued.status().queued_bytes > 0 {
+            assert!(Instant::now() < deadline, "nothing took the queued output");
+            thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    /// Bytes dropped and not reported yet.
+    fn unreported(queued: &Queued) -> usize {
+        queued.slot.queue.lock().unwrap().dropped
+    }
+
+    /// A consumer writing into a FIFO, stuck on a write far larger than the
+    /// FIFO holds until the reader is told to go. The reader reads everything
+    /// up to the consumer being dropped.
+    fn stalled<'scope>(
+        scope: &'scope thread::Scope<'scope, '_>,
+        pool: &Pool,
+        dir: &Path,
+        overflow: &str,
+    ) -> (Queued, mpsc::Sender<()>, ScopedJoinHandle<'scope, Vec<u8>>) {
+        let fifo = dir.join("fifo");
+        mkfifo(&fifo, Mode::S_IRWXU).unwrap();
+        let (go, wait) = mpsc::channel();
+        let reader = scope.spawn(move || {
+            let mut fifo = File::open(fifo).unwrap();
+            wait.recv().unwrap();
+            let mut read = Vec::new();
+            fifo.read_to_end(&mut read).unwrap();
+            read
+        });
+        let extra = format!("queue_bytes = 100\noverflow = \"{}\"", overflow);
+        let queued = logger(pool, &dir.join("fifo"), &extra);
+        queued.write(&vec![b'0'; 1024 * 1024]);
+        wait_taken(&queued);
+        (queued, go, reader)
+    }
+
+    #[test]
+    fn drop_newest_keeps_what_was_queued_first() {
+        let dir = test_dir("drop-newest");
+        let pool = pool();
+        thread::scope(|scope| {
+            let (queued, go, reader) = stalled(scope, &pool, &dir, "drop-newest");
+            queued.write(&[b'1'; 60]);
+            queued.write(&[b'2'; 60]);
+            assert_eq!(queued.status().queued_bytes, 60);
+            assert_eq!(queued.status().dropped_bytes, 60);
+            assert_eq!(unreported(&queued), 60);
+
+            go.send(()).unwrap();
+            queued.drain(Instant::now() + TIMEOUT);
+            // reported once the worker took what's queued
+            assert_eq!(unreported(&queued), 0);
+            assert_eq!(queued.status().dropped_bytes, 60);
+            drop(queued);
+            let read = reader.join().unwrap();
+            assert_eq!(read.len(), 1024 * 1024 + 60);
+            assert!(read.ends_with(&[b'1'; 60]));
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn drop_oldest_keeps_what_was_queued_last() {
+        let dir = test_dir("drop-oldest");
+        let pool = pool();
+        thread::scope(|scope| {
+            let (queued, go, reader) = stalled(scope, &pool, &dir, "drop-oldest");
+            queued.write(&[b'1'; 60]);
+            queued.write(&[b'2'; 60]);
+            queued.set_pid(None);
+            queued.write(&[b'3'; 30]);
+            assert_eq!(queued.status().queued_bytes, 90);
+            assert_eq!(queued.status().dropped_bytes, 60);
+
+            go.send(()).unwrap();
+            drop(queued);
+            let read = reader.join().unwrap();
+            assert_eq!(read.len(), 1024 * 1024 + 90);
+            assert!(read.ends_with(&[[b'2'; 60].as_slice(), &[b'3'; 30]].concat()));
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn block_waits_for_room() {
+        let dir = test_dir("block");
+        let pool = pool();
+        thread::scope(|scope| {
+            let (queued, go, reader) = stalled(scope, &pool, &dir, "block");
+            queued.write(&[b'1'; 60]);
+            thread::scope(|writer| {
+                let blocked = writer.spawn(|| queued.write(&[b'2'; 60]));
+                thread::sleep(Duration::from_millis(100));
+                assert!(!blocked.is_finished());
+                go.send(()).unwrap();
+            });
+            assert_eq!(queued.status().dropped_bytes, 0);
+            drop(queued);
+            let read = reader.join().unwrap();
+            assert_eq!(read.len(), 1024 * 1024 + 120);
+            assert!(read.ends_with(&[[b'1'; 60], [b'2'; 60]].concat()));
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn output_larger_than_the_queue_gets_in_alone() {
+        let dir = test_dir("oversized");
+        let pool = pool();
+        thread::scope(|scope| {
+            let (queued, go, reader) = stalled(scope, &pool, &dir, "drop-newest");
+            queued.write(&[b'1'; 200]);
+            assert_eq!(queued.status().queued_bytes, 200);
+            queued.write(&[b'2'; 10]);
+            assert_eq!(queued.status().dropped_bytes, 10);
+
+            go.send(()).unwrap();
+            drop(queued);
+            assert!(reader.join().unwrap().ends_with(&[b'1'; 200]));
+        });
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    /// A consumer logging to `/dev/full`, through a link that can be pointed
+    /// at a real file later on.
+    fn failing(pool: &Pool, dir: &Path, on_error: &str) -> Queued {
+        symlink("/dev/full", dir.join("app.log")).unwrap();
+        logger(
+            pool,
+            &dir.join("app.log"),
+            &format!("on_error = \"{}\"", on_error),
+        )
+    }
+
+    #[test]
+    fn retry_loses_output_until_the_consumer_recovers() {
+        let dir = test_dir("retry");
+        let pool = pool();
+        let queued = failing(&pool, &dir, "retry");
+        queued.write(b"one\n");
+        queued.write(b"two\n");
+        queued.drain(Instant::now() + TIMEOUT);
+        let status = queued.status();
+        assert_eq!(status.state, "failing");
+        assert_eq!(status.failures, 2);
+        assert_eq!(status.dropped_bytes, 8);
+        assert!(status.last_error.is_some());
+
+        fs::remove_file(dir.join("app.log")).unwrap();
+        queued.reopen();
+        queued.write(b"six\n");
+        queued.drain(Instant::now() + TIMEOUT);
+        let status = queued.status();
+        assert_eq!(status.state, "ok");
+        assert_eq!(status.failures, 2);
+        assert_eq!(fs::read_to_string(dir.join("app.log")).unwrap(), "six\n");
+        assert!(pool.take_fatal().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn disable_stops_handing_the_consumer_anything() {
+        let dir = test_dir("disable");
+        let pool = pool();
+        let queued = failing(&pool, &dir, "disable");
+        queued.write(b"one\n");
+        queued.drain(Instant::now() + TIMEOUT);
+        assert_eq!(queued.status().state, "disabled");
+
+        // writing would work again, but nobody tries
+        fs::remove_file(dir.join("app.log")).unwrap();
+        queued.reopen();
+        queued.write(b"two\n");
+        queued.drain(Instant::now() + TIMEOUT);
+        let status = queued.status();
+        assert_eq!(status.state, "disabled");
+        assert_eq!(status.failures, 1);
+        assert_eq!(status.dropped_bytes, 8);
+        assert!(!dir.join("app.log").exists());
+        assert!(pool.take_fatal().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn fatal_hands_the_error_over() {
+        let dir = test_dir("fatal");
+        let pool = pool();
+        let queued = failing(&pool, &dir, "fatal");
+        queued.write(b"one\n");
+        queued.write(b"two\n");
+        queued.drain(Instant::now() + TIMEOUT);
+        assert_eq!(queued.status().failures, 2);
+        let fatal = pool.take_fatal().unwrap();
+        assert!(fatal
+            .to_string()
+            .starts_with("log consumer of app stdout: "));
+        // only the first failure is handed over
+        assert!(pool.take_fatal().is_none());
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,97 +1,824 @@
 //! Register services and manage them through instance lifetimes.
 //!
 //! This structure provides a mechanism for bringing up services but more
-//! importantly for owning services. Services are brought online and teared down
-//! through lifetime semantics, so by simply dropping ownership of the service
-//! struct, you initiate the kill sequence. This model treats services as
-//! resources that get cleaned up through scope, which is extremely handy.
-use crate::{conf::ServiceConf, service::Service};
+//! importantly for owning services. Every configured service stays in the
+//! registry for the whole run, whether its process is alive, waiting to be
+//! restarted, or gone for good, so the registry is also the place to ask about
+//! the state of the container as a whole.
+//!
+//! Hook jobs are registered as services of their own which simply never start
+//! on their own. This way their output gets a bus like any other service and
+//! reaping them needs no special treatment.
+//!
+//! Services are started in dependency order, each one as soon as everything it
+//! depends on is up, and stopped in the reverse order on shutdown. Every
+//! service is asked to stop with its own stop signal, and killed if it doesn't
+//! within its stop timeout.
+//!
+//! Whether the death of a service takes the container down with it is decided
+//! by its priority group if it has one, or by `must_be_up` otherwise.
+//!
+//! On reload the registry is brought in line with the new config. Services
+//! dropped from it are retired: they're stopped and only let go of once their
+//! process is gone, since until then it still has to be reaped.
+use crate::{
+    conf::{Config, GroupConf, GroupPolicy, RestartPolicy, ServiceConf, ServiceType},
+    control::ControlError,
+    deps::{start_order, DependencyError},
+    health::{Probe, ProbeReports},
+    notify::Notify,
+    service::{ExitStatus, Service, State},
+};
 use nix::{
-    sys::wait::{waitpid, WaitPidFlag, WaitStatus},
+    sys::{
+        signal::{kill, Signal},
+        wait::{waitpid, WaitPidFlag, WaitStatus},
+    },
     unistd::Pid,
 };
-use std::process::exit;
+use std::ffi::CString;
+use std::fmt;
+use std::io;
+use std::os::fd::{AsRawFd, RawFd};
+use std::path::Path;
+use std::time::Duration;
+
+pub const EXIT_HOOK: &str = "on_exit";
+
+#[derive(Debug)]
+pub enum RegistryError {
+    Dependency(DependencyError),
+    UnknownGroup { service: String, group: String },
+    Io(io::Error),
+}
+
+impl fmt::Display for RegistryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Dependency(e) => e.fmt(f),
+            Self::Io(e) => e.fmt(f),
+            Self::UnknownGroup { service, group } => write!(
+                f,
+                "service {} is in group {} which isn't defined",
+                service, group
+            ),
+        }
+    }
+}
+
+impl From<DependencyError> for RegistryError {
+    fn from(e: DependencyError) -> Self {
+        Self::Dependency(e)
+    }
+}
+
+impl From<io::Error> for RegistryError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct Reaped {
+    pub name: String,
+    pub pid: Pid,
+    pub status: ExitStatus,
+    pub restart_in: Option<Duration>,
+    pub stop_requested: bool,
+}
 
 pub struct Registry {
     pub services: Vec<Service>,
+    /// Set once a critical service failed for good, kinesin exits with it.
+    pub exit_code: Option<i32>,
+    pub hook_timeout: Duration,
+    /// Stop timeout of the services which don't define their own.
+    pub stop_timeout: Duration,
+    pub shutdown_grace: Duration,
+    pub shutting_down: bool,
+    /// Where probes running on threads report back to.
+    pub probes: ProbeReports,
+    last_death: Option<Reaped>,
+    order: Vec<usize>,
+    groups: Vec<GroupConf>,
+    /// Services asked to stop whose stop timeout isn't armed yet.
+    stopped: Vec<String>,
+    /// Notification sockets of retired services, waiting for the watcher to
+    /// be told to let go of them.
+    retired_notify: Vec<Notify>,
+}
+
+enum Readiness {
+    Ready,
+    Waiting,
+    Failed(String),
+}
+
+fn hook_name(service: &str, trigger: &str) -> String {
+    format!("{}.{}", service, trigger)
+}
+
+/// Whether `name` is how hooks are named, which services share a namespace
+/// with and so can't be named.
+pub fn is_hook_name(name: &str) -> bool {
+    name == EXIT_HOOK || name.ends_with(".on_failure") || name.ends_with(".on_success")
+}
+
+/// Definitions of every hook job the config asks for. Hooks are registered up
+/// front, but only started once triggered.
+pub fn hook_defs(config: &Config) -> Vec<ServiceConf> {
+    let mut hooks = Vec::new();
+    for def in &config.service {
+        let triggers = [
+            ("on_failure", &def.on_failure),
+            ("on_success", &def.on_success),
+        ];
+        for (trigger, exec) in triggers {
+            if let Some(exec) = exec {
+                let name = hook_name(&def.name, trigger);
+                hooks.push(ServiceConf::hook(name, exec, &def.env));
+            }
+        }
+    }
+    if let Some(exec) = &config.on_exit {
+        hooks.push(ServiceConf::hook(EXIT_HOOK.to_string(), exec, &[]));
+    }
+    hooks
+}
+
+fn check_groups(config: &Config) -> Result<(), RegistryError> {
+    for def in &config.service {
+        if let Some(group) = &def.group {
+            if !config.group.iter().any(|g| g.name == *group) {
+                return Err(RegistryError::UnknownGroup {
+                    service: def.name.clone(),
+                    group: group.clone(),
+                });
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Indices of the services to start in dependency order, leaving out hooks
+/// and retired services.
+fn start_order_of(services: &[Service]) -> Result<Vec<usize>, DependencyError> {
+    let managed = services
+        .iter()
+        .enumerate()
+        .filter(|(_, srvc)| !srvc.hook && !srvc.retired)
+        .map(|(idx, _)| idx)
+        .collect::<Vec<_>>();
+    let defs = managed
+        .iter()
+        .map(|&idx| services[idx].def.clone())
+        .collect::<Vec<_>>();
+    Ok(start_order(&defs)?
+        .into_iter()
+        .map(|pos| managed[pos])
+        .collect())
 }
 
 impl Registry {
-    pub fn new(services: &[ServiceConf]) -> Self {
-        let num_services = services.len();
-        let mut services_ = Vec::with_capacity(num_services);
-        for def in services {
-            match Service::new(def) {
-                Ok(srvc) => {
-                    services_.push(srvc);
+    /// Register every service, without starting any of them yet.
+    pub fn new(config: &Config) -> Result<Self, RegistryError> {
+        check_groups(config)?;
+
+        let mut services = config.service.iter().map(Service::new).collect::<Vec<_>>();
+        services.extend(hook_defs(config).iter().map(Service::new_hook));
+        let order = start_order_of(&services)?;
+
+        Ok(Self {
+            services,
+            exit_code: None,
+            hook_timeout: Duration::from_millis(config.hook_timeout_ms),
+            stop_timeout: Duration::from_millis(config.stop_timeout_ms),
+            shutdown_grace: Duration::from_millis(config.shutdown_grace_ms),
+            shutting_down: false,
+            probes: ProbeReports::new()?,
+            last_death: None,
+            order,
+            groups: config.group.clone(),
+            stopped: Vec::new(),
+            retired_notify: Vec::new(),
+        })
+    }
+
+    /// Bring the registry in line with a new config. Services dropped from it
+    /// are stopped for good, new ones start as soon as their dependencies
+    /// allow, and services whose definition changed are restarted with the new
+    /// one. Hooks simply pick up their new definition the next time they run.
+    pub fn apply(&mut self, config: &Config) -> Result<(), RegistryError> {
+        check_groups(config)?;
+        start_order(&config.service)?;
+
+        let hooks = hook_defs(config);
+        let wanted = config
+            .service
+            .iter()
+            .map(|def| (def, false))
+            .chain(hooks.iter().map(|def| (def, true)))
+            .collect::<Vec<_>>();
+
+        for srvc in &mut self.services {
+            let kept = wanted
+                .iter()
+                .any(|(def, hook)| def.name == srvc.name && *hook == srvc.hook);
+            if kept || srvc.retired {
+                continue;
+            }
+            eprintln!("Removing service {}", srvc.name);
+            srvc.retired = true;
+            if srvc.state == State::Running && !srvc.hook {
+                srvc.requeue = false;
+                match srvc.stop() {
+                    Ok(()) => self.stopped.push(srvc.name.clone()),
+                    Err(e) => eprintln!("Failed to stop {}: {}", srvc.name, e),
                 }
-                Err(e) => {
-                    panic!("{:?}", e);
+            }
+        }
+
+        for (def, hook) in wanted {
+            let Some(srvc) = self
+                .services
+                .iter_mut()
+                .find(|srvc| srvc.name == def.name && srvc.hook == hook)
+            else {
+                eprintln!("Adding service {}", def.name);
+                let srvc = match hook {
+                    true => Service::new_hook(def),
+                    false => Service::new(def),
+                };
+                self.services.push(srvc);
+                continue;
+            };
+            if srvc.def == *def && !srvc.retired {
+                continue;
+            }
+
+            // a retired service which is still going down gets brought back
+            srvc.retired = false;
+            if !srvc.hook {
+                eprintln!("Restarting service {} with its new config", srvc.name);
+                match srvc.state {
+                    State::Running if srvc.stopping => srvc.requeue = true,
+                    State::Running => {
+                        match srvc.stop() {
+                            Ok(()) => self.stopped.push(srvc.name.clone()),
+                            Err(e) => eprintln!("Failed to stop {}: {}", srvc.name, e),
+                        }
+                        srvc.requeue = true;
+                    }
+                    _ => srvc.state = State::Inactive,
+                }
+            }
+            srvc.def = def.clone();
+            srvc.must_be_up = def.must_be_up;
+            if def.kind != ServiceType::Notify {
+                if let Some(notify) = srvc.notify.take() {
+                    self.retired_notify.push(notify);
                 }
             }
         }
 
-        Self {
-            services: services_,
+        self.hook_timeout = Duration::from_millis(config.hook_timeout_ms);
+        self.stop_timeout = Duration::from_millis(config.stop_timeout_ms);
+        self.shutdown_grace = Duration::from_millis(config.shutdown_grace_ms);
+        self.groups = config.group.clone();
+        self.prune();
+        Ok(())
+    }
+
+    /// Let go of the retired services whose process is gone.
+    fn prune(&mut self) {
+        let (gone, kept) = std::mem::take(&mut self.services)
+            .into_iter()
+            .partition::<Vec<_>, _>(|srvc| srvc.retired && srvc.state != State::Running);
+        self.services = kept;
+        for mut srvc in gone {
+            if let Some(notify) = srvc.notify.take() {
+                self.retired_notify.push(notify);
+            }
         }
+        self.order = start_order_of(&self.services)
+            .expect("the dependencies of the remaining services were checked already");
     }
 
-    pub fn reap_children(&mut self) -> Vec<Service> {
-        let mut reaped_children = Vec::new();
-        loop {
-            match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
-                Ok(WaitStatus::Exited(pid, status)) => {
-                    if let Some(srvc) = self.remove(pid) {
-                        if status != 0 && srvc.must_be_up {
-                            eprintln!("Critical Service Failed. Must Terminate...");
-                            exit(status);
-                        }
-                        reaped_children.push(srvc);
+    /// Hand over the notification sockets of retired services, which are
+    /// closed once dropped.
+    pub fn take_retired_notify(&mut self) -> Vec<Notify> {
+        std::mem::take(&mut self.retired_notify)
+    }
+
+    /// Bind the notification sockets of every `notify` service, returning
+    /// the sockets which weren't bound yet.
+    pub fn open_notify_sockets(&mut self, dir: &Path) -> io::Result<Vec<RawFd>> {
+        let mut opened = Vec::new();
+        for srvc in &mut self.services {
+            opened.extend(srvc.open_notify(dir)?);
+        }
+        Ok(opened)
+    }
+
+    fn readiness(&self, srvc: &Service) -> Readiness {
+        for name in srvc.def.dependencies() {
+            let Some(dep) = self.get_by_name(name) else {
+                continue;
+            };
+            match dep.state {
+                State::Running if !dep.is_started() => return Readiness::Waiting,
+                State::Running if srvc.def.wait_ready && !dep.is_ready() => {
+                    return Readiness::Waiting
+                }
+                State::Running => (),
+                State::Inactive | State::Restarting => return Readiness::Waiting,
+                State::Exited => {
+                    let failed = dep.last_exit.is_none_or(|status| !status.success());
+                    if failed && srvc.def.requires.contains(name) {
+                        return Readiness::Failed(name.clone());
                     }
                 }
-                Ok(WaitStatus::Signaled(pid, _, _)) => {
-                    if let Some(srvc) = self.remove(pid) {
-                        if srvc.must_be_up {
-                            eprintln!("Critical Service Failed. Must Terminate...");
-                            exit(-1);
-                        }
-                        reaped_children.push(srvc);
+            }
+        }
+        Readiness::Ready
+    }
+
+    /// Start every service whose dependencies are satisfied, returning the
+    /// indices of the services which were started along with the deaths of
+    /// those which couldn't be.
+    pub fn start_pending(&mut self) -> (Vec<usize>, Vec<Reaped>) {
+        let mut started = Vec::new();
+        let mut failed = Vec::new();
+        if self.shutting_down {
+            return (started, failed);
+        }
+        for pos in 0..self.order.len() {
+            let idx = self.order[pos];
+            if self.services[idx].state != State::Inactive {
+                continue;
+            }
+            match self.readiness(&self.services[idx]) {
+                Readiness::Waiting => (),
+                Readiness::Failed(dep) => {
+                    let srvc = &mut self.services[idx];
+                    eprintln!("Service {} won't start, {} isn't up", srvc.name, dep);
+                    srvc.state = State::Exited;
+                }
+                Readiness::Ready => match self.services[idx].start() {
+                    Ok(()) => started.push(idx),
+                    Err(e) => {
+                        let name = self.services[idx].name.clone();
+                        failed.extend(self.start_failed(&name, &e));
+                    }
+                },
+            }
+        }
+        (started, failed)
+    }
+
+    /// Bring every service down, dependents first. Services are only told to
+    /// stop once nothing that depends on them is running anymore.
+    pub fn begin_shutdown(&mut self) {
+        self.shutting_down = true;
+        for srvc in &mut self.services {
+            if srvc.hook {
+                continue;
+            }
+            srvc.stop_requested = true;
+            if matches!(srvc.state, State::Inactive | State::Restarting) {
+                srvc.state = State::Exited;
+            }
+        }
+    }
+
+    /// How long a service gets between its stop signal and SIGKILL.
+    pub fn stop_timeout(&self, srvc: &Service) -> Duration {
+        srvc.def
+            .stop_timeout_ms
+            .map_or(self.stop_timeout, Duration::from_millis)
+    }
+
+    /// During shutdown, send the stop signal to the services whose dependents
+    /// are all gone. Returns every service asked to stop since the last call,
+    /// so that their stop timeouts can be armed.
+    pub fn stop_pending(&mut self) -> Vec<String> {
+        if !self.shutting_down {
+            return std::mem::take(&mut self.stopped);
+        }
+        for pos in (0..self.order.len()).rev() {
+            let idx = self.order[pos];
+            let srvc = &self.services[idx];
+            if srvc.state != State::Running || srvc.stopping {
+                continue;
+            }
+            let dependents_running = self.services.iter().any(|other| {
+                other.state == State::Running && other.def.dependencies().any(|d| *d == srvc.name)
+            });
+            if dependents_running {
+                continue;
+            }
+            let srvc = &mut self.services[idx];
+            // a process already gone is reaped soon enough, and still counts
+            // as stopping until then
+            if let Err(e) = srvc.stop() {
+                eprintln!("Failed to stop {}: {}", srvc.name, e);
+            }
+            self.stopped.push(srvc.name.clone());
+        }
+        std::mem::take(&mut self.stopped)
+    }
+
+    /// Stop whatever requires a service that just went down. Dependents which
+    /// would restart on their own wait for it to come back instead.
+    fn stop_dependents(&mut self, name: &str, coming_back: bool) {
+        for srvc in &mut self.services {
+            if !srvc.def.requires.iter().any(|dep| dep == name) {
+                continue;
+            }
+            let requeue = coming_back && srvc.def.restart.policy != RestartPolicy::Never;
+            match srvc.state {
+                State::Running => {
+                    eprintln!("Stopping {} since {} went down", srvc.name, name);
+                    match srvc.stop() {
+                        Ok(()) => self.stopped.push(srvc.name.clone()),
+                        Err(e) => eprintln!("Failed to stop {}: {}", srvc.name, e),
                     }
+                    srvc.requeue = requeue;
                 }
+                State::Restarting if requeue => srvc.state = State::Inactive,
+                State::Restarting => srvc.state = State::Exited,
+                _ => (),
+            }
+        }
+    }
+
+    /// Whether any service or hook is still alive or about to be brought back up.
+    pub fn is_active(&self) -> bool {
+        self.services
+            .iter()
+            .any(|srvc| matches!(srvc.state, State::Running | State::Restarting))
+    }
+
+    pub fn reap_children(&mut self) -> Vec<Reaped> {
+        let mut reaped_children = Vec::new();
+        loop {
+            let (pid, status) = match waitpid(None, Some(WaitPidFlag::WNOHANG)) {
+                Ok(WaitStatus::Exited(pid, status)) => (pid, ExitStatus::Exited(status)),
+                Ok(WaitStatus::Signaled(pid, sig, _)) => (pid, ExitStatus::Signaled(sig)),
                 Ok(WaitStatus::StillAlive) => break,
                 Err(nix::errno::Errno::ECHILD) => break, // No more children
                 Err(e) => {
                     eprintln!("Error in waitpid: {:?}", e);
                     break;
                 }
-                _ => {}
+                _ => continue,
+            };
+            if let Some(idx) = self.services.iter().position(|srvc| srvc.pid == Some(pid)) {
+                reaped_children.push(self.record_death(idx, pid, status));
+            } else if let Some(srvc) = self.get_by_probe_mut(Probe::Child(pid)) {
+                srvc.probe_finished(status.success());
             }
         }
+        if self.services.iter().any(|srvc| srvc.retired) {
+            self.prune();
+        }
         reaped_children
     }
 
+    /// Take note of the death of the process of a service, deciding whether
+    /// it comes back and whether the container goes down with it.
+    fn record_death(&mut self, idx: usize, pid: Pid, status: ExitStatus) -> Reaped {
+        let srvc = &mut self.services[idx];
+        let restarts = srvc.restarts;
+        let restart_in = srvc.exited(status);
+        let reaped = Reaped {
+            name: srvc.name.clone(),
+            pid,
+            status,
+            restart_in,
+            stop_requested: srvc.stop_requested,
+        };
+        let group = srvc.def.group.clone();
+        let critical = group.is_none()
+            && restart_in.is_none()
+            && srvc.must_be_up
+            && !srvc.stop_requested
+            && !status.success();
+        let hook = srvc.hook;
+        let coming_back = srvc.state != State::Exited;
+        // the global hook wants to hear about whoever brought us down,
+        // not about the services we stopped ourselves afterwards
+        if !hook && (!reaped.stop_requested || self.last_death.is_none()) {
+            self.last_death = Some(reaped.clone());
+        }
+        if critical {
+            eprintln!("Critical Service Failed. Must Terminate...");
+            self.exit_code.get_or_insert(status.code());
+        }
+        if let Some(group) = group {
+            if self.group_lost(&group, &reaped, restarts) {
+                self.exit_code.get_or_insert(status.code());
+            }
+        }
+        if !hook {
+            self.stop_dependents(&reaped.name, coming_back);
+        }
+        reaped
+    }
+
+    /// Count a service that couldn't even be started as dead, with the status
+    /// of a command that couldn't be run, so it's restarted or brings the
+    /// container down like any other death would.
+    pub fn start_failed(&mut self, name: &str, e: &io::Error) -> Option<Reaped> {
+        let idx = self.services.iter().position(|srvc| srvc.name == name)?;
+        eprintln!("Failed to start service {}: {}", name, e);
+        Some(self.record_death(idx, Pid::from_raw(0), ExitStatus::Exited(127)))
+    }
+
+    /// Whether the death of a member means its group, and so the container, is
+    /// lost. `restarts` is how often the member was restarted before it died.
+    fn group_lost(&self, name: &str, reaped: &Reaped, restarts: u32) -> bool {
+        let Some(group) = self.groups.iter().find(|g| g.name == name) else {
+            return false;
+        };
+        if reaped.stop_requested {
+            return false;
+        }
+        let failed = !reaped.status.success();
+        let gone = reaped.restart_in.is_none();
+        let lost = match group.policy {
+            GroupPolicy::AnyFailed => failed && gone,
+            GroupPolicy::AllExited => {
+                gone && self.services.iter().all(|srvc| {
+                    srvc.def.group.as_deref() != Some(name) || srvc.state == State::Exited
+                })
+            }
+            GroupPolicy::MaxRestarts => failed && restarts >= group.max_restarts,
+        };
+        if lost {
+            eprintln!(
+                "Lost group {} with {}. Must Terminate...",
+                name, reaped.name
+            );
+        }
+        lost
+    }
+
+    /// Start the `on_failure` or `on_success` hook of a service that just died,
+    /// if it has one. Services kinesin stopped itself don't trigger hooks.
+    pub fn start_hook(&mut self, reaped: &Reaped) -> io::Result<Option<&Service>> {
+        if reaped.stop_requested {
+            return Ok(None);
+        }
+        let trigger = if reaped.status.success() {
+            "on_success"
+        } else {
+            "on_failure"
+        };
+        let name = hook_name(&reaped.name, trigger);
+        self.start_hook_named(&name, reaped)
+    }
+
+    /// Start the global hook once everything else is gone. It's told about the
+    /// last service that died on its own.
+    pub fn start_exit_hook(&mut self) -> io::Result<Option<&Service>> {
+        match (&self.last_death, self.get_by_name(EXIT_HOOK)) {
+            (Some(reaped), Some(hook)) if hook.state == State::Inactive => {
+                let reaped = reaped.clone();
+                self.start_hook_named(EXIT_HOOK, &reaped)
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn start_hook_named(&mut self, name: &str, reaped: &Reaped) -> io::Result<Option<&Service>> {
+        let Some(hook) = self.get_by_name_mut(name) else {
+            return Ok(None);
+        };
+        if hook.state == State::Running {
+            eprintln!("Hook {} is still running, skipping it", name);
+            return Ok(None);
+        }
+
+        let mut env = vec![
+            format!("KINESIN_SERVICE={}", reaped.name),
+            format!("KINESIN_PID={}", reaped.pid),
+        ];
+        match reaped.status {
+            ExitStatus::Exited(code) => env.push(format!("KINESIN_EXIT_CODE={}", code)),
+            ExitStatus::Signaled(sig) => env.push(format!("KINESIN_SIGNAL={}", sig)),
+        }
+        let env = env
+            .into_iter()
+            .map(|var| CString::new(var).unwrap())
+            .collect::<Vec<_>>();
+
+        hook.start_with(&env)?;
+        Ok(Some(hook))
+    }
+
+    /// Look up a service which may be started and stopped on request.
+    fn controllable(&self, name: &str) -> Result<usize, ControlError> {
+        if self.shutting_down {
+            return Err(ControlError::ShuttingDown);
+        }
+        let idx = self
+            .services
+            .iter()
+            .position(|srvc| srvc.name == name)
+            .ok_or_else(|| ControlError::UnknownService(name.to_string()))?;
+        if self.services[idx].hook {
+            return Err(ControlError::Hook(name.to_string()));
+        }
+        Ok(idx)
+    }
+
+    /// Bring a service back up as soon as its dependencies allow it.
+    pub fn start_service(&mut self, name: &str) -> Result<(), ControlError> {
+        let idx = self.controllable(name)?;
+        let srvc = &mut self.services[idx];
+        if srvc.state == State::Running {
+            return Err(ControlError::AlreadyRunning(name.to_string()));
+        }
+        srvc.state = State::Inactive;
+        Ok(())
+    }
+
+    /// Stop a service for good, without restarting it.
+    pub fn stop_service(&mut self, name: &str) -> Result<(), ControlError> {
+        let idx = self.controllable(name)?;
+        let srvc = &mut self.services[idx];
+        match srvc.state {
+            State::Running => {
+                srvc.requeue = false;
+                srvc.stop()?;
+                self.stopped.push(srvc.name.clone());
+            }
+            State::Inactive | State::Restarting => {
+                srvc.stop_requested = true;
+                srvc.state = State::Exited;
+            }
+            State::Exited => return Err(ControlError::NotRunning(name.to_string())),
+        }
+        Ok(())
+    }
+
+    /// Stop a service and start it again once it's gone.
+    pub fn restart_service(&mut self, name: &str) -> Result<(), ControlError> {
+        let idx = self.controllable(name)?;
+        let srvc = &mut self.services[idx];
+        if srvc.state == State::Running {
+            srvc.stop()?;
+            srvc.requeue = true;
+            self.stopped.push(srvc.name.clone());
+        } else {
+            srvc.state = State::Inactive;
+        }
+        Ok(())
+    }
+
+    /// Send an arbitrary signal to the process of a service or hook.
+    pub fn signal_service(&mut self, name: &str, sig: Signal) -> Result<(), ControlError> {
+        let srvc = self
+            .get_by_name(name)
+            .ok_or_else(|| ControlError::UnknownService(name.to_string()))?;
+        match (srvc.state, srvc.pid) {
+            (State::Running, Some(pid)) => Ok(kill(pid, sig)?),
+            _ => Err(ControlError::NotRunning(name.to_string())),
+        }
+    }
+
+    /// Kill every service at once, leaving hooks to run to completion.
+    pub fn kill_all(&mut self) {
+        for srvc in &mut self.services {
+            if srvc.hook {
+                continue;
+            }
+            srvc.stop_requested = true;
+            match srvc.state {
+                State::Running => {
+                    if let Some(pid) = srvc.pid {
+                        eprintln!("Killing service {}", srvc.name);
+                        if let Err(e) = kill(pid, Signal::SIGKILL) {
+                            eprintln!("Failed to kill {}: {}", srvc.name, e);
+                        }
+                    }
+                }
+                State::Inactive | State::Restarting => srvc.state = State::Exited,
+                State::Exited => (),
+            }
+        }
+    }
+
     pub fn get_by_name(&self, name: &str) -> Option<&Service> {
         self.services.iter().find(|&srvc| srvc.name == name)
     }
 
-    // pub fn get_by_fd(&self, fd: RawFd) -> Option<&Service> {
-    //     self.services.iter().find(|&srvc| {
-    //         srvc.stdout.map(|x| fd == x).unwrap_or(false)
-    //             || srvc.stderr.map(|x| fd == x).unwrap_or(false)
-    //     })
-    // }
-    // pub fn get_by_fd_mut(&mut self, fd: RawFd) -> Option<&mut Service> {
-    //     self.services.iter_mut().find(|srvc| {
-    //         srvc.stdout.map(|x| fd == x).unwrap_or(false)
-    //             || srvc.stderr.map(|x| fd == x).unwrap_or(false)
-    //     })
-    // }
-
-    pub fn remove(&mut self, pid: Pid) -> Option<Service> {
-        if let Some(loc) = self.services.iter().position(|srvc| srvc.pid == pid) {
-            Some(self.services.swap_remove(loc))
-        } else {
-            None
+    pub fn get_by_name_mut(&mut self, name: &str) -> Option<&mut Service> {
+        self.services.iter_mut().find(|srvc| srvc.name == name)
+    }
+
+    /// The service named `name` along with what its health probes report to.
+    pub fn get_for_probe_mut(&mut self, name: &str) -> Option<(&mut Service, &mut ProbeReports)> {
+        let probes = &mut self.probes;
+        self.services
+            .iter_mut()
+            .find(|srvc| srvc.name == name)
+            .map(|srvc| (srvc, probes))
+    }
+
+    pub fn get_by_pid_mut(&mut self, pid: Pid) -> Option<&mut Service> {
+        self.services.iter_mut().find(|srvc| srvc.pid == Some(pid))
+    }
+
+    pub fn get_by_notify_fd_mut(&mut self, fd: RawFd) -> Option<&mut Service> {
+        self.services.iter_mut().find(|srvc| {
+            srvc.notify
+                .as_ref()
+                .is_some_and(|notify| notify.as_raw_fd() == fd)
+        })
+    }
+
+    /// Find the service whose health probe is `probe`.
+    pub fn get_by_probe_mut(&mut self, probe: Probe) -> Option<&mut Service> {
+        self.services.iter_mut().find(|srvc| {
+            srvc.health
+                .as_ref()
+                .is_some_and(|health| health.probe == Some(probe))
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A registry of services `web` and `worker` in group `app` under
+    /// `policy`, along with `cron` in no group at all.
+    fn registry(policy: &str) -> Registry {
+        let toml = format!(
+            "[[group]]\nname = \"app\"\npolicy = {:?}\nmax_restarts = 2\n\
+             [[service]]\nname = \"web\"\nexec = [\"/bin/true\"]\ngroup = \"app\"\n\
+             [[service]]\nname = \"worker\"\nexec = [\"/bin/true\"]\ngroup = \"app\"\n\
+             [[service]]\nname = \"cron\"\nexec = [\"/bin/true\"]\n",
+            policy
+        );
+        Registry::new(&toml::from_str::<Config>(&toml).unwrap()).unwrap()
+    }
+
+    fn reaped(name: &str, code: i32, restart_in: Option<u64>) -> Reaped {
+        Reaped {
+            name: name.to_string(),
+            pid: Pid::from_raw(1000),
+            status: ExitStatus::Exited(code),
+            restart_in: restart_in.map(Duration::from_millis),
+            stop_requested: false,
         }
     }
+
+    fn set_state(registry: &mut Registry, name: &str, state: State) {
+        let srvc = registry.services.iter_mut().find(|s| s.name == name);
+        srvc.unwrap().state = state;
+    }
+
+    #[test]
+    fn any_failed_loses_the_group_on_a_failure_for_good() {
+        let registry = registry("any-failed");
+        assert!(registry.group_lost("app", &reaped("web", 1, None), 0));
+        assert!(!registry.group_lost("app", &reaped("web", 1, Some(100)), 0));
+        assert!(!registry.group_lost("app", &reaped("web", 0, None), 0));
+    }
+
+    #[test]
+    fn all_exited_waits_for_every_member() {
+        let mut registry = registry("all-exited");
+        set_state(&mut registry, "web", State::Exited);
+        set_state(&mut registry, "worker", State::Running);
+        assert!(!registry.group_lost("app", &reaped("web", 1, None), 0));
+
+        // services outside the group don't count
+        set_state(&mut registry, "worker", State::Exited);
+        set_state(&mut registry, "cron", State::Running);
+        assert!(registry.group_lost("app", &reaped("worker", 0, None), 0));
+        assert!(!registry.group_lost("app", &reaped("worker", 0, Some(100)), 0));
+    }
+
+    #[test]
+    fn max_restarts_loses_the_group_once_restarts_run_out() {
+        let registry = registry("max-restarts");
+        assert!(!registry.group_lost("app", &reaped("web", 1, Some(100)), 1));
+        assert!(registry.group_lost("app", &reaped("web", 1, Some(100)), 2));
+        assert!(!registry.group_lost("app", &reaped("web", 0, None), 5));
+    }
+
+    #[test]
+    fn services_stopped_on_purpose_never_lose_the_group() {
+        let registry = registry("any-failed");
+        let stopped = Reaped {
+            stop_requested: true,
+            ..reaped("web", 1, None)
+        };
+        assert!(!registry.group_lost("app", &stopped, 0));
+        assert!(!registry.group_lost("other", &reaped("cron", 1, None), 0));
+    }
 }
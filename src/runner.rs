@@ -2,7 +2,7 @@
 //! Another benifit of this data structure is that it scopes a bounded generic
 //! type, ensuring that the code using the AIO watcher backend is not accidentally
 //! tied to a specific implementation.
-use std::{collections::HashMap, io, os::fd::RawFd};
+use std::{io, os::fd::AsRawFd, time::Duration};
 
 use nix::{
     sys::signal::{kill, Signal},
@@ -10,86 +10,667 @@ use nix::{
 };
 
 use crate::{
-    bus::Bus,
-    registry::Registry,
+    bus::{Bus, BusMap},
+    check::load_checked,
+    conf::{BusFraming, Config, ConfigSource, ConsumerConf, ProducerConf, ServiceConf, SourceConf},
+    control::{ControlServer, CONTROL_BUFSIZE},
+    health::Probe,
+    registry::{hook_defs, Reaped, Registry},
+    service::{Service, State},
+    timer::{Timer, Timers},
     watcher::{AsWatcher, Event},
 };
 
-pub fn handle_event(
-    event: Event,
+/// The config kinesin currently runs with, and where to read it again from.
+pub struct LoadedConfig {
+    pub source: ConfigSource,
+    pub config: Config,
+}
+
+/// Every stream of the services and hooks `config` defines.
+fn streams(config: &Config) -> Vec<(ProducerConf, SourceConf)> {
+    let hooks = hook_defs(config);
+    let mut streams = Vec::new();
+    for def in config.service.iter().chain(&hooks) {
+        streams.push((ProducerConf::StdOut(def.name.clone()), def.stdout.clone()));
+        streams.push((ProducerConf::StdErr(def.name.clone()), def.stderr.clone()));
+    }
+    streams
+}
+
+/// Every consumer of the config, including those feeding services' stdin.
+fn consumers(config: &Config) -> Vec<ConsumerConf> {
+    let stdin = config
+        .service
+        .iter()
+        .filter_map(ServiceConf::stdin_consumer);
+    config.consumer.iter().cloned().chain(stdin).collect()
+}
+
+/// How wide service names are padded to in prefixes, so every service's lines
+/// line up with the others'.
+fn name_width(config: &Config) -> usize {
+    streams(config)
+        .iter()
+        .map(|(producer, _)| producer.service_name().len())
+        .max()
+        .unwrap_or(0)
+}
+
+/// Make sure every watched stream has a bus, and hand each bus the consumers
+/// `new` gives it. Buses whose consumers are the same as in `old` keep them,
+/// and buses keep whatever they have buffered either way.
+pub fn wire_buses(buses: &mut BusMap, old: Option<&Config>, new: &Config) -> io::Result<()> {
+    // open every new consumer before touching any bus, so that a consumer
+    // failing to open leaves everything as it was
+    buses.set_threads(new.consumer_threads)?;
+    let width = name_width(new);
+    let new_consumers = consumers(new);
+    let old_consumers = old.map(|old| (consumers(old), name_width(old)));
+    let mut wired = Vec::new();
+    for (producer, conf) in streams(new) {
+        if !conf.watch {
+            continue;
+        }
+        let consumers_of = |consumers: &[ConsumerConf]| {
+            consumers
+                .iter()
+                .filter(|consumer| consumer.consumes == producer)
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let wanted = consumers_of(&new_consumers);
+        let unchanged = buses.contains(&producer)
+            && old_consumers
+                .as_ref()
+                .is_some_and(|(old, old_width)| consumers_of(old) == wanted && *old_width == width);
+        let consumers = match unchanged {
+            true => None,
+            false => Some(
+                wanted
+                    .iter()
+                    .map(|conf| buses.open(conf, width))
+                    .collect::<io::Result<Vec<_>>>()?,
+            ),
+        };
+        wired.push((producer, conf, consumers));
+    }
+
+    for (producer, conf, consumers) in wired {
+        if !buses.contains(&producer) {
+            buses.insert(producer.clone(), Bus::new(conf.bus_bufsize));
+        }
+        let bus = buses.get_mut(&producer).unwrap();
+        bus.resize(conf.bus_bufsize)?;
+        let max_line = match conf.framing {
+            BusFraming::Bytes => None,
+            BusFraming::Line => Some(conf.max_line),
+        };
+        bus.set_framing(max_line)?;
+        bus.set_flush_interval(conf.flush_interval_ms.map(Duration::from_millis));
+        if let Some(consumers) = consumers {
+            bus.set_consumers(consumers);
+        }
+    }
+    Ok(())
+}
+
+/// Re-read the config and apply it. An invalid config is rejected as a whole,
+/// leaving the current one in place.
+fn reload<W>(
+    loaded: &mut LoadedConfig,
+    registry: &mut Registry,
+    buses: &mut BusMap,
+    watcher: &mut W,
+) -> Result<(), String>
+where
+    W: AsWatcher,
+{
+    if registry.shutting_down {
+        return Err("kinesin is shutting down".to_string());
+    }
+    if loaded.source.is_stdin() {
+        return Err("the config was read from stdin, it can't be read again".to_string());
+    }
+    let mut config = load_checked(&loaded.source).map_err(|problems| problems.join("\n"))?;
+    if config.runtime_dir != loaded.config.runtime_dir {
+        eprintln!(
+            "runtime_dir only changes on restart, keeping {}",
+            loaded.config.runtime_dir.display()
+        );
+        config.runtime_dir = loaded.config.runtime_dir.clone();
+    }
+
+    wire_buses(buses, Some(&loaded.config), &config).map_err(|e| e.to_string())?;
+    registry.apply(&config).map_err(|e| e.to_string())?;
+    let opened = registry
+        .open_notify_sockets(&config.runtime_dir)
+        .map_err(|e| e.to_string())?;
+    for fd in opened {
+        watcher.watch_readable(fd);
+    }
+
+    eprintln!("Reloaded {}", loaded.source.origin());
+    loaded.config = config;
+    Ok(())
+}
+
+/// Hand the service's current pipes over to the watcher and route them into
+/// the buses of the service, and its stdin pipe over to what feeds it.
+pub fn watch_service<W>(srvc: &Service, watcher: &mut W, buses: &mut BusMap)
+where
+    W: AsWatcher,
+{
+    if let Some(stdin) = &srvc.stdin {
+        buses.set_stdin(&srvc.name, Some(stdin.clone()));
+    }
+    if let Some(stdout) = srvc.stdout {
+        watcher.watch_fd(stdout, srvc.def.stdout.read_bufsize);
+        buses.route(stdout, ProducerConf::StdOut(srvc.name.clone()), srvc.pid);
+    }
+    if let Some(stderr) = srvc.stderr {
+        watcher.watch_fd(stderr, srvc.def.stderr.read_bufsize);
+        buses.route(stderr, ProducerConf::StdErr(srvc.name.clone()), srvc.pid);
+    }
+}
+
+/// Hook a freshly started service up to the buses and arm its periodic checks.
+fn on_started<W>(srvc: &Service, watcher: &mut W, buses: &mut BusMap, timers: &mut Timers)
+where
+    W: AsWatcher,
+{
+    watch_service(srvc, watcher, buses);
+    schedule_health(srvc, watcher, timers);
+    schedule_watchdog(srvc, watcher, timers);
+}
+
+/// Schedule the next watchdog check of a service, if it has a watchdog.
+fn schedule_watchdog<W>(srvc: &Service, watcher: &mut W, timers: &mut Timers)
+where
+    W: AsWatcher,
+{
+    if let (Some(watchdog_ms), Some(_)) = (srvc.def.watchdog_ms, &srvc.notify) {
+        let timer = Timer::Watchdog(srvc.name.clone(), srvc.generation);
+        timers.schedule(watcher, Duration::from_millis(watchdog_ms), timer);
+    }
+}
+
+/// Schedule the next health check of a service, if it has any.
+fn schedule_health<W>(srvc: &Service, watcher: &mut W, timers: &mut Timers)
+where
+    W: AsWatcher,
+{
+    if let Some(conf) = &srvc.def.healthcheck {
+        let interval = Duration::from_millis(conf.interval_ms);
+        let timer = Timer::HealthCheck(srvc.name.clone(), srvc.generation);
+        timers.schedule(watcher, interval, timer);
+    }
+}
+
+/// Start whatever services became startable and stop whatever services may
+/// go down, now that the state of the registry changed.
+fn settle<W>(
+    registry: &mut Registry,
+    buses: &mut BusMap,
+    watcher: &mut W,
+    timers: &mut Timers,
+) -> io::Result<()>
+where
+    W: AsWatcher,
+{
+    // a consumer which can't be done without failed, so everything goes down
+    // the same as it would on SIGTERM
+    if let Some(e) = buses.take_fatal() {
+        eprintln!("{}, shutting down", e);
+        registry.exit_code.get_or_insert(1);
+        if !registry.shutting_down {
+            shutdown(registry, watcher, timers);
+        }
+    }
+    let (started, failed) = registry.start_pending();
+    for idx in started {
+        on_started(&registry.services[idx], watcher, buses, timers);
+    }
+    for reaped in failed {
+        handle_death(reaped, registry, buses, watcher, timers)?;
+    }
+    // a critical service couldn't start, the container is done for
+    if registry.exit_code.is_some() && !registry.shutting_down {
+        shutdown(registry, watcher, timers);
+    }
+    for name in registry.stop_pending() {
+        if let Some(srvc) = registry.get_by_name(&name) {
+            let timer = Timer::StopTimeout(name.clone(), srvc.generation);
+            timers.schedule(watcher, registry.stop_timeout(srvc), timer);
+        }
+    }
+    for notify in registry.take_retired_notify() {
+        watcher.unwatch_fd(notify.as_raw_fd());
+    }
+    for (producer, after) in buses.take_flush_timers() {
+        timers.schedule(watcher, after, Timer::Flush(producer));
+    }
+    Ok(())
+}
+
+/// Start bringing everything down in order, with a deadline for the whole thing.
+fn shutdown<W>(registry: &mut Registry, watcher: &mut W, timers: &mut Timers)
+where
+    W: AsWatcher,
+{
+    registry.begin_shutdown();
+    timers.schedule(watcher, registry.shutdown_grace, Timer::ShutdownGrace);
+}
+
+/// Deal with the death of a service's process, or with its failure to start:
+/// bring it back once its restart delay is up and run its hooks.
+fn handle_death<W>(
+    reaped: Reaped,
+    registry: &mut Registry,
+    buses: &mut BusMap,
+    watcher: &mut W,
+    timers: &mut Timers,
+) -> io::Result<()>
+where
+    W: AsWatcher,
+{
+    // we don't drop the bus here because this signal may have been
+    // caught before the final event on the relevent fds. This flush
+    // is here in case 1 service dies much earlier than other(s)
+    buses.flush(&ProducerConf::StdOut(reaped.name.clone()))?;
+    buses.flush(&ProducerConf::StdErr(reaped.name.clone()))?;
+    buses.set_stdin(&reaped.name, None);
+
+    if let Some(delay) = reaped.restart_in {
+        timers.schedule(watcher, delay, Timer::Restart(reaped.name.clone()));
+    }
+
+    let timeout = registry.hook_timeout;
+    match registry.start_hook(&reaped) {
+        Ok(Some(hook)) => watch_hook(hook, timeout, watcher, buses, timers),
+        Ok(None) => {}
+        Err(e) => eprintln!("Failed to start a hook of service {}: {}", reaped.name, e),
+    }
+    Ok(())
+}
+
+/// Watch a hook that just started and make sure it can't outstay its timeout.
+fn watch_hook<W>(
+    hook: &Service,
+    timeout: Duration,
+    watcher: &mut W,
+    buses: &mut BusMap,
+    timers: &mut Timers,
+) where
+    W: AsWatcher,
+{
+    watch_service(hook, watcher, buses);
+    if let Some(pid) = hook.pid {
+        timers.schedule(watcher, timeout, Timer::HookTimeout(pid));
+    }
+}
+
+/// File events borrow the watcher's buffers, so they're fed to the buses (or to
+/// the control socket) right away.
+/// Everything else is handed back without the borrow so that handling it is
+/// free to use the watcher again.
+fn dispatch_file(
+    event: Event<'_>,
     registry: &mut Registry,
-    bus_map: &mut HashMap<RawFd, Bus>,
-) -> io::Result<()> {
+    buses: &mut BusMap,
+    control: &mut ControlServer,
+) -> io::Result<Option<Event<'static>>> {
+    Ok(match event {
+        Event::File(fd, data) => {
+            if control.owns(fd) {
+                control.received(fd, data, registry, buses);
+            } else {
+                buses.consume(fd, data)?;
+            }
+            None
+        }
+        Event::Signal(sig) => Some(Event::Signal(sig)),
+        Event::Closed(fd) => Some(Event::Closed(fd)),
+        Event::Timer(id) => Some(Event::Timer(id)),
+        Event::Readable(fd) => Some(Event::Readable(fd)),
+    })
+}
+
+pub fn handle_event<W>(
+    event: Event<'static>,
+    registry: &mut Registry,
+    buses: &mut BusMap,
+    watcher: &mut W,
+    timers: &mut Timers,
+    control: &mut ControlServer,
+    loaded: &mut LoadedConfig,
+) -> io::Result<()>
+where
+    W: AsWatcher,
+{
     match event {
         Event::Signal(sig) => match sig {
             Signal::SIGCHLD => {
-                for srvc in registry.reap_children() {
-                    // we don't drop the bus here because this signal may have been
-                    // caught before the final event on the relevent fds. This flush
-                    // is here in case 1 service dies much earlier than other(s)
-                    if let Some(stdout) = srvc.stdout {
-                        if let Some(bus) = bus_map.get_mut(&stdout) {
-                            bus.flush()?;
+                for reaped in registry.reap_children() {
+                    handle_death(reaped, registry, buses, watcher, timers)?;
+                }
+
+                // a critical service is gone for good, so is the container
+                if registry.exit_code.is_some() && !registry.shutting_down {
+                    shutdown(registry, watcher, timers);
+                }
+            }
+            Signal::SIGTERM | Signal::SIGINT => {
+                if registry.shutting_down {
+                    eprintln!("Received {} during shutdown, killing everything", sig);
+                    registry.kill_all();
+                } else {
+                    shutdown(registry, watcher, timers);
+                }
+            }
+            Signal::SIGHUP => {
+                if let Err(e) = reload(loaded, registry, buses, watcher) {
+                    eprintln!("Not reloading the config:\n{}", e);
+                }
+            }
+            sig if sig == loaded.config.reopen_signal => {
+                if let Err(e) = buses.reopen_all() {
+                    eprintln!("Failed to reopen log files: {}", e);
+                }
+            }
+            _ => {
+                eprintln!("Ignoring signal {}, kinesin has no use for it", sig);
+            }
+        },
+        Event::File(fd, data) => {
+            buses.consume(fd, data)?;
+        }
+        Event::Closed(fd) => {
+            if control.closed(fd) {
+                // dropping the connection closed it
+            } else if buses.unroute(fd)?.is_some() {
+                close(fd)?;
+            }
+        }
+        Event::Readable(fd) => {
+            if fd == control.as_raw_fd() {
+                match control.accept() {
+                    Ok(conns) => {
+                        for conn in conns {
+                            watcher.watch_fd(conn, CONTROL_BUFSIZE);
                         }
                     }
-                    if let Some(stderr) = srvc.stderr {
-                        if let Some(bus) = bus_map.get_mut(&stderr) {
-                            bus.flush()?;
+                    Err(e) => eprintln!("Failed to accept control connection: {}", e),
+                }
+                watcher.watch_readable(fd);
+            } else if fd == registry.probes.as_raw_fd() {
+                for (probe, healthy) in registry.probes.take() {
+                    if let Some(srvc) = registry.get_by_probe_mut(probe) {
+                        srvc.probe_finished(healthy);
+                    }
+                }
+                watcher.watch_readable(fd);
+            } else if let Some(srvc) = registry.get_by_notify_fd_mut(fd) {
+                let received = srvc.notify.as_ref().map(|notify| notify.receive());
+                match received {
+                    Some(Ok(received)) => {
+                        let pid = srvc.pid;
+                        for (sender, msg) in received {
+                            srvc.notified(sender, &msg);
+                        }
+                        if srvc.pid != pid {
+                            for producer in [
+                                ProducerConf::StdOut(srvc.name.clone()),
+                                ProducerConf::StdErr(srvc.name.clone()),
+                            ] {
+                                if let Some(bus) = buses.get_mut(&producer) {
+                                    bus.set_pid(srvc.pid);
+                                }
+                            }
                         }
                     }
+                    Some(Err(e)) => {
+                        eprintln!(
+                            "Failed to receive notification of service {}: {}",
+                            srvc.name, e
+                        )
+                    }
+                    None => {}
                 }
+                watcher.watch_readable(fd);
             }
-            Signal::SIGTERM => {
-                for srvc in &registry.services {
-                    if srvc.must_be_up {
-                        kill(srvc.pid, Signal::SIGTERM)?;
+        }
+        Event::Timer(id) => match timers.take(id) {
+            Some(Timer::Restart(name)) => {
+                if let Some(srvc) = registry.get_by_name_mut(&name) {
+                    // the restart may have been called off while waiting
+                    if srvc.state == State::Restarting {
+                        match srvc.start() {
+                            Ok(()) => {
+                                eprintln!("Restarted service {} (attempt {})", name, srvc.restarts);
+                                on_started(srvc, watcher, buses, timers);
+                            }
+                            Err(e) => {
+                                if let Some(reaped) = registry.start_failed(&name, &e) {
+                                    handle_death(reaped, registry, buses, watcher, timers)?;
+                                }
+                                if registry.exit_code.is_some() && !registry.shutting_down {
+                                    shutdown(registry, watcher, timers);
+                                }
+                            }
+                        }
                     }
                 }
             }
-            Signal::SIGINT => {
-                for srvc in &registry.services {
-                    if srvc.must_be_up {
-                        kill(srvc.pid, Signal::SIGINT)?;
+            Some(Timer::HealthCheck(name, generation)) => {
+                if let Some((srvc, probes)) = registry.get_for_probe_mut(&name) {
+                    if srvc.state == State::Running && srvc.generation == generation {
+                        match srvc.start_probe(probes) {
+                            Ok(Some(probe)) => {
+                                let conf = srvc.def.healthcheck.as_ref().unwrap();
+                                let timeout = Duration::from_millis(conf.timeout_ms);
+                                timers.schedule(watcher, timeout, Timer::ProbeTimeout(probe));
+                            }
+                            Ok(None) => {}
+                            // the next round of checks gets another go
+                            Err(e) => eprintln!("Failed to probe service {}: {}", name, e),
+                        }
+                        schedule_health(srvc, watcher, timers);
                     }
                 }
             }
-            _ => {
-                println!("{:?}", sig);
+            Some(Timer::Watchdog(name, generation)) => {
+                if let Some(srvc) = registry.get_by_name_mut(&name) {
+                    if srvc.state == State::Running && srvc.generation == generation {
+                        if srvc.watchdog_expired() {
+                            eprintln!("Watchdog of service {} expired, aborting it", name);
+                            if let Some(pid) = srvc.pid {
+                                if let Err(e) = kill(pid, Signal::SIGABRT) {
+                                    eprintln!("Failed to abort service {}: {}", name, e);
+                                }
+                            }
+                        } else {
+                            schedule_watchdog(srvc, watcher, timers);
+                        }
+                    }
+                }
             }
-        },
-        Event::File(fd, data) => {
-            if let Some(bus) = bus_map.get_mut(&fd) {
-                bus.consume(data)?;
+            Some(Timer::StopTimeout(name, generation)) => {
+                if let Some(srvc) = registry.get_by_name_mut(&name) {
+                    if srvc.state == State::Running && srvc.generation == generation {
+                        if let Some(pid) = srvc.pid {
+                            eprintln!("Service {} didn't stop in time, killing it", name);
+                            if let Err(e) = kill(pid, Signal::SIGKILL) {
+                                eprintln!("Failed to kill service {}: {}", name, e);
+                            }
+                        }
+                    }
+                }
             }
-        }
+            Some(Timer::Flush(producer)) => buses.flush_timer_fired(&producer)?,
+            Some(Timer::ShutdownGrace) => {
+                if registry.is_active() {
+                    eprintln!("Shutdown grace period expired");
+                    registry.kill_all();
+                }
+            }
+            Some(Timer::ProbeTimeout(probe)) => {
+                if let Some(srvc) = registry.get_by_probe_mut(probe) {
+                    match probe {
+                        // the probe gets reaped and counted as a failure like any other
+                        Probe::Child(pid) => {
+                            if let Err(e) = kill(pid, Signal::SIGKILL) {
+                                eprintln!("Failed to kill the probe of {}: {}", srvc.name, e);
+                            }
+                        }
+                        // whatever the thread reports later on goes unheard
+                        Probe::Thread(_) => srvc.probe_finished(false),
+                    }
+                }
+            }
+            Some(Timer::HookTimeout(pid)) => {
+                if let Some(hook) = registry.get_by_pid_mut(pid) {
+                    eprintln!("Hook {} timed out, killing it", hook.name);
+                    if let Err(e) = kill(pid, Signal::SIGKILL) {
+                        eprintln!("Failed to kill hook {}: {}", hook.name, e);
+                    }
+                }
+            }
+            None => (),
+        },
     }
     Ok(())
 }
 
 pub fn run<W>(
     registry: &mut Registry,
-    bus_map: &mut HashMap<RawFd, Bus>,
+    buses: &mut BusMap,
     watcher: &mut W,
+    control: &mut ControlServer,
+    loaded: &mut LoadedConfig,
 ) -> io::Result<()>
 where
     W: AsWatcher,
 {
-    // block on events
-    while !registry.services.is_empty() {
-        if let Some(event) = watcher.poll_block()? {
-            handle_event(event, registry, bus_map)?;
+    let mut timers = Timers::new();
+
+    watcher.watch_readable(control.as_raw_fd());
+    watcher.watch_readable(registry.probes.as_raw_fd());
+    for srvc in &registry.services {
+        if let Some(notify) = &srvc.notify {
+            watcher.watch_readable(notify.as_raw_fd());
+        }
+    }
+    settle(registry, buses, watcher, &mut timers)?;
+
+    loop {
+        // block on events
+        while registry.is_active() {
+            if let Some(event) = watcher.poll_block()? {
+                if let Some(event) = dispatch_file(event, registry, buses, control)? {
+                    handle_event(
+                        event,
+                        registry,
+                        buses,
+                        watcher,
+                        &mut timers,
+                        control,
+                        loaded,
+                    )?;
+                }
+                if control.reload_requested() {
+                    let result = reload(loaded, registry, buses, watcher);
+                    control.reloaded(result);
+                }
+                // notifications count as well, they may make dependents startable
+                settle(registry, buses, watcher, &mut timers)?;
+            }
+        }
+
+        // everything is gone, so the global hook gets its turn before we exit
+        let timeout = registry.hook_timeout;
+        match registry.start_exit_hook() {
+            Ok(Some(hook)) => watch_hook(hook, timeout, watcher, buses, &mut timers),
+            Ok(None) => break,
+            Err(e) => {
+                eprintln!("Failed to start the on_exit hook: {}", e);
+                break;
+            }
         }
     }
 
     // flush out the remaining events until no more events exist
     while let Some(event) = watcher.poll_no_block()? {
-        handle_event(event, registry, bus_map)?;
+        if let Some(event) = dispatch_file(event, registry, buses, control)? {
+            handle_event(
+                event,
+                registry,
+                buses,
+                watcher,
+                &mut timers,
+                control,
+                loaded,
+            )?;
+        }
     }
 
     // flush the buses
-    for (fd, bus) in bus_map.iter_mut() {
-        bus.flush()?;
-        close(*fd)?;
+    for fd in buses.routed_fds() {
+        buses.unroute(fd)?;
+        close(fd)?;
     }
+    buses.flush_all()?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::ConfigFormat;
+    use std::{os::fd::RawFd, time::Instant};
+
+    /// A watcher that never reports anything, for driving the loop by hand.
+    struct Idle;
+
+    impl AsWatcher for Idle {
+        fn watch_fd(&mut self, _fd: RawFd, _buffsize: usize) {}
+
+        fn unwatch_fd(&mut self, _fd: RawFd) {}
+
+        fn watch_readable(&mut self, _fd: RawFd) {}
+
+        fn watch_timer(&mut self, _id: u64, _after: Duration) {}
+
+        fn poll_block(&mut self) -> io::Result<Option<Event<'_>>> {
+            Ok(None)
+        }
+
+        fn poll_no_block(&mut self) -> io::Result<Option<Event<'_>>> {
+            Ok(None)
+        }
+    }
+
+    #[test]
+    fn fatal_consumer_failures_shut_everything_down() {
+        let config = Config::parse("service = []", ConfigFormat::Toml).unwrap();
+        let mut registry = Registry::new(&config).unwrap();
+        let mut buses = BusMap::new();
+        buses.set_threads(1).unwrap();
+        let mut timers = Timers::new();
+
+        let conf = r#"
+            consumes = { stdout = "app" }
+            kind = { log = "/dev/full" }
+            on_error = "fatal"
+        "#;
+        let conf = toml::from_str::<ConsumerConf>(conf).unwrap();
+        let consumer = buses.open(&conf, 0).unwrap();
+        consumer.write(b"lost\n");
+        consumer.drain(Instant::now() + Duration::from_secs(5));
+
+        settle(&mut registry, &mut buses, &mut Idle, &mut timers).unwrap();
+        assert!(registry.shutting_down);
+        assert_eq!(registry.exit_code, Some(1));
+        assert!(matches!(timers.take(0), Some(Timer::ShutdownGrace)));
+    }
+}
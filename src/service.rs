@@ -2,34 +2,389 @@
 //!
 //! The service is concerned with everything relating to per-process management.
 //! Open file descriptors, environment variables, the process ID, etc are all
-//! managed here.
-use crate::conf::ServiceConf;
-use crate::utils::{set_fd_nonblocking, set_std_stream};
-use nix::sys::signal::SigSet;
+//! managed here. A service outlives the processes it forks, so that it can be
+//! brought back up according to its restart policy once its process dies.
+use crate::conf::{RestartPolicy, ServiceConf, ServiceType, StdinConf, UnhealthyAction};
+use crate::health::{spawn_probe, Health, HealthStatus, Probe, ProbeReports};
+use crate::notify::{descends_from, Notify};
+use crate::users::Credentials;
+use crate::utils::{jitter, pointers, reset_signal_handlers, set_fd_nonblocking, set_std_stream};
+use nix::sys::{
+    resource::{setrlimit, Resource},
+    signal::{kill, SigSet, Signal},
+    stat::{umask, Mode},
+};
 use nix::{
     errno::Errno,
-    fcntl::{open, OFlag},
+    fcntl::{fcntl, open, FcntlArg, FdFlag, OFlag},
     libc,
-    unistd::{close, dup2, execve, fork, pipe, ForkResult, Pid},
+    unistd::{close, dup2, fork, pipe, write, ForkResult, Pid},
 };
+use serde::{Deserialize, Serialize};
 use std::ffi::CString;
-use std::os::fd::{IntoRawFd, RawFd};
+use std::fmt;
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::os::fd::{AsRawFd, FromRawFd, IntoRawFd, OwnedFd, RawFd};
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
 
 const DEVNULL: &str = "/dev/null";
 
+/// Open what a child reads its stdin from.
+fn open_stdin(path: &Path) -> io::Result<OwnedFd> {
+    let fd = open(path, OFlag::O_RDONLY | OFlag::O_CLOEXEC, Mode::empty()).map_err(|e| {
+        io::Error::new(
+            io::Error::from(e).kind(),
+            format!("stdin {}: {}", path.display(), e.desc()),
+        )
+    })?;
+    Ok(unsafe { OwnedFd::from_raw_fd(fd) })
+}
+
+/// Tell the supervisor why the child couldn't become the service, and exit
+/// with the status a shell gives commands it couldn't run.
+fn report_failure(report: &OwnedFd, parts: &[&str]) -> ! {
+    for part in parts {
+        let _ = write(report, part.as_bytes());
+    }
+    unsafe { libc::_exit(127) }
+}
+
+/// Everything a forked child needs to turn into the service, worked out
+/// before forking. Other threads may have held a lock at the time of the
+/// fork, the allocator's say, which nothing in the child would ever release,
+/// so the child sticks to system calls on what's already here.
+struct Launch {
+    argv: Vec<CString>,
+    /// Only kept alive for `env_ptrs`.
+    _env: Vec<CString>,
+    /// `argv` and the environment as `execve` takes them, pointing into heap
+    /// buffers which stay put however `Launch` is moved.
+    argv_ptrs: Vec<*const libc::c_char>,
+    env_ptrs: Vec<*const libc::c_char>,
+    stdin: Option<OwnedFd>,
+    credentials: Option<Credentials>,
+    rlimits: Vec<(&'static str, Resource, u64, u64)>,
+    umask: Option<Mode>,
+    /// The directory to start in, along with how to show it.
+    working_dir: Option<(CString, String)>,
+    /// Names of things the child may fail at, made up front.
+    program_name: String,
+    user_name: String,
+}
+
+impl Launch {
+    /// `extra_env` goes on top of the service's configured environment.
+    fn new(
+        def: &ServiceConf,
+        stdin: Option<OwnedFd>,
+        extra_env: &[&[CString]],
+    ) -> io::Result<Self> {
+        let credentials = match &def.user {
+            Some(user) => {
+                let group = def.user_group.as_deref();
+                let credentials = Credentials::resolve(user, group, &def.supplementary_groups)
+                    .map_err(io::Error::other)?;
+                Some(credentials)
+            }
+            None => None,
+        };
+
+        let login = ["HOME", "USER", "LOGNAME"];
+        let mut env = std::env::vars_os()
+            .filter(|(k, _)| credentials.is_none() || !login.contains(&&*k.to_string_lossy()))
+            .map(|(k, v)| {
+                CString::new(format!("{}={}", k.to_string_lossy(), v.to_string_lossy())).unwrap()
+            })
+            .collect::<Vec<_>>();
+        // the login variables of the user, unless the service sets them
+        if let Some(credentials) = &credentials {
+            for (key, value) in credentials.env() {
+                let prefix = format!("{}=", key);
+                let set = def
+                    .env
+                    .iter()
+                    .any(|var| var.to_bytes().starts_with(prefix.as_bytes()));
+                if !set {
+                    env.push(CString::new(format!("{}{}", prefix, value)).unwrap());
+                }
+            }
+        }
+        env.extend(def.env.iter().cloned());
+        for vars in extra_env {
+            env.extend(vars.iter().cloned());
+        }
+
+        let working_dir = match &def.working_dir {
+            Some(dir) => {
+                let path = CString::new(dir.as_os_str().as_bytes()).map_err(io::Error::other)?;
+                Some((path, dir.display().to_string()))
+            }
+            None => None,
+        };
+
+        let argv = def.exec.clone();
+        Ok(Self {
+            argv_ptrs: pointers(&argv),
+            env_ptrs: pointers(&env),
+            argv,
+            _env: env,
+            stdin,
+            user_name: credentials
+                .as_ref()
+                .map_or(String::new(), |c| c.name.clone()),
+            credentials,
+            rlimits: def
+                .rlimits
+                .iter()
+                .map(|(name, resource, limit)| {
+                    (name, resource, limit.soft().raw(), limit.hard().raw())
+                })
+                .collect(),
+            umask: def.umask.map(Mode::from_bits_truncate),
+            working_dir,
+            program_name: def.exec[0].to_string_lossy().into_owned(),
+        })
+    }
+
+    /// Become the service in the forked child, with `wout` and `werr` as its
+    /// stdout and stderr, or report why not through `report`.
+    fn exec(&self, wout: RawFd, werr: RawFd, report: &OwnedFd) -> ! {
+        // remove the blocking of signals for children.
+        reset_signal_handlers();
+        if let Err(e) = SigSet::all().thread_unblock() {
+            report_failure(report, &["unblocking signals: ", e.desc()]);
+        }
+        for (fd, target) in [(wout, libc::STDOUT_FILENO), (werr, libc::STDERR_FILENO)] {
+            if let Err(e) = set_std_stream(fd).and_then(|_| dup2(fd, target)) {
+                report_failure(report, &["redirecting output: ", e.desc()]);
+            }
+        }
+        if let Some(stdin) = &self.stdin {
+            if let Err(e) = dup2(stdin.as_raw_fd(), libc::STDIN_FILENO) {
+                report_failure(report, &["redirecting stdin: ", e.desc()]);
+            }
+        }
+        // limits first, raising one takes privileges the user may lack
+        for &(name, resource, soft, hard) in &self.rlimits {
+            if let Err(e) = setrlimit(resource, soft, hard) {
+                report_failure(report, &["rlimit ", name, ": ", e.desc()]);
+            }
+        }
+        if let Some(mask) = self.umask {
+            umask(mask);
+        }
+        if let Some(credentials) = &self.credentials {
+            if let Err(e) = credentials.apply() {
+                report_failure(
+                    report,
+                    &["switching to user ", &self.user_name, ": ", e.desc()],
+                );
+            }
+        }
+        // only once it's the user, who might not be allowed in
+        if let Some((path, shown)) = &self.working_dir {
+            if unsafe { libc::chdir(path.as_ptr()) } == -1 {
+                report_failure(report, &["working_dir ", shown, ": ", Errno::last().desc()]);
+            }
+        }
+
+        unsafe {
+            libc::execve(
+                self.argv[0].as_ptr(),
+                self.argv_ptrs.as_ptr(),
+                self.env_ptrs.as_ptr(),
+            )
+        };
+        report_failure(report, &[&self.program_name, ": ", Errno::last().desc()])
+    }
+}
+
+/// Start feeding a freshly started service through our end of its stdin
+/// pipe. Inline text gets written on a thread of its own, which closes the
+/// pipe once it's done, while the pipe of a service fed another's output is
+/// returned to be handed to its consumer.
+fn feed_stdin(conf: &StdinConf, pipe: OwnedFd) -> Option<Arc<File>> {
+    let mut pipe = File::from(pipe);
+    match conf {
+        StdinConf::Text(text) => {
+            let text = text.clone();
+            thread::spawn(move || {
+                // the service may well exit without reading all of it
+                let _ = pipe.write_all(text.as_bytes());
+            });
+            None
+        }
+        _ => Some(Arc::new(pipe)),
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ExitStatus {
+    Exited(i32),
+    Signaled(Signal),
+}
+
+impl ExitStatus {
+    pub fn success(&self) -> bool {
+        matches!(self, Self::Exited(0))
+    }
+
+    /// The code kinesin itself exits with when this status brings it down.
+    pub fn code(&self) -> i32 {
+        match self {
+            Self::Exited(code) => *code,
+            Self::Signaled(_) => -1,
+        }
+    }
+}
+
+impl fmt::Display for ExitStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Exited(code) => write!(f, "exited with {}", code),
+            Self::Signaled(sig) => write!(f, "killed by {}", sig),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum State {
+    Inactive,
+    Running,
+    Restarting,
+    Exited,
+}
+
 #[derive(Debug)]
 pub struct Service {
     pub def: ServiceConf,
     pub name: String,
-    pub pid: Pid,
+    pub pid: Option<Pid>,
     pub stdout: Option<RawFd>,
     pub stderr: Option<RawFd>,
+    /// Our end of the stdin pipe, when the service is fed another's output.
+    pub stdin: Option<Arc<File>>,
     pub must_be_up: bool,
+    pub state: State,
+    pub restarts: u32,
+    /// Bumped on every start, so timers armed for a previous process can tell
+    /// they no longer apply.
+    pub generation: u32,
+    pub last_exit: Option<ExitStatus>,
+    pub stop_requested: bool,
+    pub hook: bool,
+    pub health: Option<Health>,
+    pub notify: Option<Notify>,
+    /// Restart once the process dies, whatever the restart policy says.
+    pub force_restart: bool,
+    /// Go back to waiting on dependencies once the process dies.
+    pub requeue: bool,
+    /// The stop signal was sent to the current process.
+    pub stopping: bool,
+    /// Dropped from the config, goes away once its process is gone.
+    pub retired: bool,
+    started_at: Option<Instant>,
+    backoff_step: u32,
 }
 
 impl Service {
-    pub fn new(def: &ServiceConf) -> Result<Self, Errno> {
-        let name = def.name.clone();
+    pub fn new(def: &ServiceConf) -> Self {
+        Self {
+            def: def.clone(),
+            name: def.name.clone(),
+            pid: None,
+            stdout: None,
+            stderr: None,
+            stdin: None,
+            must_be_up: def.must_be_up,
+            state: State::Inactive,
+            restarts: 0,
+            generation: 0,
+            last_exit: None,
+            stop_requested: false,
+            hook: false,
+            health: None,
+            notify: None,
+            force_restart: false,
+            requeue: false,
+            stopping: false,
+            retired: false,
+            started_at: None,
+            backoff_step: 0,
+        }
+    }
+
+    pub fn new_hook(def: &ServiceConf) -> Self {
+        Self {
+            hook: true,
+            ..Self::new(def)
+        }
+    }
+
+    /// Bind the notification socket of a `notify` service inside `dir`,
+    /// returning it if it wasn't bound yet.
+    pub fn open_notify(&mut self, dir: &Path) -> std::io::Result<Option<RawFd>> {
+        if self.def.kind != ServiceType::Notify || self.notify.is_some() {
+            return Ok(None);
+        }
+        let notify = Notify::bind(Notify::socket_path(dir, &self.name))?;
+        let fd = notify.as_raw_fd();
+        self.notify = Some(notify);
+        Ok(Some(fd))
+    }
+
+    pub fn start(&mut self) -> io::Result<()> {
+        self.start_with(&[])
+    }
+
+    /// Start the service with `extra_env` added on top of its configured environment.
+    pub fn start_with(&mut self, extra_env: &[CString]) -> io::Result<()> {
+        let def = &self.def;
+
+        let mut notify_env = Vec::new();
+        if let Some(notify) = &mut self.notify {
+            notify.reset();
+            let path = notify.path.to_string_lossy();
+            notify_env.push(CString::new(format!("NOTIFY_SOCKET={}", path)).unwrap());
+            if let Some(watchdog_ms) = def.watchdog_ms {
+                let usec = watchdog_ms * 1000;
+                notify_env.push(CString::new(format!("WATCHDOG_USEC={}", usec)).unwrap());
+            }
+        }
+
+        // a pipe for stdin whenever kinesin is the one writing to it, neither
+        // end of which should leak into other services
+        let (stdin, stdin_feed) = match &def.stdin {
+            StdinConf::Inherit => (None, None),
+            StdinConf::Null => (Some(open_stdin(Path::new(DEVNULL))?), None),
+            StdinConf::File(path) => (Some(open_stdin(path)?), None),
+            StdinConf::Text(_) | StdinConf::StdOut(_) | StdinConf::StdErr(_) => {
+                let (read, write) = pipe()?;
+                for fd in [&read, &write] {
+                    fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+                }
+                // the consumer waits for room with poll, so it can notice the
+                // service going away while it does
+                if !matches!(def.stdin, StdinConf::Text(_)) {
+                    set_fd_nonblocking(write.as_raw_fd())?;
+                }
+                (Some(read), Some(write))
+            }
+        };
+        let launch = Launch::new(def, stdin, &[extra_env, &notify_env])?;
+
+        // the child reports what kept it from running the service through
+        // this, which closes without a word once it does
+        let (report_read, report_write) = pipe()?;
+        for fd in [&report_read, &report_write] {
+            fcntl(fd.as_raw_fd(), FcntlArg::F_SETFD(FdFlag::FD_CLOEXEC))?;
+        }
 
         let (rout_owned, wout_owned) = pipe()?;
         let (rerr_owned, werr_owned) = pipe()?;
@@ -41,14 +396,14 @@ impl Service {
             wout_owned.into_raw_fd()
         } else {
             close(wout_owned.into_raw_fd()).unwrap();
-            open(DEVNULL, OFlag::O_WRONLY, nix::sys::stat::Mode::empty()).unwrap()
+            open(DEVNULL, OFlag::O_WRONLY, Mode::empty()).unwrap()
         };
 
         let werr = if def.stderr.watch {
             werr_owned.into_raw_fd()
         } else {
             close(werr_owned.into_raw_fd()).unwrap();
-            open(DEVNULL, OFlag::O_WRONLY, nix::sys::stat::Mode::empty()).unwrap()
+            open(DEVNULL, OFlag::O_WRONLY, Mode::empty()).unwrap()
         };
 
         match unsafe { fork() } {
@@ -56,6 +411,15 @@ impl Service {
                 close(wout).unwrap();
                 close(werr).unwrap();
 
+                drop(report_write);
+                drop(launch);
+                let mut failure = String::new();
+                let _ = File::from(report_read).read_to_string(&mut failure);
+                if !failure.is_empty() {
+                    // the child exits right after, and dies like any service
+                    eprintln!("Failed to start service {}: {}", self.name, failure);
+                }
+
                 if def.stdout.watch {
                     set_fd_nonblocking(stdout)?;
                 } else {
@@ -68,39 +432,255 @@ impl Service {
                     close(stderr).unwrap();
                 }
 
-                Ok(Self {
-                    def: def.clone(),
-                    name,
-                    pid,
-                    stdout: if def.stdout.watch { Some(stdout) } else { None },
-                    stderr: if def.stderr.watch { Some(stderr) } else { None },
-                    must_be_up: def.must_be_up,
-                })
+                self.stdin = stdin_feed.and_then(|write| feed_stdin(&def.stdin, write));
+                self.stdout = if def.stdout.watch { Some(stdout) } else { None };
+                self.stderr = if def.stderr.watch { Some(stderr) } else { None };
+                self.pid = Some(pid);
+                self.state = State::Running;
+                self.generation = self.generation.wrapping_add(1);
+                self.stop_requested = false;
+                self.force_restart = false;
+                self.requeue = false;
+                self.stopping = false;
+                self.health = def.healthcheck.as_ref().map(|_| Health::new());
+                self.started_at = Some(Instant::now());
+                Ok(())
             }
             Ok(ForkResult::Child) => {
-                // remove the blocking of signals for children.
-                SigSet::all().thread_unblock().unwrap();
-                set_std_stream(wout)?;
-                set_std_stream(werr)?;
-                dup2(wout, libc::STDOUT_FILENO).unwrap();
-                dup2(werr, libc::STDERR_FILENO).unwrap();
-                close(stdout).unwrap();
-                close(stderr).unwrap();
-                let mut env_vars = std::env::vars_os()
-                    .map(|(k, v)| {
-                        CString::new(format!("{}={}", k.to_string_lossy(), v.to_string_lossy()))
-                            .unwrap()
-                    })
-                    .collect::<Vec<_>>();
-
-                for var in &def.env {
-                    env_vars.push(var.clone());
+                // the child end of every pipe becomes one of its std streams
+                for fd in [stdout, stderr] {
+                    let _ = close(fd);
                 }
+                launch.exec(wout, werr, &report_write)
+            }
+            Err(e) => Err(e.into()),
+        }
+    }
+
+    /// Ask the process to go away with the service's stop signal. It won't be
+    /// restarted, unless it's requeued.
+    pub fn stop(&mut self) -> Result<(), Errno> {
+        self.stop_requested = true;
+        self.stopping = true;
+        match self.pid {
+            Some(pid) => kill(pid, self.def.stop_signal),
+            None => Ok(()),
+        }
+    }
+
+    /// How long the current process has been running.
+    pub fn uptime(&self) -> Option<Duration> {
+        self.started_at.map(|at| at.elapsed())
+    }
+
+    /// Whether the service is up as far as its dependents are concerned. A
+    /// `notify` service has to report `READY=1` first.
+    pub fn is_started(&self) -> bool {
+        self.state == State::Running && self.notify.as_ref().is_none_or(|notify| notify.ready)
+    }
+
+    /// Whether dependents waiting for readiness may start: the service is
+    /// started and, if it has a health check, passed it.
+    pub fn is_ready(&self) -> bool {
+        self.is_started()
+            && self
+                .health
+                .as_ref()
+                .is_none_or(|health| health.status == HealthStatus::Healthy)
+    }
+
+    /// Record the death of the service's process and decide whether it should
+    /// be brought back up, returning how long to wait before doing so.
+    pub fn exited(&mut self, status: ExitStatus) -> Option<Duration> {
+        let uptime = self.started_at.take().map(|at| at.elapsed());
+        self.pid = None;
+        self.stdin = None;
+        self.last_exit = Some(status);
+
+        if self.requeue {
+            self.requeue = false;
+            self.state = State::Inactive;
+            return None;
+        }
+
+        let restart = &self.def.restart;
+        let wanted = !self.stop_requested
+            && (self.force_restart
+                || match restart.policy {
+                    RestartPolicy::Never => false,
+                    RestartPolicy::OnFailure => !status.success(),
+                    RestartPolicy::Always => true,
+                    RestartPolicy::UnlessStopped => !matches!(
+                        status,
+                        ExitStatus::Signaled(Signal::SIGTERM | Signal::SIGINT)
+                    ),
+                });
+        let exhausted = restart.max_attempts.is_some_and(|max| self.restarts >= max);
+
+        if !wanted || exhausted {
+            self.state = State::Exited;
+            return None;
+        }
+
+        if uptime.is_some_and(|up| up >= Duration::from_millis(restart.reset_after_ms)) {
+            self.backoff_step = 0;
+        }
+
+        // Exponential backoff with "equal jitter": half the delay is fixed and
+        // the other half is random, so flapping replicas don't restart in step.
+        let delay = restart
+            .delay_ms
+            .saturating_mul(1 << self.backoff_step.min(32))
+            .min(restart.max_delay_ms);
+        let delay = delay / 2 + jitter(delay - delay / 2);
 
-                execve(&def.exec[0], &def.exec, env_vars.as_slice()).unwrap();
-                unreachable!()
+        self.backoff_step += 1;
+        self.restarts += 1;
+        self.state = State::Restarting;
+        Some(Duration::from_millis(delay))
+    }
+
+    /// Launch a health probe unless the previous one is still going.
+    pub fn start_probe(&mut self, reports: &mut ProbeReports) -> io::Result<Option<Probe>> {
+        let (Some(conf), Some(health)) = (&self.def.healthcheck, &mut self.health) else {
+            return Ok(None);
+        };
+        if health.probe.is_some() {
+            return Ok(None);
+        }
+        let probe = spawn_probe(conf, &self.def.env, reports)?;
+        health.probe = Some(probe);
+        Ok(Some(probe))
+    }
+
+    /// Record the outcome of a health probe, and deal with the service as
+    /// configured if it just turned unhealthy.
+    pub fn probe_finished(&mut self, healthy: bool) {
+        let (Some(conf), Some(health)) = (&self.def.healthcheck, &mut self.health) else {
+            return;
+        };
+        let starting = self
+            .started_at
+            .is_some_and(|at| at.elapsed() < Duration::from_millis(conf.start_period_ms));
+        if !health.record(conf, healthy, starting) {
+            return;
+        }
+        eprintln!("Service {} is unhealthy", self.name);
+        let action = conf.on_unhealthy;
+        if let (UnhealthyAction::Restart | UnhealthyAction::Kill, Some(pid)) = (action, self.pid) {
+            self.force_restart = action == UnhealthyAction::Restart;
+            if let Err(e) = kill(pid, Signal::SIGKILL) {
+                eprintln!("Failed to kill unhealthy service {}: {}", self.name, e);
             }
-            Err(e) => Err(e),
         }
     }
+
+    /// Apply a message received on the notification socket from `sender`,
+    /// which has to be part of the service unless the platform can't tell.
+    pub fn notified(&mut self, sender: Option<Pid>, msg: &[u8]) {
+        let (Some(notify), Some(pid)) = (&mut self.notify, self.pid) else {
+            return;
+        };
+        if let Some(sender) = sender.filter(|&sender| !descends_from(sender, pid)) {
+            eprintln!(
+                "Ignoring notification for service {} from pid {}, which isn't part of it",
+                self.name, sender
+            );
+            return;
+        }
+        notify.handle(msg);
+        // track the real daemon when the process we forked hands over to it
+        let Some(main_pid) = notify.main_pid.take() else {
+            return;
+        };
+        if descends_from(main_pid, pid) {
+            self.pid = Some(main_pid);
+        } else {
+            eprintln!(
+                "Ignoring MAINPID={} of service {}, which isn't part of it",
+                main_pid, self.name
+            );
+        }
+    }
+
+    /// Whether a watchdog enabled service went silent for too long after it
+    /// reported being ready.
+    pub fn watchdog_expired(&self) -> bool {
+        let (Some(watchdog_ms), Some(notify)) = (self.def.watchdog_ms, &self.notify) else {
+            return false;
+        };
+        notify
+            .last_watchdog
+            .is_some_and(|at| at.elapsed() > Duration::from_millis(watchdog_ms))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::conf::Config;
+
+    /// A service restarted always, after `delay_ms` doubled on every restart
+    /// up to `max_delay_ms`.
+    fn service(delay_ms: u64, max_delay_ms: u64, extra: &str) -> Service {
+        let toml = format!(
+            "[[service]]\nname = \"app\"\nexec = [\"/bin/true\"]\n\
+             restart = {{ policy = \"always\", delay_ms = {}, max_delay_ms = {}{} }}\n",
+            delay_ms, max_delay_ms, extra
+        );
+        Service::new(&toml::from_str::<Config>(&toml).unwrap().service[0])
+    }
+
+    /// Let the service die after running for `uptime`, returning the delay in
+    /// milliseconds before it's restarted.
+    fn died_after(srvc: &mut Service, uptime: Duration) -> Option<u64> {
+        srvc.started_at = Some(Instant::now().checked_sub(uptime).unwrap());
+        srvc.exited(ExitStatus::Exited(1))
+            .map(|delay| delay.as_millis() as u64)
+    }
+
+    #[test]
+    fn backoff_doubles_up_to_the_max_delay() {
+        let mut srvc = service(100, 1000, "");
+        for full in [100, 200, 400, 800, 1000, 1000, 1000] {
+            let delay = died_after(&mut srvc, Duration::ZERO).unwrap();
+            // half of it is fixed, the other half random
+            assert!((full / 2..=full).contains(&delay), "{} of {}", delay, full);
+        }
+        assert_eq!(srvc.restarts, 7);
+        assert_eq!(srvc.state, State::Restarting);
+    }
+
+    #[test]
+    fn backoff_is_jittered() {
+        let mut srvc = service(1000, 1000, "");
+        let delays = (0..20)
+            .map(|_| died_after(&mut srvc, Duration::ZERO).unwrap())
+            .collect::<Vec<_>>();
+        assert!(delays.iter().all(|delay| (500..=1000).contains(delay)));
+        assert!(delays.iter().any(|&delay| delay != delays[0]));
+    }
+
+    #[test]
+    fn backoff_starts_over_after_a_long_enough_run() {
+        let mut srvc = service(100, 100_000, ", reset_after_ms = 5000");
+        for _ in 0..5 {
+            died_after(&mut srvc, Duration::from_millis(10));
+        }
+        let delay = died_after(&mut srvc, Duration::from_millis(4000)).unwrap();
+        assert!(delay >= 1600, "{}", delay);
+        let delay = died_after(&mut srvc, Duration::from_millis(5000)).unwrap();
+        assert!((50..=100).contains(&delay), "{}", delay);
+        let delay = died_after(&mut srvc, Duration::ZERO).unwrap();
+        assert!((100..=200).contains(&delay), "{}", delay);
+    }
+
+    #[test]
+    fn no_restart_past_max_attempts() {
+        let mut srvc = service(1, 1, ", max_attempts = 2");
+        assert!(died_after(&mut srvc, Duration::ZERO).is_some());
+        assert!(died_after(&mut srvc, Duration::ZERO).is_some());
+        assert_eq!(died_after(&mut srvc, Duration::ZERO), None);
+        assert_eq!(srvc.state, State::Exited);
+    }
 }
@@ -0,0 +1,260 @@
+//! A consumer sending every line of output to a syslog server.
+//!
+//! Lines become messages in either the RFC 5424 or the older RFC 3164 (BSD)
+//! format, whose severity comes from the stream they were read from. Delivery
+//! goes through an `Outbox` like any other network consumer.
+use crate::{
+    conf::{ProducerConf, SyslogConf, SyslogFormat, SyslogServer},
+    net::{connect_tcp, Connect, Datagrams, Lines, Outbox},
+    utils::CivilTime,
+};
+use nix::unistd::gethostname;
+use std::{
+    io::{self, Write},
+    net::TcpStream,
+    time::{Duration, Instant},
+};
+
+const SEVERITY_ERR: u8 = 3;
+const SEVERITY_INFO: u8 = 6;
+
+/// RFC 5424 caps the APP-NAME field at this many characters.
+const MAX_APP_NAME: usize = 48;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Either kind of connection to a syslog server.
+enum SyslogStream {
+    Tcp(TcpStream),
+    Datagrams(Datagrams),
+}
+
+impl Write for SyslogStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(stream) => stream.write(buf),
+            Self::Datagrams(socket) => socket.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(stream) => stream.flush(),
+            Self::Datagrams(socket) => socket.flush(),
+        }
+    }
+}
+
+struct SyslogConnector {
+    server: SyslogServer,
+    timeout: Duration,
+}
+
+impl Connect for SyslogConnector {
+    type Stream = SyslogStream;
+
+    fn connect(&self) -> io::Result<SyslogStream> {
+        Ok(match &self.server {
+            SyslogServer::Udp(address) => SyslogStream::Datagrams(Datagrams::connect_udp(address)?),
+            SyslogServer::Tcp(address) => SyslogStream::Tcp(connect_tcp(address, self.timeout)?),
+            SyslogServer::Unix(path) => {
+                SyslogStream::Datagrams(Datagrams::connect_unix(path, self.timeout)?)
+            }
+        })
+    }
+
+    fn name(&self) -> String {
+        match &self.server {
+            SyslogServer::Udp(address) => format!("syslog udp {}", address),
+            SyslogServer::Tcp(address) => format!("syslog tcp {}", address),
+            SyslogServer::Unix(path) => format!("syslog {}", path.display()),
+        }
+    }
+}
+
+/// Header fields may only hold printable ASCII without spaces.
+fn header_field(value: &str, max_len: usize) -> String {
+    let field = value
+        .chars()
+        .map(|c| if c.is_ascii_graphic() { c } else { '_' })
+        .take(max_len)
+        .collect::<String>();
+    match field.is_empty() {
+        true => "-".to_string(),
+        false => field,
+    }
+}
+
+/// How lines are framed as messages.
+struct Header {
+    format: SyslogFormat,
+    priority: u8,
+    hostname: String,
+    app_name: String,
+    /// Messages over TCP are prefixed with their length.
+    octet_counting: bool,
+}
+
+impl Header {
+    fn message(&self, line: &[u8], now: &CivilTime) -> Vec<u8> {
+        let header = match self.format {
+            SyslogFormat::Rfc5424 => format!(
+                "<{}>1 {} {} {} - - - ",
+                self.priority,
+                now.rfc3339(),
+                self.hostname,
+                self.app_name
+            ),
+            SyslogFormat::Rfc3164 => format!(
+                "<{}>{} {:2} {:02}:{:02}:{:02} {} {}: ",
+                self.priority,
+                MONTHS[now.month as usize - 1],
+                now.day,
+                now.hour,
+                now.minute,
+                now.second,
+                self.hostname,
+                self.app_name
+            ),
+        };
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        let message = [header.as_bytes(), line].concat();
+        match self.octet_counting {
+            true => [format!("{} ", message.len()).as_bytes(), &message].concat(),
+            false => message,
+        }
+    }
+}
+
+pub struct SyslogConsumer {
+    lines: Lines,
+    header: Header,
+    outbox: Outbox,
+}
+
+impl SyslogConsumer {
+    pub fn new(conf: &SyslogConf, producer: &ProducerConf) -> io::Result<Self> {
+        let severity = match producer {
+            ProducerConf::StdOut(_) => SEVERITY_INFO,
+            ProducerConf::StdErr(_) => SEVERITY_ERR,
+        };
+        let hostname = gethostname()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let app_name = conf.app_name.as_deref().unwrap_or(producer.service_name());
+        let connector = SyslogConnector {
+            server: conf.server.clone(),
+            timeout: Duration::from_millis(conf.delivery.timeout_ms),
+        };
+        Ok(Self {
+            lines: Lines::new(conf.max_line),
+            header: Header {
+                format: conf.format,
+                priority: (conf.facility as u8) * 8 + severity,
+                hostname: header_field(&hostname, 255),
+                app_name: header_field(app_name, MAX_APP_NAME),
+                octet_counting: matches!(conf.server, SyslogServer::Tcp(_)),
+            },
+            outbox: Outbox::spawn(
+                connector,
+                conf.delivery.queue_bytes,
+                (&conf.delivery).into(),
+            )?,
+        })
+    }
+
+    fn message(&self, line: &[u8]) -> Vec<u8> {
+        self.header.message(line, &CivilTime::now())
+    }
+
+    pub fn write(&mut self, data: &[u8]) {
+        for line in self.lines.split(data) {
+            if !line.is_empty() {
+                self.outbox.push(self.message(&line));
+            }
+        }
+    }
+
+    pub fn drain(&mut self, deadline: Instant) {
+        if let Some(line) = self.lines.finish() {
+            self.outbox.push(self.message(&line));
+        }
+        self.outbox.drain(deadline);
+    }
+}
+
+impl Drop for SyslogConsumer {
+    fn drop(&mut self) {
+        if let Some(line) = self.lines.finish() {
+            self.outbox.push(self.message(&line));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// 2024-03-05 07:08:09.012 UTC
+    fn then() -> CivilTime {
+        CivilTime {
+            year: 2024,
+            month: 3,
+            day: 5,
+            hour: 7,
+            minute: 8,
+            second: 9,
+            millis: 12,
+        }
+    }
+
+    fn header(format: SyslogFormat, octet_counting: bool) -> Header {
+        Header {
+            format,
+            // local0 and err
+            priority: 16 * 8 + SEVERITY_ERR,
+            hostname: header_field("box", 255),
+            app_name: header_field("web", MAX_APP_NAME),
+            octet_counting,
+        }
+    }
+
+    #[test]
+    fn rfc5424() {
+        let message = header(SyslogFormat::Rfc5424, false).message(b"hello", &then());
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "<131>1 2024-03-05T07:08:09.012Z box web - - - hello"
+        );
+    }
+
+    #[test]
+    fn rfc3164_pads_the_day() {
+        let message = header(SyslogFormat::Rfc3164, false).message(b"hello", &then());
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "<131>Mar  5 07:08:09 box web: hello"
+        );
+    }
+
+    #[test]
+    fn octet_counting_prefixes_the_length() {
+        let message = header(SyslogFormat::Rfc3164, true).message(b"hello\r", &then());
+        assert_eq!(
+            String::from_utf8(message).unwrap(),
+            "35 <131>Mar  5 07:08:09 box web: hello"
+        );
+    }
+
+    #[test]
+    fn header_fields_are_printable() {
+        assert_eq!(header_field("my app", MAX_APP_NAME), "my_app");
+        assert_eq!(header_field("", MAX_APP_NAME), "-");
+        assert_eq!(
+            header_field(&"a".repeat(60), MAX_APP_NAME).len(),
+            MAX_APP_NAME
+        );
+    }
+}
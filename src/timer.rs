@@ -0,0 +1,54 @@
+//! Bookkeeping for the timers armed through the watcher.
+//!
+//! Watcher backends only deal in opaque timer ids, so this table remembers what
+//! every id handed out was meant to do. Timers are never cancelled; whoever
+//! handles a fired timer is expected to check that it still applies.
+use crate::{conf::ProducerConf, health::Probe, watcher::AsWatcher};
+use nix::unistd::Pid;
+use std::{collections::HashMap, time::Duration};
+
+#[derive(Debug)]
+pub enum Timer {
+    Restart(String),
+    HookTimeout(Pid),
+    /// Probe the health of a service, as long as it wasn't restarted since.
+    /// Timers of a service carry the generation of the process they're for.
+    HealthCheck(String, u32),
+    ProbeTimeout(Probe),
+    /// Check that a service kept pinging its watchdog since it was started.
+    Watchdog(String, u32),
+    /// Kill a service which is still around after it was asked to stop.
+    StopTimeout(String, u32),
+    /// Kill everything that outlived the shutdown grace period.
+    ShutdownGrace,
+    /// Flush whatever a bus with a flush interval has buffered.
+    Flush(ProducerConf),
+}
+
+pub struct Timers {
+    next_id: u64,
+    pending: HashMap<u64, Timer>,
+}
+
+impl Timers {
+    pub fn new() -> Self {
+        Self {
+            next_id: 0,
+            pending: HashMap::new(),
+        }
+    }
+
+    pub fn schedule<W>(&mut self, watcher: &mut W, after: Duration, timer: Timer)
+    where
+        W: AsWatcher,
+    {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.pending.insert(id, timer);
+        watcher.watch_timer(id, after);
+    }
+
+    pub fn take(&mut self, id: u64) -> Option<Timer> {
+        self.pending.remove(&id)
+    }
+}
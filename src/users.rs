@@ -0,0 +1,158 @@
+//! The users and groups services run as.
+//!
+//! Names are looked up in `/etc/passwd` and `/etc/group` directly rather than
+//! through NSS, which static builds and slim images tend to go without.
+use nix::unistd::{setgid, setgroups, setuid, Gid, Uid};
+use std::{fs, path::Path};
+
+const PASSWD: &str = "/etc/passwd";
+const GROUP: &str = "/etc/group";
+
+/// Every entry of `/etc/passwd` or `/etc/group` split into its fields,
+/// skipping blank lines and comments.
+fn entries(path: &str) -> Result<Vec<Vec<String>>, String> {
+    let text = fs::read_to_string(path).map_err(|e| format!("{}: {}", path, e))?;
+    let entries = text
+        .lines()
+        .filter(|line| !line.trim().is_empty() && !line.starts_with('#'))
+        .map(|line| line.split(':').map(str::to_string).collect::<Vec<_>>())
+        .collect();
+    Ok(entries)
+}
+
+struct Passwd {
+    name: String,
+    uid: u32,
+    gid: u32,
+    home: String,
+}
+
+/// The account named or numbered `spec`. A number without an account is
+/// still a valid user, named after its number, with `/` as its home.
+fn find_user(spec: &str) -> Result<Passwd, String> {
+    let number = spec.parse::<u32>().ok();
+    let found = match Path::new(PASSWD).exists() {
+        true => entries(PASSWD)?.into_iter().find_map(|fields| {
+            let [name, _, uid, gid, _, home, ..] = fields.as_slice() else {
+                return None;
+            };
+            let uid = uid.parse::<u32>().ok()?;
+            let matches = match number {
+                Some(number) => uid == number,
+                None => name == spec,
+            };
+            if !matches {
+                return None;
+            }
+            Some(Passwd {
+                name: name.clone(),
+                uid,
+                gid: gid.parse().ok()?,
+                home: home.clone(),
+            })
+        }),
+        false => None,
+    };
+    match (found, number) {
+        (Some(found), _) => Ok(found),
+        // with the group of the same number, as images tend to pair them up
+        (None, Some(number)) => Ok(Passwd {
+            name: number.to_string(),
+            uid: number,
+            gid: number,
+            home: "/".to_string(),
+        }),
+        (None, None) => Err(format!("user {} isn't in {}", spec, PASSWD)),
+    }
+}
+
+/// The id of the group named or numbered `spec`, out of the entries of
+/// `/etc/group`.
+fn find_group(spec: &str, groups: &[Vec<String>]) -> Result<u32, String> {
+    if let Ok(number) = spec.parse::<u32>() {
+        return Ok(number);
+    }
+    groups
+        .iter()
+        .find_map(|fields| match fields.as_slice() {
+            [name, _, gid, ..] if name == spec => gid.parse().ok(),
+            _ => None,
+        })
+        .ok_or_else(|| format!("group {} isn't in {}", spec, GROUP))
+}
+
+/// Who a service runs as.
+#[derive(Debug)]
+pub struct Credentials {
+    pub name: String,
+    pub home: String,
+    pub uid: Uid,
+    pub gid: Gid,
+    /// Every group the service is in, its primary group included.
+    pub groups: Vec<Gid>,
+}
+
+impl Credentials {
+    /// Look up the credentials of `user` in `group`, both given by name or
+    /// number. The user is in the groups listing it as a member on top of
+    /// `supplementary_groups`, like after a login.
+    pub fn resolve(
+        user: &str,
+        group: Option<&str>,
+        supplementary_groups: &[String],
+    ) -> Result<Self, String> {
+        let passwd = find_user(user)?;
+        let groups = match Path::new(GROUP).exists() {
+            true => entries(GROUP)?,
+            false => Vec::new(),
+        };
+        let gid = match group {
+            Some(group) => find_group(group, &groups)?,
+            None => passwd.gid,
+        };
+
+        let mut gids = vec![gid];
+        for fields in &groups {
+            let [_, _, gid, members, ..] = fields.as_slice() else {
+                continue;
+            };
+            if members.split(',').any(|member| member == passwd.name) {
+                gids.extend(gid.parse::<u32>().ok());
+            }
+        }
+        for group in supplementary_groups {
+            gids.push(find_group(group, &groups)?);
+        }
+        let mut unique = Vec::new();
+        for gid in gids {
+            if !unique.contains(&gid) {
+                unique.push(gid);
+            }
+        }
+
+        Ok(Self {
+            name: passwd.name,
+            home: passwd.home,
+            uid: Uid::from_raw(passwd.uid),
+            gid: Gid::from_raw(gid),
+            groups: unique.into_iter().map(Gid::from_raw).collect(),
+        })
+    }
+
+    /// Give up our own credentials for these, groups first since changing
+    /// them takes the privileges given up along with the user.
+    pub fn apply(&self) -> nix::Result<()> {
+        setgroups(&self.groups)?;
+        setgid(self.gid)?;
+        setuid(self.uid)
+    }
+
+    /// The variables a login would set for the user.
+    pub fn env(&self) -> [(&'static str, &str); 3] {
+        [
+            ("HOME", &self.home),
+            ("USER", &self.name),
+            ("LOGNAME", &self.name),
+        ]
+    }
+}
@@ -1,5 +1,17 @@
 use nix::fcntl::{fcntl, FcntlArg, OFlag};
+use nix::libc;
+use nix::sys::signal::{signal, SigHandler, Signal};
+use nix::unistd::geteuid;
+use std::collections::hash_map::RandomState;
+use std::ffi::CString;
+use std::fs::{self, DirBuilder};
+use std::hash::{BuildHasher, Hasher};
+use std::io;
 use std::os::fd::RawFd;
+use std::os::unix::fs::{DirBuilderExt, MetadataExt};
+use std::path::Path;
+use std::ptr;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 pub fn set_fd_nonblocking(fd: RawFd) -> nix::Result<()> {
     let bits = fcntl(fd, FcntlArg::F_GETFL)?;
@@ -17,3 +29,156 @@ pub fn set_std_stream(fd: RawFd) -> nix::Result<()> {
     )?;
     Ok(())
 }
+
+/// Create `dir` for our sockets, readable by us only, or make sure the one
+/// that's already there can't be tampered with by anyone else: the default
+/// one lives in the shared temporary directory.
+pub fn create_private_dir(dir: &Path) -> io::Result<()> {
+    DirBuilder::new().recursive(true).mode(0o700).create(dir)?;
+    let meta = fs::symlink_metadata(dir)?;
+    let refuse = |why: String| {
+        Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            format!("refusing to use {}: {}", dir.display(), why),
+        ))
+    };
+    if !meta.is_dir() {
+        return refuse("not a directory".to_string());
+    }
+    if meta.uid() != geteuid().as_raw() {
+        return refuse(format!("owned by uid {}", meta.uid()));
+    }
+    if meta.mode() & 0o022 != 0 {
+        return refuse(format!(
+            "writable by others (mode {:o})",
+            meta.mode() & 0o777
+        ));
+    }
+    Ok(())
+}
+
+/// A null terminated array of pointers to `strings`, as `execve` takes them.
+pub fn pointers(strings: &[CString]) -> Vec<*const libc::c_char> {
+    strings
+        .iter()
+        .map(|string| string.as_ptr())
+        .chain([ptr::null()])
+        .collect()
+}
+
+/// Returns a random value in `0..=max`. This is only meant for spreading out
+/// timers, so the randomly seeded std hasher is good enough.
+pub fn jitter(max: u64) -> u64 {
+    if max == 0 {
+        return 0;
+    }
+    RandomState::new().build_hasher().finish() % max.saturating_add(1)
+}
+
+/// Give every signal its default disposition back. Signals ignored by whoever
+/// started kinesin would otherwise stay ignored in the services, and e.g. a
+/// `stop_signal = "SIGINT"` could never reach them.
+pub fn reset_signal_handlers() {
+    for sig in Signal::iterator() {
+        if matches!(sig, Signal::SIGKILL | Signal::SIGSTOP) {
+            continue;
+        }
+        let _ = unsafe { signal(sig, SigHandler::SigDfl) };
+    }
+}
+
+/// A point in time as read off a calendar and a clock, in UTC.
+pub struct CivilTime {
+    pub year: i64,
+    pub month: u32,
+    pub day: u32,
+    pub hour: u32,
+    pub minute: u32,
+    pub second: u32,
+    pub millis: u32,
+}
+
+impl CivilTime {
+    pub fn now() -> Self {
+        Self::at(SystemTime::now())
+    }
+
+    pub fn at(time: SystemTime) -> Self {
+        let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+        let secs = since_epoch.as_secs();
+        let time_of_day = (secs % 86_400) as u32;
+
+        // days to civil date, after http://howardhinnant.github.io/date_algorithms.html
+        let z = (secs / 86_400) as i64 + 719_468;
+        let era = z.div_euclid(146_097);
+        let doe = z - era * 146_097;
+        let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+        let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+        let mp = (5 * doy + 2) / 153;
+        let day = (doy - (153 * mp + 2) / 5 + 1) as u32;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 } as u32;
+        let year = yoe + era * 400 + i64::from(month <= 2);
+
+        Self {
+            year,
+            month,
+            day,
+            hour: time_of_day / 3600,
+            minute: time_of_day / 60 % 60,
+            second: time_of_day % 60,
+            millis: since_epoch.subsec_millis(),
+        }
+    }
+
+    /// ISO 8601 without separators, which sorts like the time it stands for.
+    pub fn basic(&self) -> String {
+        format!(
+            "{:04}{:02}{:02}T{:02}{:02}{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+
+    /// RFC 3339, with milliseconds.
+    pub fn rfc3339(&self) -> String {
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:03}Z",
+            self.year, self.month, self.day, self.hour, self.minute, self.second, self.millis
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::time::Duration;
+
+    fn rfc3339(secs: u64, millis: u64) -> String {
+        let since_epoch = Duration::from_secs(secs) + Duration::from_millis(millis);
+        CivilTime::at(UNIX_EPOCH + since_epoch).rfc3339()
+    }
+
+    #[test]
+    fn civil_dates() {
+        assert_eq!(rfc3339(0, 0), "1970-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(951_782_400, 0), "2000-02-29T00:00:00.000Z");
+        assert_eq!(rfc3339(951_868_800, 0), "2000-03-01T00:00:00.000Z");
+        assert_eq!(rfc3339(1_709_251_199, 999), "2024-02-29T23:59:59.999Z");
+        assert_eq!(rfc3339(1_735_689_599, 0), "2024-12-31T23:59:59.000Z");
+        assert_eq!(rfc3339(1_735_689_600, 0), "2025-01-01T00:00:00.000Z");
+        assert_eq!(rfc3339(4_107_542_400, 0), "2100-03-01T00:00:00.000Z");
+    }
+
+    #[test]
+    fn basic_format_sorts_like_time() {
+        let at = |secs| CivilTime::at(UNIX_EPOCH + Duration::from_secs(secs)).basic();
+        assert_eq!(at(1_735_732_800), "20250101T120000.000Z");
+        assert!(at(1_735_732_799) < at(1_735_732_800));
+        assert!(at(999_999_999) < at(1_000_000_000));
+    }
+
+    #[test]
+    fn times_before_the_epoch_are_clamped() {
+        let before = UNIX_EPOCH - Duration::from_secs(1);
+        assert_eq!(CivilTime::at(before).rfc3339(), "1970-01-01T00:00:00.000Z");
+    }
+}
@@ -1,15 +1,21 @@
-use nix::sys::{
-    epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
-    signal::{SigSet, Signal},
-    signalfd::SignalFd,
+use nix::{
+    errno::Errno,
+    sys::{
+        epoll::{Epoll, EpollCreateFlags, EpollEvent, EpollFlags, EpollTimeout},
+        signal::{SigSet, Signal},
+        signalfd::SignalFd,
+        time::TimeSpec,
+        timerfd::{ClockId, Expiration, TimerFd, TimerFlags, TimerSetTimeFlags},
+    },
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io,
     os::{
-        fd::{BorrowedFd, RawFd},
+        fd::{AsFd, BorrowedFd, RawFd},
         unix::io::AsRawFd,
     },
+    time::Duration,
 };
 
 use crate::{buffd::BufFd, utils::set_fd_nonblocking};
@@ -21,6 +27,8 @@ pub struct EpollWatcher {
     signal_fd: SignalFd,
     epoll: Epoll,
     fdstore: HashMap<RawFd, BufFd>,
+    timers: HashMap<RawFd, (TimerFd, u64)>,
+    readable: HashSet<RawFd>,
 }
 
 impl EpollWatcher {
@@ -40,16 +48,20 @@ impl EpollWatcher {
         epoll.add(&signal_fd, event).unwrap();
 
         let fdstore = HashMap::new();
+        let timers = HashMap::new();
+        let readable = HashSet::new();
 
         Self {
             event_buffer,
             signal_fd,
             epoll,
             fdstore,
+            timers,
+            readable,
         }
     }
 
-    fn epoll(&mut self, timeout: EpollTimeout) -> io::Result<Option<Event>> {
+    fn epoll(&mut self, timeout: EpollTimeout) -> io::Result<Option<Event<'_>>> {
         let num_fds = self.epoll.wait(&mut self.event_buffer, timeout)?;
 
         if num_fds > 1 {
@@ -71,17 +83,31 @@ impl EpollWatcher {
             Ok(Some(Event::Signal(Signal::try_from(
                 siginfo.ssi_signo as i32,
             )?)))
-        } else {
-            if let Some(buf_fd) = self.fdstore.get_mut(&(data as _)) {
-                if buf_fd.read(None)? > 0 {
+        } else if let Some((timer_fd, id)) = self.timers.remove(&(data as _)) {
+            self.epoll.delete(timer_fd.as_fd())?;
+            Ok(Some(Event::Timer(id)))
+        } else if self.readable.remove(&(data as _)) {
+            let borrowed_fd = unsafe { BorrowedFd::borrow_raw(data as _) };
+            self.epoll.delete(borrowed_fd)?;
+            Ok(Some(Event::Readable(data as _)))
+        } else if let Some(buf_fd) = self.fdstore.get_mut(&(data as _)) {
+            match buf_fd.read(None) {
+                Ok(0) => {
+                    self.fdstore.remove(&(data as _));
+                    let borrowed_fd = unsafe { BorrowedFd::borrow_raw(data as _) };
+                    self.epoll.delete(borrowed_fd)?;
+                    Ok(Some(Event::Closed(data as _)))
+                }
+                Ok(_) => {
+                    let buf_fd = self.fdstore.get(&(data as _)).unwrap();
                     Ok(Some(Event::File(data as _, buf_fd.data())))
-                } else {
-                    Ok(None)
                 }
-            } else {
-                eprintln!("received an event for an fd not in the store...?");
-                panic!();
+                Err(Errno::EAGAIN) => Ok(None),
+                Err(e) => Err(e.into()),
             }
+        } else {
+            eprintln!("received an event for an fd not in the store...?");
+            panic!();
         }
     }
 }
@@ -102,11 +128,51 @@ impl AsWatcher for EpollWatcher {
         self.fdstore.insert(fd, buf_fd);
     }
 
-    fn poll_block(&mut self) -> io::Result<Option<Event>> {
+    fn unwatch_fd(&mut self, fd: RawFd) {
+        if self.fdstore.remove(&fd).is_some() || self.readable.remove(&fd) {
+            let borrowed_fd = unsafe { BorrowedFd::borrow_raw(fd) };
+            if let Err(e) = self.epoll.delete(borrowed_fd) {
+                eprintln!("Failed to unwatch fd {}: {}", fd, e);
+            }
+        }
+    }
+
+    fn watch_readable(&mut self, fd: RawFd) {
+        if !self.readable.insert(fd) {
+            return;
+        }
+        let borrowed_fd = unsafe { BorrowedFd::borrow_raw(fd) };
+        self.epoll
+            .add(borrowed_fd, EpollEvent::new(EpollFlags::EPOLLIN, fd as _))
+            .unwrap();
+    }
+
+    fn watch_timer(&mut self, id: u64, after: Duration) {
+        let timer_fd = TimerFd::new(
+            ClockId::CLOCK_MONOTONIC,
+            TimerFlags::TFD_NONBLOCK | TimerFlags::TFD_CLOEXEC,
+        )
+        .unwrap();
+        // an all-zero expiration would disarm the timer instead of firing it
+        let after = after.max(Duration::from_nanos(1));
+        timer_fd
+            .set(
+                Expiration::OneShot(TimeSpec::from_duration(after)),
+                TimerSetTimeFlags::empty(),
+            )
+            .unwrap();
+        let fd = timer_fd.as_fd().as_raw_fd();
+        self.epoll
+            .add(&timer_fd, EpollEvent::new(EpollFlags::EPOLLIN, fd as _))
+            .unwrap();
+        self.timers.insert(fd, (timer_fd, id));
+    }
+
+    fn poll_block(&mut self) -> io::Result<Option<Event<'_>>> {
         self.epoll(EpollTimeout::NONE)
     }
 
-    fn poll_no_block(&mut self) -> io::Result<Option<Event>> {
+    fn poll_no_block(&mut self) -> io::Result<Option<Event<'_>>> {
         self.epoll(EpollTimeout::ZERO)
     }
 }
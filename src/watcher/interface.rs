@@ -8,27 +8,52 @@
 //! The notable limitations to the underlying backends are as follows:
 //!
 //! 1) kqueue: changelists and eventlists cannot be combined
-//!            into a single syscall. This is because the other implementations
-//!            don't support this ability
+//!    into a single syscall. This is because the other implementations
+//!    don't support this ability
 //! 2) io_uring: notifications can't hold the new data which the kernel surfaced
-//!              even though it wouldn't require another syscall. The reasoning
-//!              is two-fold. First is that it would require really difficult
-//!              lifetime semantics and odd references in the driver
-//!              implementation itself. The second, and more important issue
-//!              is that io_uring is the only backend which supports this.
+//!    even though it wouldn't require another syscall. The reasoning
+//!    is two-fold. First is that it would require really difficult
+//!    lifetime semantics and odd references in the driver
+//!    implementation itself. The second, and more important issue
+//!    is that io_uring is the only backend which supports this.
+//!
+//! Watched fds are forgotten by the backend once they reach end-of-file, which
+//! is reported through `Event::Closed`, or once they're unwatched. The fd itself
+//! is never closed by the watcher, that remains the job of whoever handed it
+//! over.
+//!
+//! Fds which can't simply be read from, like listening sockets, are watched for
+//! readiness instead. That interest is one-shot: after `Event::Readable` the fd
+//! has to be watched again to hear about it anew.
 use nix::sys::signal::Signal;
 use std::io;
 use std::os::fd::RawFd;
+use std::time::Duration;
 
 pub enum Event<'a> {
     Signal(Signal),
     File(RawFd, &'a [u8]),
+    Closed(RawFd),
+    Timer(u64),
+    Readable(RawFd),
 }
 
 pub trait AsWatcher {
     fn watch_fd(&mut self, fd: RawFd, buffsize: usize);
 
-    fn poll_block(&mut self) -> io::Result<Option<Event>>;
+    /// Stop reading from `fd` before it reached its end, or stop waiting for
+    /// it to become readable. Nothing more is reported about it, not even
+    /// `Event::Closed`, so it can be closed right away.
+    fn unwatch_fd(&mut self, fd: RawFd);
+
+    /// Report once, as `Event::Readable(fd)`, that `fd` can be read without
+    /// blocking. Nothing is read from it.
+    fn watch_readable(&mut self, fd: RawFd);
+
+    /// Arm a one-shot timer which fires as `Event::Timer(id)` after `after`.
+    fn watch_timer(&mut self, id: u64, after: Duration);
+
+    fn poll_block(&mut self) -> io::Result<Option<Event<'_>>>;
 
-    fn poll_no_block(&mut self) -> io::Result<Option<Event>>;
+    fn poll_no_block(&mut self) -> io::Result<Option<Event<'_>>>;
 }
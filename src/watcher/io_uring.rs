@@ -1,17 +1,18 @@
-use io_uring::{opcode, types, IoUring};
+use io_uring::{opcode, squeue, types, IoUring};
 
 use nix::{
     errno::Errno,
-    libc::signalfd_siginfo,
+    libc::{self, signalfd_siginfo},
     sys::{
         signal::{SigSet, Signal},
         signalfd::SignalFd,
     },
 };
 use std::{
-    collections::HashMap,
+    collections::{HashMap, HashSet},
     io, mem,
     os::{fd::RawFd, unix::io::AsRawFd},
+    time::Duration,
 };
 
 use super::{AsWatcher, Event};
@@ -23,11 +24,27 @@ const IO_URING_ENTRIES: u32 = 32;
 // This is based on the size of signalfd_siginfo, please do not change.
 const IO_URING_SIG_BUF_SIZE: usize = 128;
 
+// Timeout completions are told apart from reads (whose user data is the fd) by
+// setting the top bit of their user data.
+const IO_URING_TIMER_TAG: u64 = 1 << 63;
+
+// Same for the completions of readiness polls.
+const IO_URING_POLL_TAG: u64 = 1 << 62;
+
+// And for those of cancellations.
+const IO_URING_CANCEL_TAG: u64 = 1 << 61;
+
 pub struct IoUringWatcher {
     signal_fd: SignalFd,
     signal_buffer: Box<[u8; IO_URING_SIG_BUF_SIZE]>,
     ring: IoUring,
     fdstore: HashMap<RawFd, BufFd>,
+    /// Buffers of unwatched fds, which the kernel may write to until their
+    /// read completes.
+    cancelled: HashMap<RawFd, BufFd>,
+    /// Fds waited on to become readable.
+    readable: HashSet<RawFd>,
+    timers: HashMap<u64, Box<types::Timespec>>,
 }
 
 impl IoUringWatcher {
@@ -43,6 +60,7 @@ impl IoUringWatcher {
         let mut ring = IoUring::new(IO_URING_ENTRIES).unwrap();
 
         let fdstore = HashMap::new();
+        let timers = HashMap::new();
 
         let signal_e = opcode::Read::new(
             types::Fd(signal_fd.as_raw_fd()),
@@ -63,6 +81,23 @@ impl IoUringWatcher {
             ring,
             signal_buffer,
             fdstore,
+            cancelled: HashMap::new(),
+            readable: HashSet::new(),
+            timers,
+        }
+    }
+
+    /// Queue `entry` up, submitting what's queued so far if there's no room
+    /// left for it. Whatever the entry points at must outlive its completion.
+    fn push(&mut self, entry: &squeue::Entry) {
+        if unsafe { self.ring.submission().push(entry) }.is_ok() {
+            return;
+        }
+        if let Err(e) = self.ring.submit() {
+            eprintln!("Failed to submit to io_uring: {}", e);
+        }
+        if unsafe { self.ring.submission().push(entry) }.is_err() {
+            eprintln!("io_uring submission queue is full, dropping an entry");
         }
     }
 
@@ -78,7 +113,7 @@ impl IoUringWatcher {
         unsafe { buffer.assume_init() }
     }
 
-    fn poll_internal(&mut self, wait: bool) -> io::Result<Option<Event>> {
+    fn poll_internal(&mut self, wait: bool) -> io::Result<Option<Event<'_>>> {
         if wait {
             self.ring.submit_and_wait(1).expect("blocking failed");
         } else {
@@ -95,6 +130,31 @@ impl IoUringWatcher {
 
         let usr_data = cqe.user_data();
 
+        if usr_data & IO_URING_TIMER_TAG != 0 {
+            // the kernel is done with the timespec once the timeout completes
+            self.timers.remove(&usr_data);
+            return Ok(Some(Event::Timer(usr_data & !IO_URING_TIMER_TAG)));
+        }
+
+        if usr_data & IO_URING_POLL_TAG != 0 {
+            // a removed poll completes too, and must not take the interest
+            // of a newer one along
+            let fd = (usr_data & !IO_URING_POLL_TAG) as _;
+            if cqe.result() < 0 || !self.readable.remove(&fd) {
+                return Ok(None);
+            }
+            return Ok(Some(Event::Readable(fd)));
+        }
+
+        if usr_data & IO_URING_CANCEL_TAG != 0 {
+            return Ok(None);
+        }
+
+        // the last read of an unwatched fd, whatever its outcome
+        if self.cancelled.remove(&(usr_data as _)).is_some() {
+            return Ok(None);
+        }
+
         if usr_data == self.signal_fd.as_raw_fd() as u64 {
             let siginfo = self.load_from_sigbuf(cqe.result() as _);
 
@@ -105,13 +165,7 @@ impl IoUringWatcher {
             )
             .build()
             .user_data(self.signal_fd.as_raw_fd() as _);
-
-            unsafe {
-                self.ring
-                    .submission()
-                    .push(&signal_e)
-                    .expect("Submission queue is full");
-            }
+            self.push(&signal_e);
 
             Ok(Some(Event::Signal(Signal::try_from(
                 siginfo.ssi_signo as i32,
@@ -122,10 +176,10 @@ impl IoUringWatcher {
             match _res {
                 0 => {
                     self.fdstore.remove(&(usr_data as _));
-                    Ok(None)
+                    Ok(Some(Event::Closed(usr_data as _)))
                 }
                 x if x < 0 => {
-                    let bad = Errno::result(x).err().unwrap();
+                    let bad = Errno::from_raw(-x);
                     eprintln!("{}", bad);
                     panic!();
                 }
@@ -139,12 +193,8 @@ impl IoUringWatcher {
                     )
                     .build()
                     .user_data(buf_fd.as_raw_fd() as u64);
-                    unsafe {
-                        self.ring
-                            .submission()
-                            .push(&entry)
-                            .expect("coudln't push new entry")
-                    };
+                    self.push(&entry);
+                    let buf_fd = &self.fdstore[&(usr_data as _)];
                     Ok(Some(Event::File(usr_data as i32, buf_fd.data())))
                 }
                 _ => todo!(),
@@ -160,14 +210,74 @@ impl AsWatcher for IoUringWatcher {
         let entry = opcode::Read::new(types::Fd(fd), buf_fd.as_mut_ptr(), buf_fd.capacity() as _)
             .build()
             .user_data(buf_fd.as_raw_fd() as u64);
-        unsafe { self.ring.submission().push(&entry).unwrap() };
+        self.push(&entry);
+    }
+
+    fn unwatch_fd(&mut self, fd: RawFd) {
+        if self.readable.remove(&fd) {
+            let entry = opcode::PollRemove::new(fd as u64 | IO_URING_POLL_TAG)
+                .build()
+                .user_data(fd as u64 | IO_URING_CANCEL_TAG);
+            self.push(&entry);
+        }
+        let Some(buf_fd) = self.fdstore.remove(&fd) else {
+            return;
+        };
+        self.cancelled.insert(fd, buf_fd);
+        let entry = opcode::AsyncCancel::new(fd as u64)
+            .build()
+            .user_data(fd as u64 | IO_URING_CANCEL_TAG);
+        self.push(&entry);
     }
 
-    fn poll_block(&mut self) -> io::Result<Option<Event>> {
+    fn watch_readable(&mut self, fd: RawFd) {
+        if !self.readable.insert(fd) {
+            return;
+        }
+        let entry = opcode::PollAdd::new(types::Fd(fd), libc::POLLIN as _)
+            .build()
+            .user_data(fd as u64 | IO_URING_POLL_TAG);
+        self.push(&entry);
+    }
+
+    fn watch_timer(&mut self, id: u64, after: Duration) {
+        let usr_data = id | IO_URING_TIMER_TAG;
+        let timespec = Box::new(types::Timespec::from(after));
+        let entry = opcode::Timeout::new(&*timespec as *const _)
+            .build()
+            .user_data(usr_data);
+        // the timespec has to stay put until the kernel has read it
+        self.timers.insert(usr_data, timespec);
+        self.push(&entry);
+    }
+
+    fn poll_block(&mut self) -> io::Result<Option<Event<'_>>> {
         self.poll_internal(true)
     }
 
-    fn poll_no_block(&mut self) -> io::Result<Option<Event>> {
+    fn poll_no_block(&mut self) -> io::Result<Option<Event<'_>>> {
         self.poll_internal(false)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::BTreeSet;
+
+    #[test]
+    fn more_timers_than_the_ring_holds() {
+        let mut watcher = IoUringWatcher::new();
+        let count = IO_URING_ENTRIES as u64 * 4;
+        for id in 0..count {
+            watcher.watch_timer(id, Duration::from_millis(1));
+        }
+        let mut fired = BTreeSet::new();
+        while fired.len() < count as usize {
+            if let Some(Event::Timer(id)) = watcher.poll_block().unwrap() {
+                fired.insert(id);
+            }
+        }
+        assert_eq!(fired, (0..count).collect());
+    }
+}
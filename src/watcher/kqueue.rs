@@ -1,11 +1,13 @@
 use super::{AsWatcher, Event};
 use crate::buffd::BufFd;
+use nix::errno::Errno;
 use nix::libc::timespec;
 use nix::sys::event::{EventFilter, EventFlag, FilterFlag, KEvent, Kqueue};
 use nix::sys::signal::Signal;
 use std::collections::{HashMap, HashSet};
 use std::io;
 use std::os::fd::RawFd;
+use std::time::Duration;
 
 const NO_TIME_WAIT: timespec = unsafe { std::mem::zeroed() };
 
@@ -13,6 +15,7 @@ pub struct KqueueWatcher {
     kq: Kqueue,
     sigstore: HashSet<Signal>,
     fdstore: HashMap<RawFd, BufFd>,
+    readable: HashSet<RawFd>,
 }
 
 impl KqueueWatcher {
@@ -20,14 +23,16 @@ impl KqueueWatcher {
         let kq = Kqueue::new().unwrap();
         let sigstore = HashSet::new();
         let fdstore = HashMap::new();
+        let readable = HashSet::new();
         Self {
             kq,
             sigstore,
             fdstore,
+            readable,
         }
     }
 
-    fn poll_internal(&mut self, block: bool) -> io::Result<Option<Event>> {
+    fn poll_internal(&mut self, block: bool) -> io::Result<Option<Event<'_>>> {
         let mut eventlist: [KEvent; 1] = unsafe { std::mem::zeroed() };
         let num_events = self
             .kq
@@ -43,11 +48,23 @@ impl KqueueWatcher {
         let ev = eventlist[0];
         if ev.filter().unwrap() == EventFilter::EVFILT_SIGNAL {
             Ok(Some(Event::Signal(Signal::try_from(ev.ident() as i32)?)))
+        } else if ev.filter().unwrap() == EventFilter::EVFILT_TIMER {
+            Ok(Some(Event::Timer(ev.ident() as _)))
+        } else if self.readable.remove(&(ev.ident() as _)) {
+            Ok(Some(Event::Readable(ev.ident() as _)))
         } else if let Some(buf_fd) = self.fdstore.get_mut(&(ev.ident() as _)) {
-            if buf_fd.read(Some(ev.data() as _))? > 0 {
-                Ok(Some(Event::File(ev.ident() as _, buf_fd.data())))
-            } else {
-                Ok(None)
+            match buf_fd.read(Some(ev.data() as _)) {
+                // the kevent goes away by itself once the fd gets closed
+                Ok(0) => {
+                    self.fdstore.remove(&(ev.ident() as _));
+                    Ok(Some(Event::Closed(ev.ident() as _)))
+                }
+                Ok(_) => {
+                    let buf_fd = self.fdstore.get(&(ev.ident() as _)).unwrap();
+                    Ok(Some(Event::File(ev.ident() as _, buf_fd.data())))
+                }
+                Err(Errno::EAGAIN) => Ok(None),
+                Err(e) => Err(e.into()),
             }
         } else {
             println!("Received an event for an fd not in the store...?");
@@ -97,11 +114,62 @@ impl AsWatcher for KqueueWatcher {
             .unwrap();
     }
 
-    fn poll_block(&mut self) -> io::Result<Option<Event>> {
+    fn unwatch_fd(&mut self, fd: RawFd) {
+        if self.fdstore.remove(&fd).is_none() && !self.readable.remove(&fd) {
+            return;
+        }
+        let ev = KEvent::new(
+            fd as _,
+            EventFilter::EVFILT_READ,
+            EventFlag::EV_DELETE,
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        let changelist = [ev];
+        if let Err(e) = self.kq.kevent(&changelist, &mut [], Some(NO_TIME_WAIT)) {
+            eprintln!("Failed to unwatch fd {}: {}", fd, e);
+        }
+    }
+
+    fn watch_readable(&mut self, fd: RawFd) {
+        if !self.readable.insert(fd) {
+            return;
+        }
+        let ev = KEvent::new(
+            fd as _,
+            EventFilter::EVFILT_READ,
+            EventFlag::EV_ADD | EventFlag::EV_ONESHOT,
+            FilterFlag::empty(),
+            0,
+            0,
+        );
+        let changelist = [ev];
+        self.kq
+            .kevent(&changelist, &mut [], Some(NO_TIME_WAIT))
+            .unwrap();
+    }
+
+    fn watch_timer(&mut self, id: u64, after: Duration) {
+        let ev = KEvent::new(
+            id as _,
+            EventFilter::EVFILT_TIMER,
+            EventFlag::EV_ADD | EventFlag::EV_ONESHOT,
+            FilterFlag::empty(),
+            after.as_millis() as _,
+            0,
+        );
+        let changelist = [ev];
+        self.kq
+            .kevent(&changelist, &mut [], Some(NO_TIME_WAIT))
+            .unwrap();
+    }
+
+    fn poll_block(&mut self) -> io::Result<Option<Event<'_>>> {
         self.poll_internal(true)
     }
 
-    fn poll_no_block(&mut self) -> io::Result<Option<Event>> {
+    fn poll_no_block(&mut self) -> io::Result<Option<Event<'_>>> {
         self.poll_internal(false)
     }
 }